@@ -0,0 +1,93 @@
+// Runs the render proxies of a material with the interpreter and with the compiled program
+// cargo bench --bench proxies
+use materialparser::bytecode::compile_material;
+use materialparser::random::ProxyRng;
+use materialparser::runtime::{run_proxies, ProxyHost, ProxyRegistry};
+use materialparser::{parse_material_file, MaterialVariableType, RENDER_PHASE};
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+const FRAMES: u32 = 100_000;
+
+const MATERIAL: &str = r#"UnlitGeneric
+{
+	$basetexture "dev/gradient_dif"
+	$color [1.0, 1.0, 1.0]
+	$health 0
+	$scale 0.0
+	$pulse 0.0
+	$alpha 1.0
+	$frame 0
+
+	RenderProxies
+	{
+		EntityGetHealth { resultvar $health }
+		DivideF { srcvar $health divisor 100 resultvar $scale }
+		$pulse = clamp($scale * 2 - 0.5, 0, 1)
+		$color = [1, $scale, $scale * $pulse]
+		DivideF { srcvar $pulse divisor 2 resultvar $color[2] }
+		$alpha = lerp(0.25, 1, $scale) if $health > 0
+		$alpha = 0 if $health <= 0 || $frame < 0
+		$frame = $frame + 1 if $frame < 1000000
+		$frame = 0 if $frame >= 1000000
+	}
+}"#;
+
+struct BenchHost {
+    health: i32,
+}
+
+impl ProxyHost for BenchHost {
+    fn entity_field(&mut self, name: &str) -> Option<MaterialVariableType> {
+        match name {
+            "health" => Some(MaterialVariableType::INTEGER(self.health)),
+            _ => None
+        }
+    }
+}
+
+fn report(name: &str, elapsed: Duration) {
+    println!("{:<12} {:>10.1} ns/frame", name, elapsed.as_nanos() as f64 / f64::from(FRAMES));
+}
+
+fn main() {
+    let material = match parse_material_file(MATERIAL) {
+        Ok(data) => data,
+        Err(e) => panic!("{}", e)
+    };
+    let registry = ProxyRegistry::with_builtins();
+    let proxies = material.proxies(RENDER_PHASE).to_vec();
+
+    let mut interpreted = material.clone();
+    let mut rng = ProxyRng::for_phase(0, RENDER_PHASE);
+    let start = Instant::now();
+    for frame in 0..FRAMES {
+        let mut host = BenchHost { health: (frame % 101) as i32 };
+        if let Err(e) = run_proxies(&proxies, &mut interpreted, &registry, &mut host, &mut rng) {
+            panic!("{}", e)
+        }
+        black_box(&interpreted);
+    }
+    let ast = start.elapsed();
+
+    let program = compile_material(&material, &registry);
+    let mut state = program.state();
+    let render = match program.phase_index(RENDER_PHASE) {
+        Some(data) => data,
+        None => panic!("The material has no render proxies")
+    };
+    let start = Instant::now();
+    for frame in 0..FRAMES {
+        let mut host = BenchHost { health: (frame % 101) as i32 };
+        if let Err(e) = program.run_index(render, &mut state, &mut host) {
+            panic!("{}", e)
+        }
+        black_box(&state);
+    }
+    let compiled = start.elapsed();
+
+    println!("{} frames of {} render proxies", FRAMES, proxies.len());
+    report("interpreter", ast);
+    report("compiled", compiled);
+    println!("speedup      {:>10.2}x", ast.as_secs_f64() / compiled.as_secs_f64());
+}
@@ -0,0 +1,748 @@
+// Compiled proxies: the phases of a material as a compact program for a stack machine
+// Variables are resolved to slots and named proxies to the functions of the registry once, when the
+// material is compiled, so running a phase does not look anything up by name:
+// DivideF { srcvar $health divisor 100 resultvar $health }
+// becomes LOAD 3, CONST 0, CALL_PROXY 1, STORE 3
+// Errors the interpreter reports when a proxy runs (unknown variables, missing parameters...) are
+// compiled to FAIL and reported when the program runs, with the same messages.
+use crate::conditions::{compare_values, CompareOperator};
+use crate::expression::{apply_binary, apply_neg, call_function, make_vector, unknown_parameter, BinaryOperator, Expression, ExpressionKind, ProxyGuard};
+use crate::ordered_map::OrderedMap;
+use crate::random::ProxyRng;
+use crate::runtime::{element, with_element, DirtyFlags, ParameterRole, ProxyFunction, ProxyHost, ProxyRegistry};
+use crate::{MaterialError, MaterialFile, MaterialProxy, MaterialVariableKind, MaterialVariableReference, MaterialVariableType, SourceSpan, EXPRESSION_PROXY};
+
+/// Index of a variable in the slots of a program
+pub type Slot = u32;
+
+/// Position of a phase in MaterialProgram::phases, see MaterialProgram::phase_index
+pub type PhaseIndex = usize;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum Instruction {
+    CONST (u32), // pushes a constant
+    LOAD (Slot), // pushes the value of a variable
+    LOAD_ELEMENT (Slot, u32), // pushes an element of a variable
+    ENTITY (u32, SourceSpan), // pushes an entity field, its name is a string of the program
+    NEG (SourceSpan),
+    BINARY (BinaryOperator, SourceSpan),
+    VECTOR (u32, bool), // pops the elements, true to make a list
+    CALL (u32, u32, SourceSpan), // function name and number of arguments
+    COMPARE (CompareOperator, u32, SourceSpan), // pops both operands and sets the flag, the string is the comparison for errors
+    NOT, // inverts the flag
+    JUMP_IF_FALSE (u32), // jumps to an instruction if the flag is not set
+    JUMP_IF_TRUE (u32),
+    CALL_PROXY (u32), // pops the inputs of a proxy function and pushes its result
+    STORE (Slot), // pops a value into a variable
+    STORE_ELEMENT (Slot, u32),
+    POP,
+    FAIL (u32, Option<SourceSpan>), // error message
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SlotInfo {
+    pub name: String,
+    pub kind: Option<MaterialVariableKind>, // declared type, values written to the slot are converted to it
+}
+
+/// A named proxy resolved in the registry
+#[derive(Clone)]
+pub struct ProxyCall {
+    pub name: &'static str,
+    pub inputs: Vec<(&'static str, Option<MaterialVariableKind>)>, // in the order of the arguments
+    pub result: MaterialVariableKind,
+    pub function: ProxyFunction,
+}
+
+/// The proxy an instruction was compiled from
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxyOrigin {
+    pub name: String,
+    pub span: Option<SourceSpan>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CompiledPhase {
+    pub code: Vec<Instruction>,
+    pub origins: Vec<u32>, // for every instruction, index of its proxy in 'proxies'
+    pub proxies: Vec<ProxyOrigin>,
+}
+
+#[derive(Clone)]
+pub struct MaterialProgram {
+    pub slots: Vec<SlotInfo>, // the variables of the material in declaration order
+    pub initial: Vec<MaterialVariableType>, // value of every slot when the material was compiled
+    pub constants: Vec<MaterialVariableType>,
+    pub strings: Vec<String>, // entity fields, functions and error messages
+    pub calls: Vec<ProxyCall>,
+    pub phases: OrderedMap<CompiledPhase>,
+}
+
+// The stack of a program, entries above the top are kept so that the strings and lists copied
+// to the stack reuse their buffers on the next run instead of allocating
+#[derive(Debug, Clone, Default)]
+struct OperandStack {
+    entries: Vec<MaterialVariableType>,
+    top: usize,
+}
+
+impl PartialEq for OperandStack {
+    fn eq(&self, other: &OperandStack) -> bool {
+        self.live() == other.live()
+    }
+}
+
+impl OperandStack {
+    fn live(&self) -> &[MaterialVariableType] {
+        &self.entries[..self.top]
+    }
+
+    fn clear(&mut self) {
+        self.top = 0;
+    }
+
+    fn push(&mut self, value: MaterialVariableType) {
+        if self.top < self.entries.len() {
+            self.entries[self.top] = value;
+        } else {
+            self.entries.push(value);
+        }
+        self.top += 1;
+    }
+
+    // Pushes a copy of a constant or of a variable into the buffers of the entry
+    fn push_copy(&mut self, value: &MaterialVariableType) {
+        if self.top < self.entries.len() {
+            assign(&mut self.entries[self.top], value);
+        } else {
+            self.entries.push(value.clone());
+        }
+        self.top += 1;
+    }
+
+    // The 'count' values at the top, borrowed in the order they were pushed
+    fn operands(&self, count: usize) -> Result<&[MaterialVariableType], MaterialError> {
+        match self.top.checked_sub(count) {
+            Some(base) => Ok(&self.entries[base..self.top]),
+            None => Err("Stack underflow".into())
+        }
+    }
+
+    fn operands_mut(&mut self, count: usize) -> Result<&mut [MaterialVariableType], MaterialError> {
+        match self.top.checked_sub(count) {
+            Some(base) => Ok(&mut self.entries[base..self.top]),
+            None => Err("Stack underflow".into())
+        }
+    }
+
+    fn discard(&mut self, count: usize) {
+        self.top = self.top.saturating_sub(count);
+    }
+
+    // Pops 'count' operands and pushes the value computed from them
+    fn replace(&mut self, count: usize, value: MaterialVariableType) {
+        self.discard(count);
+        self.push(value);
+    }
+}
+
+// Copies a value, reusing the string or list it overwrites
+fn assign(target: &mut MaterialVariableType, value: &MaterialVariableType) {
+    match (target, value) {
+        (MaterialVariableType::STRING(target), MaterialVariableType::STRING(value)) => target.clone_from(value),
+        (MaterialVariableType::LIST(target), MaterialVariableType::LIST(values)) => {
+            target.truncate(values.len());
+            let kept = target.len();
+            for (element, value) in target.iter_mut().zip(values) {
+                assign(element, value);
+            }
+            target.extend(values[kept..].iter().cloned());
+        },
+        (target, value) => *target = value.clone()
+    }
+}
+
+/// Values of the slots of a program and the stack it runs on, reused by every run
+/// Slots whose value changes are flagged dirty, writes made directly to 'values' are not tracked
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProgramState {
+    pub values: Vec<MaterialVariableType>,
+    dirty: DirtyFlags,
+    stack: OperandStack,
+    flag: bool,
+    rngs: Vec<ProxyRng>, // random numbers of every phase, in the order of MaterialProgram::phases
+}
+
+impl ProgramState {
+    pub fn value(&self, slot: Slot) -> &MaterialVariableType {
+        &self.values[slot as usize]
+    }
+
+    /// Values computed by the instructions and not consumed yet, the last one is the top
+    pub fn stack(&self) -> &[MaterialVariableType] {
+        self.stack.live()
+    }
+
+    /// Result of the last comparison of a guard
+    pub fn flag(&self) -> bool {
+        self.flag
+    }
+
+    fn write(&mut self, slot: Slot, value: MaterialVariableType) {
+        let current = &mut self.values[slot as usize];
+        if *current != value {
+            *current = value;
+            self.dirty.mark(slot as usize);
+        }
+    }
+
+    // Same as write for a value borrowed from the stack, it is copied into the buffers of the slot
+    fn write_copy(values: &mut [MaterialVariableType], dirty: &mut DirtyFlags, slot: Slot, value: &MaterialVariableType) {
+        let current = &mut values[slot as usize];
+        if current != value {
+            assign(current, value);
+            dirty.mark(slot as usize);
+        }
+    }
+
+    /// Slots whose value changed since the flags were cleared, every slot is dirty in a new state
+    pub fn dirty(&self) -> impl Iterator<Item = Slot> + '_ {
+        self.dirty.iter().map(|index| index as Slot)
+    }
+
+    pub fn is_dirty(&self, slot: Slot) -> bool {
+        self.dirty.contains(slot as usize)
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+}
+
+/// An instruction run by MaterialProgram::run_traced
+pub struct TraceStep<'a> {
+    pub pc: usize, // index of the instruction in the code of the phase
+    pub instruction: Instruction,
+    pub before: &'a ProgramState,
+    pub after: &'a ProgramState,
+    pub error: Option<&'a MaterialError>, // the run stops after it
+}
+
+struct Compiler<'a> {
+    material: &'a MaterialFile,
+    registry: &'a ProxyRegistry,
+    program: MaterialProgram,
+    phase: CompiledPhase,
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.phase.code.push(instruction);
+        self.phase.origins.push(self.phase.proxies.len() as u32 - 1);
+        self.phase.code.len() - 1
+    }
+
+    fn string(&mut self, string: String) -> u32 {
+        match self.program.strings.iter().position(|s| *s == string) {
+            Some(index) => index as u32,
+            None => {
+                self.program.strings.push(string);
+                self.program.strings.len() as u32 - 1
+            }
+        }
+    }
+
+    fn constant(&mut self, value: &MaterialVariableType) {
+        let index = match self.program.constants.iter().position(|c| c == value) {
+            Some(index) => index,
+            None => {
+                self.program.constants.push(value.clone());
+                self.program.constants.len() - 1
+            }
+        };
+        self.emit(Instruction::CONST(index as u32));
+    }
+
+    fn fail(&mut self, message: String, span: Option<SourceSpan>) {
+        let message = self.string(message);
+        self.emit(Instruction::FAIL(message, span));
+    }
+
+    fn slot(&self, name: &str) -> Option<Slot> {
+        self.material.variables.index_of(name).map(|index| index as Slot)
+    }
+
+    // Sets the target of a jump to the next instruction
+    fn land(&mut self, jump: usize) {
+        let target = self.phase.code.len() as u32;
+        match &mut self.phase.code[jump] {
+            Instruction::JUMP_IF_FALSE(data) | Instruction::JUMP_IF_TRUE(data) => *data = target,
+            _ => {}
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        let span = expression.span;
+        match &expression.kind {
+            ExpressionKind::VALUE(value) => self.constant(value),
+            ExpressionKind::VARIABLE(name) => {
+                match self.slot(name) {
+                    Some(slot) => {
+                        self.emit(Instruction::LOAD(slot));
+                    },
+                    None => self.fail(format!("Unknown variable '${}'", name), Some(span))
+                }
+            },
+            ExpressionKind::ENTITY(name) => {
+                let name = self.string(name.clone());
+                self.emit(Instruction::ENTITY(name, span));
+            },
+            ExpressionKind::PARAMETER(name) => {
+                let e = unknown_parameter(name, span);
+                self.fail(e.message, e.span)
+            },
+            ExpressionKind::NEG(operand) => {
+                self.expression(operand);
+                self.emit(Instruction::NEG(span));
+            },
+            ExpressionKind::BINARY(operator, left, right) => {
+                self.expression(left);
+                self.expression(right);
+                self.emit(Instruction::BINARY(*operator, span));
+            },
+            ExpressionKind::VECTOR(elements, trailing_comma) => {
+                for element in elements {
+                    self.expression(element);
+                }
+                self.emit(Instruction::VECTOR(elements.len() as u32, *trailing_comma));
+            },
+            ExpressionKind::CALL(name, args) => {
+                for arg in args {
+                    self.expression(arg);
+                }
+                let name = self.string(name.clone());
+                self.emit(Instruction::CALL(name, args.len() as u32, span));
+            },
+        }
+    }
+
+    // Leaves the result of the guard in the flag, 'and' and 'or' short-circuit like the interpreter
+    fn guard(&mut self, guard: &ProxyGuard) {
+        match guard {
+            ProxyGuard::COMPARE(left, operator, right) => {
+                self.expression(left);
+                self.expression(right);
+                let text = self.string(guard.to_string());
+                self.emit(Instruction::COMPARE(*operator, text, left.span));
+            },
+            ProxyGuard::NOT(inner) => {
+                self.guard(inner);
+                self.emit(Instruction::NOT);
+            },
+            ProxyGuard::AND(left, right) => {
+                self.guard(left);
+                let jump = self.emit(Instruction::JUMP_IF_FALSE(0));
+                self.guard(right);
+                self.land(jump);
+            },
+            ProxyGuard::OR(left, right) => {
+                self.guard(left);
+                let jump = self.emit(Instruction::JUMP_IF_TRUE(0));
+                self.guard(right);
+                self.land(jump);
+            },
+        }
+    }
+
+    fn read(&mut self, reference: &MaterialVariableReference) {
+        match reference {
+            MaterialVariableReference::TYPE(value) => self.constant(value),
+            MaterialVariableReference::VARIABLE(name) => {
+                match self.slot(name) {
+                    Some(slot) => {
+                        self.emit(Instruction::LOAD(slot));
+                    },
+                    None => self.fail(format!("Unknown variable '${}'", name), None)
+                }
+            },
+            MaterialVariableReference::ARRAYREF(name, index) => {
+                match self.slot(name) {
+                    Some(slot) => {
+                        self.emit(Instruction::LOAD_ELEMENT(slot, *index));
+                    },
+                    None => self.fail(format!("Unknown variable '${}'", name), None)
+                }
+            },
+            MaterialVariableReference::EXPRESSION(expression) => self.expression(expression),
+        }
+    }
+
+    fn write(&mut self, reference: Option<&MaterialVariableReference>, parameter: &str) {
+        match reference {
+            Some(MaterialVariableReference::VARIABLE(name)) => {
+                match self.slot(name) {
+                    Some(slot) => {
+                        self.emit(Instruction::STORE(slot));
+                    },
+                    None => self.fail(format!("Unknown variable '${}'", name), None)
+                }
+            },
+            Some(MaterialVariableReference::ARRAYREF(name, index)) => {
+                match self.slot(name) {
+                    Some(slot) => {
+                        self.emit(Instruction::STORE_ELEMENT(slot, *index));
+                    },
+                    None => self.fail(format!("Unknown variable '${}'", name), None)
+                }
+            },
+            Some(_) => self.fail(format!("'{}' must be a variable", parameter), None),
+            None => self.fail(format!("Missing parameter '{}'", parameter), None)
+        }
+    }
+
+    fn named(&mut self, proxy: &MaterialProxy) {
+        let spec = match self.registry.get(&proxy.name) {
+            Some(data) => data,
+            None => return self.fail(format!("Unknown proxy '{}'", proxy.name), None)
+        };
+        let mut inputs = Vec::new();
+        for parameter in spec.parameters.iter().filter(|p| p.role == ParameterRole::INPUT) {
+            match (proxy.parameters.get(parameter.name), &parameter.default) {
+                (Some(reference), _) => self.read(reference),
+                (None, Some(default)) => self.constant(default),
+                (None, None) => return self.fail(format!("Missing parameter '{}'", parameter.name), None)
+            }
+            inputs.push((parameter.name, parameter.kind));
+        }
+        let index = match self.program.calls.iter().position(|call| call.name == spec.name) {
+            Some(index) => index,
+            None => {
+                self.program.calls.push(ProxyCall {
+                    name: spec.name,
+                    inputs,
+                    result: spec.result,
+                    function: spec.function,
+                });
+                self.program.calls.len() - 1
+            }
+        };
+        self.emit(Instruction::CALL_PROXY(index as u32));
+        match spec.output() {
+            Some(parameter) => self.write(proxy.parameters.get(parameter.name), parameter.name),
+            None => {
+                self.emit(Instruction::POP);
+            }
+        }
+    }
+
+    fn proxy(&mut self, proxy: &MaterialProxy) {
+        self.phase.proxies.push(ProxyOrigin {
+            name: proxy.name.clone(),
+            span: proxy.span,
+        });
+        let skip = match &proxy.guard {
+            Some(guard) => {
+                self.guard(guard);
+                Some(self.emit(Instruction::JUMP_IF_FALSE(0)))
+            },
+            None => None
+        };
+        if proxy.name == EXPRESSION_PROXY {
+            match proxy.parameters.get("expression") {
+                Some(reference) => {
+                    self.read(reference);
+                    self.write(proxy.parameters.get("resultvar"), "resultvar");
+                },
+                None => self.fail(String::from("Missing parameter 'expression'"), None)
+            }
+        } else {
+            self.named(proxy);
+        }
+        if let Some(jump) = skip {
+            self.land(jump);
+        }
+    }
+}
+
+/// Compiles every phase of a material. The slots are the variables of the material, #if blocks are
+/// not compiled, compile the material returned by evaluate_conditions
+pub fn compile_material(material: &MaterialFile, registry: &ProxyRegistry) -> MaterialProgram {
+    let mut compiler = Compiler {
+        material,
+        registry,
+        program: MaterialProgram {
+            slots: Vec::with_capacity(material.variables.len()),
+            initial: Vec::with_capacity(material.variables.len()),
+            constants: Vec::new(),
+            strings: Vec::new(),
+            calls: Vec::new(),
+            phases: OrderedMap::with_capacity(material.phases.len()),
+        },
+        phase: CompiledPhase {
+            code: Vec::new(),
+            origins: Vec::new(),
+            proxies: Vec::new(),
+        },
+    };
+    for (name, value) in &material.variables {
+        compiler.program.slots.push(SlotInfo {
+            name: name.clone(),
+            kind: material.declared_types.get(name).cloned(),
+        });
+        compiler.program.initial.push(value.clone());
+    }
+    for (phase, block) in &material.phases {
+        for proxy in &block.proxies {
+            compiler.proxy(proxy);
+        }
+        let compiled = std::mem::replace(&mut compiler.phase, CompiledPhase {
+            code: Vec::new(),
+            origins: Vec::new(),
+            proxies: Vec::new(),
+        });
+        compiler.program.phases.insert(phase.clone(), compiled);
+    }
+    compiler.program
+}
+
+// The value converted to the declared type of the slot, None if it already has that type
+fn convert(slot: &SlotInfo, value: &MaterialVariableType) -> Result<Option<MaterialVariableType>, MaterialError> {
+    match slot.kind {
+        Some(kind) if value.kind() != kind => match value.coerce(kind) {
+            Some(data) => Ok(Some(data)),
+            None => Err(format!("Type mismatch: '${}' is declared as '{}' but its value is a '{}'", slot.name, kind.name(), value.kind().name()).into())
+        },
+        _ => Ok(None)
+    }
+}
+
+impl MaterialProgram {
+    /// Slots holding the values of the variables when the material was compiled, with the seed 0
+    pub fn state(&self) -> ProgramState {
+        self.seeded_state(0)
+    }
+
+    /// Same as state, the random numbers of the phases come from 'seed', see ProxyRng
+    pub fn seeded_state(&self, seed: u64) -> ProgramState {
+        ProgramState {
+            values: self.initial.clone(),
+            dirty: DirtyFlags::all(self.slots.len()),
+            stack: OperandStack::default(),
+            flag: true,
+            rngs: self.phases.keys().map(|phase| ProxyRng::for_phase(seed, phase)).collect(),
+        }
+    }
+
+    /// Restarts the random streams of every phase from a new seed, the values are kept
+    pub fn reseed(&self, state: &mut ProgramState, seed: u64) {
+        state.rngs = self.phases.keys().map(|phase| ProxyRng::for_phase(seed, phase)).collect();
+    }
+
+    pub fn slot(&self, name: &str) -> Option<Slot> {
+        self.slots.iter().position(|slot| slot.name == name).map(|index| index as Slot)
+    }
+
+    /// Executes a single instruction, returns the next instruction if it jumps.
+    /// Operands are borrowed from the stack, only lists built by VECTOR allocate once the stack and
+    /// the slots hold buffers large enough for the values copied into them
+    fn step(&self, instruction: &Instruction, state: &mut ProgramState, host: &mut dyn ProxyHost, rng: &mut ProxyRng) -> Result<Option<usize>, MaterialError> {
+        let stack = &mut state.stack;
+        match *instruction {
+            Instruction::CONST(index) => stack.push_copy(&self.constants[index as usize]),
+            Instruction::LOAD(slot) => stack.push_copy(&state.values[slot as usize]),
+            Instruction::LOAD_ELEMENT(slot, index) => {
+                let value = element(&state.values[slot as usize], &self.slots[slot as usize].name, index)?;
+                stack.push(value);
+            },
+            Instruction::ENTITY(name, span) => {
+                let name = &self.strings[name as usize];
+                match host.entity_field(name) {
+                    Some(value) => stack.push(value),
+                    None => return Err(MaterialError::new(format!("The host does not provide 'entity.{}'", name), span))
+                }
+            },
+            Instruction::NEG(span) => {
+                let value = apply_neg(&stack.operands(1)?[0], span)?;
+                stack.replace(1, value);
+            },
+            Instruction::BINARY(operator, span) => {
+                let operands = stack.operands(2)?;
+                let value = apply_binary(operator, &operands[0], &operands[1], span)?;
+                stack.replace(2, value);
+            },
+            Instruction::VECTOR(count, is_list) => {
+                let values = stack.operands(count as usize)?;
+                let vector = if is_list { None } else { make_vector(values) };
+                let value = match vector {
+                    Some(data) => data,
+                    None => MaterialVariableType::LIST(values.to_vec())
+                };
+                stack.replace(count as usize, value);
+            },
+            Instruction::CALL(name, count, span) => {
+                let value = call_function(&self.strings[name as usize], stack.operands(count as usize)?, span)?;
+                stack.replace(count as usize, value);
+            },
+            Instruction::COMPARE(operator, text, span) => {
+                let operands = stack.operands(2)?;
+                let (l, r) = (&operands[0], &operands[1]);
+                match compare_values(l, operator, r) {
+                    Some(result) => state.flag = result,
+                    None => return Err(MaterialError::new(format!("Cannot evaluate '{}': only numbers can be ordered, got '{}' and '{}'", self.strings[text as usize], l.kind().name(), r.kind().name()), span))
+                }
+                stack.discard(2);
+            },
+            Instruction::NOT => state.flag = !state.flag,
+            Instruction::JUMP_IF_FALSE(target) => {
+                if !state.flag {
+                    return Ok(Some(target as usize))
+                }
+            },
+            Instruction::JUMP_IF_TRUE(target) => {
+                if state.flag {
+                    return Ok(Some(target as usize))
+                }
+            },
+            Instruction::CALL_PROXY(index) => {
+                let call = &self.calls[index as usize];
+                let count = call.inputs.len();
+                for (value, (name, kind)) in stack.operands_mut(count)?.iter_mut().zip(&call.inputs) {
+                    match kind {
+                        Some(kind) if value.kind() != *kind => match value.coerce(*kind) {
+                            Some(data) => *value = data,
+                            None => return Err(format!("Parameter '{}' expects a '{}', got a '{}'", name, kind.name(), value.kind().name()).into())
+                        },
+                        _ => {}
+                    }
+                }
+                let result = (call.function)(stack.operands(count)?, host, rng)?;
+                let result = if result.kind() == call.result {
+                    result
+                } else {
+                    match result.coerce(call.result) {
+                        Some(data) => data,
+                        None => return Err(format!("Returned a '{}' instead of a '{}'", result.kind().name(), call.result.name()).into())
+                    }
+                };
+                stack.replace(count, result);
+            },
+            Instruction::STORE(slot) => {
+                let value = &stack.operands(1)?[0];
+                match convert(&self.slots[slot as usize], value)? {
+                    Some(data) => ProgramState::write_copy(&mut state.values, &mut state.dirty, slot, &data),
+                    None => ProgramState::write_copy(&mut state.values, &mut state.dirty, slot, value)
+                }
+                stack.discard(1);
+            },
+            Instruction::STORE_ELEMENT(slot, index) => {
+                let info = &self.slots[slot as usize];
+                let value = with_element(&state.values[slot as usize], &info.name, index, stack.operands(1)?[0].clone())?;
+                stack.discard(1);
+                match convert(info, &value)? {
+                    Some(data) => state.write(slot, data),
+                    None => state.write(slot, value)
+                }
+            },
+            Instruction::POP => stack.discard(1),
+            Instruction::FAIL(message, span) => return Err(MaterialError {
+                message: self.strings[message as usize].clone(),
+                span,
+                related: None,
+            }),
+        }
+        Ok(None)
+    }
+
+    // Runs the code of a phase, 'trace' gets the state before and after every instruction
+    fn execute_code(&self, phase: &CompiledPhase, state: &mut ProgramState, host: &mut dyn ProxyHost, rng: &mut ProxyRng, mut trace: Option<&mut dyn FnMut(&TraceStep<'_>)>) -> Result<(), MaterialError> {
+        state.stack.clear();
+        state.flag = true;
+        let mut pc = 0;
+        while pc < phase.code.len() {
+            let before = trace.as_ref().map(|_| state.clone());
+            let result = self.step(&phase.code[pc], state, host, rng);
+            if let (Some(trace), Some(before)) = (&mut trace, &before) {
+                trace(&TraceStep {
+                    pc,
+                    instruction: phase.code[pc],
+                    before,
+                    after: state,
+                    error: result.as_ref().err(),
+                });
+            }
+            match result {
+                Ok(Some(target)) => pc = target,
+                Ok(None) => pc += 1,
+                Err(e) => {
+                    let origin = &phase.proxies[phase.origins[pc] as usize];
+                    return Err(MaterialError {
+                        message: format!("In '{}': {}", origin.name, e.message),
+                        span: e.span.or(origin.span),
+                        related: e.related,
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Runs a phase with the random numbers of its stream
+    fn execute(&self, index: PhaseIndex, state: &mut ProgramState, host: &mut dyn ProxyHost, trace: Option<&mut dyn FnMut(&TraceStep<'_>)>) -> Result<(), MaterialError> {
+        let code = match self.phases.get_index(index) {
+            Some((_, data)) => data,
+            None => return Err(format!("Unknown phase {}, the program has {} phases", index, self.phases.len()).into())
+        };
+        // The stream is copied out while the state is borrowed by the instructions
+        let mut rng = state.rngs[index];
+        let result = self.execute_code(code, state, host, &mut rng, trace);
+        state.rngs[index] = rng;
+        result
+    }
+
+    /// Position of a phase the material has a block for. Hosts running a phase often resolve it once
+    /// and run it with run_index, which does not look the phase up by name
+    pub fn phase_index(&self, phase: &str) -> Option<PhaseIndex> {
+        self.phases.index_of(phase)
+    }
+
+    /// Runs a phase on the state, fails if the material has no block for it.
+    /// Stops at the first error, reported like the interpreter does
+    pub fn run(&self, phase: &str, state: &mut ProgramState, host: &mut dyn ProxyHost) -> Result<(), MaterialError> {
+        match self.phase_index(phase) {
+            Some(index) => self.execute(index, state, host, None),
+            None => Err(format!("The material has no '{}' phase", phase).into())
+        }
+    }
+
+    /// Same as run for a phase resolved by phase_index
+    pub fn run_index(&self, index: PhaseIndex, state: &mut ProgramState, host: &mut dyn ProxyHost) -> Result<(), MaterialError> {
+        self.execute(index, state, host, None)
+    }
+
+    /// Same as run_index, calls 'trace' after every instruction with the state before and after it.
+    /// Slower than run_index, the state is copied for every instruction
+    pub fn run_traced(&self, index: PhaseIndex, state: &mut ProgramState, host: &mut dyn ProxyHost, trace: &mut dyn FnMut(&TraceStep<'_>)) -> Result<(), MaterialError> {
+        self.execute(index, state, host, Some(trace))
+    }
+
+    /// Writes a slot like the proxies do, converting the value to the declared type of its variable
+    pub fn set_value(&self, state: &mut ProgramState, slot: Slot, value: MaterialVariableType) -> Result<(), MaterialError> {
+        let info = match self.slots.get(slot as usize) {
+            Some(data) => data,
+            None => return Err(format!("Unknown slot {}", slot).into())
+        };
+        match convert(info, &value)? {
+            Some(data) => state.write(slot, data),
+            None => state.write(slot, value)
+        }
+        Ok(())
+    }
+
+    /// Copies the values of the slots into the variables of a material
+    pub fn write_back(&self, state: &ProgramState, material: &mut MaterialFile) {
+        for (slot, value) in self.slots.iter().zip(&state.values) {
+            material.variables.insert(slot.name.clone(), value.clone());
+        }
+    }
+}
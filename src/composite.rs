@@ -0,0 +1,209 @@
+// Composite proxies: a file can define a chain of proxies once and invoke it like a single proxy
+// define NormalizedHealth(out) { EntityGetHealth { resultvar $out } DivideF { srcvar $out divisor 100 resultvar $out } }
+// UnlitGeneric { $health 0 RenderProxies { NormalizedHealth { out $health } } }
+// Definitions are expanded when the file is loaded: the parameters are replaced by the arguments of
+// the invocation, the other variables of the definition are variables of the material
+use crate::expression::ExpressionKind;
+use crate::ordered_map::OrderedMap;
+use crate::{treat_proxy, MaterialError, MaterialFile, MaterialProxy, MaterialVariableReference, Rule, SourceSpan, EXPRESSION_PROXY};
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxyDefinition {
+    pub name: String,
+    pub parameters: Vec<String>,
+    pub proxies: Vec<MaterialProxy>, // the definitions they invoke are already expanded
+    pub span: SourceSpan,
+}
+
+fn invocation_error(message: String, proxy: &MaterialProxy, definition: &ProxyDefinition) -> MaterialError {
+    MaterialError {
+        message,
+        span: proxy.span.or(Some(definition.span)),
+        related: Some(definition.span),
+    }
+}
+
+// Argument of a parameter used in an expression or a guard
+fn bind_expression(name: &str, bindings: &HashMap<&str, &MaterialVariableReference>, proxy: &MaterialProxy, definition: &ProxyDefinition) -> Result<Option<ExpressionKind>, MaterialError> {
+    match bindings.get(name) {
+        Some(MaterialVariableReference::VARIABLE(variable)) => Ok(Some(ExpressionKind::VARIABLE(variable.clone()))),
+        Some(MaterialVariableReference::TYPE(value)) => Ok(Some(ExpressionKind::VALUE(value.clone()))),
+        Some(_) => Err(invocation_error(format!("'{}' of '{}' is used in an expression, it must be a variable or a value", name, definition.name), proxy, definition)),
+        None => Ok(None)
+    }
+}
+
+fn bind_reference(reference: &MaterialVariableReference, bindings: &HashMap<&str, &MaterialVariableReference>, proxy: &MaterialProxy, definition: &ProxyDefinition) -> Result<MaterialVariableReference, MaterialError> {
+    match reference {
+        MaterialVariableReference::VARIABLE(name) => {
+            match bindings.get(name.as_str()) {
+                Some(argument) => Ok((*argument).clone()),
+                None => Ok(reference.clone())
+            }
+        },
+        MaterialVariableReference::ARRAYREF(name, index) => {
+            match bindings.get(name.as_str()) {
+                Some(MaterialVariableReference::VARIABLE(variable)) => Ok(MaterialVariableReference::ARRAYREF(variable.clone(), *index)),
+                Some(_) => Err(invocation_error(format!("'{}' of '{}' is indexed with '${}[{}]', it must be a variable", name, definition.name, name, index), proxy, definition)),
+                None => Ok(reference.clone())
+            }
+        },
+        MaterialVariableReference::EXPRESSION(expression) => {
+            let mut expression = expression.clone();
+            match expression.replace_variables(&mut |name: &str, _: SourceSpan| bind_expression(name, bindings, proxy, definition)) {
+                Ok(_) => Ok(MaterialVariableReference::EXPRESSION(expression)),
+                Err(e) => Err(e)
+            }
+        },
+        MaterialVariableReference::TYPE(_) => Ok(reference.clone())
+    }
+}
+
+// The proxies of the definition with the arguments of the invocation in place of the parameters
+fn expand_invocation(proxy: &MaterialProxy, definition: &ProxyDefinition) -> Result<Vec<MaterialProxy>, MaterialError> {
+    if proxy.guard.is_some() {
+        return Err(invocation_error(format!("'{}' is a defined proxy, it cannot have a guard", definition.name), proxy, definition))
+    }
+    for name in proxy.parameters.keys() {
+        if !definition.parameters.contains(name) {
+            return Err(invocation_error(format!("Unknown parameter '{}' for '{}'", name, definition.name), proxy, definition))
+        }
+    }
+    let mut bindings = HashMap::new();
+    for name in &definition.parameters {
+        match proxy.parameters.get(name) {
+            Some(argument) => bindings.insert(name.as_str(), argument),
+            None => return Err(invocation_error(format!("Missing parameter '{}' for '{}'", name, definition.name), proxy, definition))
+        };
+    }
+
+    let mut expanded = Vec::with_capacity(definition.proxies.len());
+    for inner in &definition.proxies {
+        let mut parameters = OrderedMap::with_capacity(inner.parameters.len());
+        for (name, reference) in inner.parameters.iter() {
+            match bind_reference(reference, &bindings, proxy, definition) {
+                Ok(data) => parameters.insert(name.clone(), data),
+                Err(e) => return Err(e)
+            };
+        }
+        let guard = match &inner.guard {
+            Some(guard) => {
+                let mut guard = guard.clone();
+                match guard.replace_variables(&mut |name: &str, _: SourceSpan| bind_expression(name, &bindings, proxy, definition)) {
+                    Ok(_) => Some(guard),
+                    Err(e) => return Err(e)
+                }
+            },
+            None => None
+        };
+        // Runtime errors point at the invocation
+        expanded.push(MaterialProxy {
+            name: inner.name.clone(),
+            parameters,
+            guard,
+            span: proxy.span,
+        });
+    }
+    Ok(expanded)
+}
+
+/// Replaces the invocations of defined proxies by the proxies of their definitions
+pub fn expand_proxies(proxies: &mut Vec<MaterialProxy>, definitions: &OrderedMap<ProxyDefinition>) -> Result<(), MaterialError> {
+    if !proxies.iter().any(|proxy| definitions.contains_key(&proxy.name)) {
+        return Ok(())
+    }
+    let mut expanded = Vec::with_capacity(proxies.len());
+    for proxy in proxies.drain(..) {
+        match definitions.get(&proxy.name) {
+            Some(definition) => {
+                let data = expand_invocation(&proxy, definition)?;
+                // The definitions above were expanded with the definition, only the ones below remain
+                if let Some(later) = data.iter().find_map(|inner| definitions.get(&inner.name)) {
+                    return Err(MaterialError::with_related(format!("'{}' invokes '{}', which must be defined above it", definition.name, later.name), definition.span, later.span))
+                }
+                expanded.extend(data);
+            },
+            None => expanded.push(proxy)
+        }
+    }
+    *proxies = expanded;
+    Ok(())
+}
+
+/// Expands the defined proxies of every phase, including the phases of the conditional blocks
+pub fn expand_material(material: &mut MaterialFile, definitions: &OrderedMap<ProxyDefinition>) -> Result<(), MaterialError> {
+    if definitions.is_empty() {
+        return Ok(())
+    }
+    for block in material.phases.values_mut() {
+        match expand_proxies(&mut block.proxies, definitions) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    for conditional in &mut material.conditionals {
+        match expand_material(&mut conditional.then, definitions) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+        if let Some(otherwise) = &mut conditional.otherwise {
+            match expand_material(otherwise, definitions) {
+                Ok(_) => {},
+                Err(e) => return Err(e)
+            }
+        }
+    }
+    Ok(())
+}
+
+// Treats a 'definition' and adds it to the definitions, it can invoke the definitions above it
+pub(crate) fn treat_definition(pair: pest::iterators::Pair<'_, Rule>, definitions: &mut OrderedMap<ProxyDefinition>) -> Result<(), MaterialError> {
+    let span = SourceSpan::from_pest(&pair.as_span());
+    let mut inner = pair.into_inner();
+    let name = match inner.next() {
+        Some(data) => data.as_str().to_owned(),
+        None => return Err("Expected name in 'define'".into())
+    };
+    if name == EXPRESSION_PROXY {
+        return Err(MaterialError::new(format!("'{}' is reserved for assignments", name), span))
+    }
+    if let Some(first) = definitions.get(&name) {
+        return Err(MaterialError::with_related(format!("Duplicate definition '{}'", name), span, first.span))
+    }
+
+    let mut parameters: Vec<String> = Vec::new();
+    match inner.next() {
+        Some(data) => {
+            for parameter in data.into_inner() {
+                let parameter_name = parameter.as_str().to_owned();
+                if parameters.contains(&parameter_name) {
+                    return Err(MaterialError::new(format!("Duplicate parameter '{}' in definition '{}'", parameter_name, name), SourceSpan::from_pest(&parameter.as_span())))
+                }
+                parameters.push(parameter_name);
+            }
+        },
+        None => return Err("Expected parameters in 'define'".into())
+    }
+
+    let mut proxies = Vec::new();
+    for element in inner {
+        let proxy = treat_proxy(element, &OrderedMap::new())?;
+        if proxy.name == name {
+            return Err(MaterialError::with_related(format!("'{}' cannot invoke itself", name), proxy.span.unwrap_or(span), span))
+        }
+        proxies.push(proxy);
+    }
+    match expand_proxies(&mut proxies, definitions) {
+        Ok(_) => {},
+        Err(e) => return Err(e)
+    }
+
+    definitions.insert(name.clone(), ProxyDefinition {
+        name,
+        parameters,
+        proxies,
+        span,
+    });
+    Ok(())
+}
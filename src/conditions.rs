@@ -0,0 +1,286 @@
+// Conditional blocks for platforms and quality tiers:
+// #if quality >= high && !mobile { $detailtexture "dev/noise" } #else { ... }
+// Conditions are evaluated against defines supplied by the caller, the blocks whose condition
+// holds are applied over the unconditional declarations of the material, in source order
+// The quality tiers low < medium < high < ultra are ordered values: a tier name and a define whose
+// value is a tier name compare by rank, like the numbers 0 to 3. Other words in a comparison are
+// defines, strings are quoted: #if platform == "mobile"
+use crate::writer::write_value;
+use crate::{MaterialError, MaterialFile, MaterialVariableType};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Values of the defines, e.g. "quality" => STRING("high"), "platform" => STRING("mobile")
+pub type Defines = HashMap<String, MaterialVariableType>;
+
+/// Quality tiers in increasing order, their rank is their index
+pub const QUALITY_TIERS: [&str; 4] = ["low", "medium", "high", "ultra"];
+
+fn tier_rank(name: &str) -> Option<i32> {
+    QUALITY_TIERS.iter().position(|tier| *tier == name).map(|rank| rank as i32)
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CompareOperator {
+    EQ,
+    NE,
+    LT,
+    LE,
+    GT,
+    GE,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ConditionOperand {
+    DEFINE (String), // a quality tier, or a define which must exist
+    VALUE (MaterialVariableType),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MaterialCondition {
+    DEFINED (String), // true if the define exists and is not 0 or ""
+    COMPARE (ConditionOperand, CompareOperator, ConditionOperand),
+    NOT (Box<MaterialCondition>),
+    AND (Box<MaterialCondition>, Box<MaterialCondition>),
+    OR (Box<MaterialCondition>, Box<MaterialCondition>),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MaterialConditional {
+    pub condition: MaterialCondition,
+    pub then: MaterialFile, // bodies have no shader
+    pub otherwise: Option<MaterialFile>,
+}
+
+impl CompareOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            CompareOperator::EQ => "==",
+            CompareOperator::NE => "!=",
+            CompareOperator::LT => "<",
+            CompareOperator::LE => "<=",
+            CompareOperator::GT => ">",
+            CompareOperator::GE => ">=",
+        }
+    }
+}
+
+impl fmt::Display for ConditionOperand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConditionOperand::DEFINE(name) => write!(f, "{}", name),
+            ConditionOperand::VALUE(value) => write!(f, "{}", write_value(value)),
+        }
+    }
+}
+
+impl fmt::Display for MaterialCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MaterialCondition::DEFINED(name) => write!(f, "{}", name),
+            MaterialCondition::COMPARE(left, operator, right) => write!(f, "{} {} {}", left, operator.symbol(), right),
+            MaterialCondition::NOT(condition) => {
+                match **condition {
+                    MaterialCondition::DEFINED(_) | MaterialCondition::NOT(_) => write!(f, "!{}", condition),
+                    _ => write!(f, "!({})", condition),
+                }
+            },
+            MaterialCondition::AND(left, right) => {
+                for (i, operand) in [left, right].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " && ")?;
+                    }
+                    match ***operand {
+                        MaterialCondition::OR(..) => write!(f, "({})", operand)?,
+                        _ => write!(f, "{}", operand)?,
+                    }
+                }
+                Ok(())
+            },
+            MaterialCondition::OR(left, right) => write!(f, "{} || {}", left, right),
+        }
+    }
+}
+
+// Tiers and strings naming a tier resolve to the rank of the tier
+fn resolve_operand(operand: &ConditionOperand, defines: &Defines, condition: &MaterialCondition) -> Result<MaterialVariableType, MaterialError> {
+    let value = match operand {
+        ConditionOperand::DEFINE(name) => {
+            if let Some(rank) = tier_rank(name) {
+                return Ok(MaterialVariableType::INTEGER(rank))
+            }
+            match defines.get(name) {
+                Some(value) => value,
+                None => return Err(format!("Cannot evaluate '{}': '{}' is not defined, strings are written between quotes", condition, name).into())
+            }
+        },
+        ConditionOperand::VALUE(value) => value,
+    };
+    match value {
+        MaterialVariableType::STRING(name) => match tier_rank(name) {
+            Some(rank) => Ok(MaterialVariableType::INTEGER(rank)),
+            None => Ok(value.clone())
+        },
+        _ => Ok(value.clone())
+    }
+}
+
+fn is_truthy(value: &MaterialVariableType) -> bool {
+    match value {
+        MaterialVariableType::NONE => false,
+        MaterialVariableType::STRING(s) => !s.is_empty(),
+        _ => match value.as_f64() {
+            Some(n) => n != 0.0,
+            None => true
+        }
+    }
+}
+
+/// Numbers compare by value whatever their type, other values can only be tested for equality.
+/// Returns None if the values cannot be ordered
+pub fn compare_values(l: &MaterialVariableType, operator: CompareOperator, r: &MaterialVariableType) -> Option<bool> {
+    let ordering = match (l.as_f64(), r.as_f64()) {
+        (Some(a), Some(b)) => a.partial_cmp(&b),
+        _ => None
+    };
+    match (operator, ordering) {
+        (CompareOperator::EQ, Some(o)) => Some(o == Ordering::Equal),
+        (CompareOperator::NE, Some(o)) => Some(o != Ordering::Equal),
+        (CompareOperator::EQ, None) => Some(l == r),
+        (CompareOperator::NE, None) => Some(l != r),
+        (CompareOperator::LT, Some(o)) => Some(o == Ordering::Less),
+        (CompareOperator::LE, Some(o)) => Some(o != Ordering::Greater),
+        (CompareOperator::GT, Some(o)) => Some(o == Ordering::Greater),
+        (CompareOperator::GE, Some(o)) => Some(o != Ordering::Less),
+        (_, None) => None
+    }
+}
+
+impl MaterialCondition {
+    pub fn evaluate(&self, defines: &Defines) -> Result<bool, MaterialError> {
+        match self {
+            MaterialCondition::DEFINED(name) => Ok(defines.get(name).map(is_truthy).unwrap_or(false)),
+            MaterialCondition::NOT(condition) => condition.evaluate(defines).map(|b| !b),
+            MaterialCondition::AND(left, right) => {
+                match left.evaluate(defines) {
+                    Ok(true) => right.evaluate(defines),
+                    other => other
+                }
+            },
+            MaterialCondition::OR(left, right) => {
+                match left.evaluate(defines) {
+                    Ok(false) => right.evaluate(defines),
+                    other => other
+                }
+            },
+            MaterialCondition::COMPARE(left, operator, right) => {
+                let l = resolve_operand(left, defines, self)?;
+                let r = resolve_operand(right, defines, self)?;
+                match compare_values(&l, *operator, &r) {
+                    Some(result) => Ok(result),
+                    None => Err(format!("Cannot evaluate '{}': only numbers can be ordered, got '{}' and '{}'", self, l.kind().name(), r.kind().name()).into())
+                }
+            },
+        }
+    }
+
+    fn collect_defines(&self, names: &mut Vec<String>) {
+        let mut push_operand = |operand: &ConditionOperand| {
+            if let ConditionOperand::DEFINE(name) = operand {
+                if tier_rank(name).is_none() {
+                    names.push(name.clone());
+                }
+            }
+        };
+        match self {
+            MaterialCondition::DEFINED(name) => names.push(name.clone()),
+            MaterialCondition::COMPARE(left, _, right) => {
+                push_operand(left);
+                push_operand(right);
+            },
+            MaterialCondition::NOT(condition) => condition.collect_defines(names),
+            MaterialCondition::AND(left, right) | MaterialCondition::OR(left, right) => {
+                left.collect_defines(names);
+                right.collect_defines(names);
+            },
+        }
+    }
+}
+
+fn apply_body(effective: &mut MaterialFile, body: &MaterialFile, defines: &Defines) -> Result<(), MaterialError> {
+    for (name, kind) in &body.declared_types {
+        effective.declared_types.insert(name.clone(), *kind);
+    }
+    for (name, value) in &body.variables {
+        match effective.set_variable(name, value.clone()) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    for (phase, block) in &body.phases {
+        effective.proxies_mut(phase).extend(block.proxies.iter().cloned());
+    }
+    for conditional in &body.conditionals {
+        match apply_conditional(effective, conditional, defines) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+fn apply_conditional(effective: &mut MaterialFile, conditional: &MaterialConditional, defines: &Defines) -> Result<(), MaterialError> {
+    let holds = conditional.condition.evaluate(defines)?;
+    if holds {
+        apply_body(effective, &conditional.then, defines)
+    } else {
+        match &conditional.otherwise {
+            Some(body) => apply_body(effective, body, defines),
+            None => Ok(())
+        }
+    }
+}
+
+fn collect_conditions<'a>(material: &'a MaterialFile, conditions: &mut Vec<&'a MaterialCondition>) {
+    for conditional in &material.conditionals {
+        conditions.push(&conditional.condition);
+        collect_conditions(&conditional.then, conditions);
+        if let Some(body) = &conditional.otherwise {
+            collect_conditions(body, conditions);
+        }
+    }
+}
+
+impl MaterialFile {
+    /// Produces the effective material for the given defines, it has no conditionals left
+    pub fn evaluate_conditions(&self, defines: &Defines) -> Result<MaterialFile, MaterialError> {
+        let mut effective = self.clone();
+        effective.conditionals.clear();
+        for conditional in &self.conditionals {
+            match apply_conditional(&mut effective, conditional, defines) {
+                Ok(_) => {},
+                Err(e) => return Err(e)
+            }
+        }
+        Ok(effective)
+    }
+
+    /// Every condition of the material, nested ones included, in source order
+    pub fn conditions(&self) -> Vec<&MaterialCondition> {
+        let mut conditions = Vec::new();
+        collect_conditions(self, &mut conditions);
+        conditions
+    }
+
+    /// Sorted names of the defines the conditions of the material depend on, tiers are not defines
+    pub fn condition_defines(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for condition in self.conditions() {
+            condition.collect_defines(&mut names);
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+}
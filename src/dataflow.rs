@@ -0,0 +1,349 @@
+// Dataflow analysis of the proxies: def-use chains of the variables of a material
+// Setup runs once, then the other phases run in any order and any number of times, and the shader
+// reads its inputs after every phase. Guarded writes and writes to an element may not replace the
+// whole value, they do not end the values written before them.
+// #if blocks are not taken into account, analyze the material returned by evaluate_conditions.
+use crate::runtime::ProxyRegistry;
+use crate::{MaterialFile, MaterialProxy, MaterialVariableReference, SourceSpan, EXPRESSION_PROXY, SETUP_PHASE};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt;
+
+/// Where a value is written or read
+#[derive(Debug, PartialEq, Clone)]
+pub enum Site {
+    DECLARATION, // initial value of the variable
+    PROXY (String, usize), // phase and index of the proxy in it
+    SHADER, // shader input read after a phase
+}
+
+/// A value written to a variable and the sites reading it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Definition {
+    pub variable: String,
+    pub site: Site, // DECLARATION or PROXY
+    pub uses: Vec<Site>, // PROXY or SHADER
+    pub overwritten_by: Vec<Site>, // proxies replacing the whole value
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[allow(non_camel_case_types)]
+pub enum DataflowIssueKind {
+    UNUSED_VARIABLE, // declared, never referenced by a proxy nor read by the shader
+    UNUSED_INITIAL_VALUE, // always overwritten before it is read
+    UNREAD_RESULT, // written by a proxy, never read
+    OVERWRITTEN_RESULT, // written by a proxy, overwritten in the same phase before it is read
+    UNINITIALIZED_READ, // read of a variable that may not have a value yet
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DataflowIssue {
+    pub kind: DataflowIssueKind,
+    pub variable: String,
+    pub message: String,
+    pub span: Option<SourceSpan>, // None for declarations
+    pub related: Option<SourceSpan>, // e.g. the proxy overwriting the value
+}
+
+impl fmt::Display for DataflowIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.span, &self.related) {
+            (Some(span), Some(related)) => write!(f, "{} (line {}, column {}; see line {}, column {})", self.message, span.line, span.column, related.line, related.column),
+            (Some(span), None) => write!(f, "{} (line {}, column {})", self.message, span.line, span.column),
+            (None, Some(related)) => write!(f, "{} (see line {}, column {})", self.message, related.line, related.column),
+            (None, None) => write!(f, "{}", self.message)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Dataflow {
+    pub definitions: Vec<Definition>, // declarations first, then proxies in phase order
+    pub issues: Vec<DataflowIssue>,
+}
+
+// Variables read and written by a proxy, reads happen before the write
+struct Access<'a> {
+    reads: Vec<&'a str>,
+    write: Option<&'a str>,
+    replaces: bool, // the write replaces the whole value every time the proxy runs
+}
+
+fn reference_reads<'a>(reference: &'a MaterialVariableReference, reads: &mut Vec<&'a str>) {
+    match reference {
+        MaterialVariableReference::VARIABLE(name) | MaterialVariableReference::ARRAYREF(name, _) => reads.push(name),
+        MaterialVariableReference::EXPRESSION(expression) => reads.extend(expression.variables()),
+        MaterialVariableReference::TYPE(_) => {}
+    }
+}
+
+// Unknown proxies are assumed to only read their parameters
+fn access<'a>(proxy: &'a MaterialProxy, registry: &ProxyRegistry) -> Access<'a> {
+    let output = if proxy.name == EXPRESSION_PROXY {
+        Some("resultvar")
+    } else {
+        match registry.get(&proxy.name) {
+            Some(spec) => spec.output().map(|p| p.name),
+            None => None
+        }
+    };
+    let mut access = Access {
+        reads: match &proxy.guard {
+            Some(guard) => guard.variables(),
+            None => Vec::new()
+        },
+        write: None,
+        replaces: false,
+    };
+    for (parameter, reference) in proxy.parameters.iter() {
+        if output != Some(parameter.as_str()) {
+            reference_reads(reference, &mut access.reads);
+            continue
+        }
+        match reference {
+            MaterialVariableReference::VARIABLE(name) => {
+                access.write = Some(name);
+                access.replaces = proxy.guard.is_none();
+            },
+            MaterialVariableReference::ARRAYREF(name, _) => {
+                // the other elements are kept
+                access.reads.push(name);
+                access.write = Some(name);
+            },
+            _ => {}
+        }
+    }
+    access
+}
+
+// Values that may reach a point of a phase, as indices into the definitions
+#[derive(PartialEq, Clone, Default)]
+struct State {
+    reaching: HashMap<String, BTreeSet<usize>>,
+    uninitialized: BTreeSet<String>,
+}
+
+impl State {
+    fn merge(&mut self, other: &State) {
+        for (name, definitions) in &other.reaching {
+            self.reaching.entry(name.clone()).or_default().extend(definitions);
+        }
+        self.uninitialized.extend(other.uninitialized.iter().cloned());
+    }
+}
+
+fn push_unique(sites: &mut Vec<Site>, site: &Site) {
+    if !sites.contains(site) {
+        sites.push(site.clone());
+    }
+}
+
+struct Analysis<'a> {
+    material: &'a MaterialFile,
+    registry: &'a ProxyRegistry,
+    shader_inputs: &'a [&'a str],
+    definitions: Vec<Definition>,
+    proxy_definitions: HashMap<(String, usize), usize>,
+    reaches_end: HashSet<usize>, // definitions still live at the end of a phase
+    uninitialized_reads: Vec<(String, usize, String)>, // phase, proxy index and variable
+}
+
+impl<'a> Analysis<'a> {
+    fn read(&mut self, state: &State, name: &str, site: &Site) {
+        if let Some(definitions) = state.reaching.get(name) {
+            for definition in definitions {
+                push_unique(&mut self.definitions[*definition].uses, site);
+            }
+        }
+    }
+
+    fn run(&mut self, phase: &str, mut state: State) -> State {
+        let material = self.material;
+        for (index, proxy) in material.proxies(phase).iter().enumerate() {
+            let access = access(proxy, self.registry);
+            let site = Site::PROXY(phase.to_owned(), index);
+            for name in access.reads {
+                self.read(&state, name, &site);
+                let read = (phase.to_owned(), index, name.to_owned());
+                if state.uninitialized.contains(name) && !self.uninitialized_reads.contains(&read) {
+                    self.uninitialized_reads.push(read);
+                }
+            }
+            if let Some(name) = access.write {
+                let next = self.definitions.len();
+                let definition = *self.proxy_definitions.entry((phase.to_owned(), index)).or_insert(next);
+                if definition == next {
+                    self.definitions.push(Definition {
+                        variable: name.to_owned(),
+                        site: site.clone(),
+                        uses: Vec::new(),
+                        overwritten_by: Vec::new(),
+                    });
+                }
+                if access.replaces {
+                    if let Some(previous) = state.reaching.remove(name) {
+                        for overwritten in previous {
+                            push_unique(&mut self.definitions[overwritten].overwritten_by, &site);
+                        }
+                    }
+                    state.uninitialized.remove(name);
+                }
+                state.reaching.entry(name.to_owned()).or_default().insert(definition);
+            }
+        }
+        for name in self.shader_inputs {
+            self.read(&state, name, &Site::SHADER);
+        }
+        for definitions in state.reaching.values() {
+            self.reaches_end.extend(definitions);
+        }
+        state
+    }
+
+    fn proxy(&self, site: &Site) -> Option<&'a MaterialProxy> {
+        match site {
+            Site::PROXY(phase, index) => self.material.proxies(phase).get(*index),
+            _ => None
+        }
+    }
+
+    fn issue(&self, kind: DataflowIssueKind, variable: &str, message: String, site: &Site, related: Option<&Site>) -> DataflowIssue {
+        DataflowIssue {
+            kind,
+            variable: variable.to_owned(),
+            message,
+            span: self.proxy(site).and_then(|proxy| proxy.span),
+            related: related.and_then(|site| self.proxy(site)).and_then(|proxy| proxy.span),
+        }
+    }
+
+    // 'DivideF', or '$health = $health / 100' for assignments
+    fn proxy_name(&self, site: &Site) -> String {
+        let proxy = match self.proxy(site) {
+            Some(data) => data,
+            None => return String::new()
+        };
+        if proxy.name != EXPRESSION_PROXY {
+            return proxy.name.clone()
+        }
+        let target = match proxy.parameters.get("resultvar") {
+            Some(MaterialVariableReference::VARIABLE(name)) => format!("${}", name),
+            Some(MaterialVariableReference::ARRAYREF(name, index)) => format!("${}[{}]", name, index),
+            _ => String::new()
+        };
+        match proxy.parameters.get("expression") {
+            Some(MaterialVariableReference::EXPRESSION(expression)) => format!("{} = {}", target, expression),
+            _ => proxy.name.clone()
+        }
+    }
+
+    fn issues(&self) -> Vec<DataflowIssue> {
+        let mut referenced = HashSet::new();
+        for block in self.material.phases.values() {
+            for proxy in &block.proxies {
+                let access = access(proxy, self.registry);
+                referenced.extend(access.reads);
+                referenced.extend(access.write);
+            }
+        }
+
+        let mut issues = Vec::new();
+        for (index, definition) in self.definitions.iter().enumerate() {
+            let name = definition.variable.as_str();
+            if !definition.uses.is_empty() {
+                continue
+            }
+            let overwriter = definition.overwritten_by.first();
+            let issue = match &definition.site {
+                Site::DECLARATION if !referenced.contains(name) && !self.shader_inputs.contains(&name) => {
+                    self.issue(DataflowIssueKind::UNUSED_VARIABLE, name, format!("'${}' is declared but never used", name), &definition.site, None)
+                },
+                Site::DECLARATION => {
+                    let message = match overwriter {
+                        Some(site) => format!("The initial value of '${}' is never read, '{}' overwrites it first", name, self.proxy_name(site)),
+                        None => format!("The initial value of '${}' is never read", name)
+                    };
+                    self.issue(DataflowIssueKind::UNUSED_INITIAL_VALUE, name, message, &definition.site, overwriter)
+                },
+                site => {
+                    let proxy = self.proxy_name(site);
+                    match overwriter {
+                        Some(by) if !self.reaches_end.contains(&index) => {
+                            self.issue(DataflowIssueKind::OVERWRITTEN_RESULT, name, format!("'{}' writes '${}' but '{}' overwrites it before it is read", proxy, name, self.proxy_name(by)), site, Some(by))
+                        },
+                        _ => self.issue(DataflowIssueKind::UNREAD_RESULT, name, format!("'{}' writes '${}' but it is never read", proxy, name), site, None)
+                    }
+                }
+            };
+            issues.push(issue);
+        }
+
+        for (phase, index, name) in &self.uninitialized_reads {
+            let site = Site::PROXY(phase.clone(), *index);
+            let proxy = self.proxy_name(&site);
+            let message = if self.definitions.iter().any(|d| &d.variable == name) {
+                format!("'{}' may read '${}' before it is written", proxy, name)
+            } else {
+                format!("'{}' reads '${}', which is never declared nor written", proxy, name)
+            };
+            issues.push(self.issue(DataflowIssueKind::UNINITIALIZED_READ, name, message, &site, None));
+        }
+        issues
+    }
+}
+
+/// Builds the def-use chains of the variables and reports unused variables, results that are never
+/// read or overwritten before they are read, and reads of variables that may not have a value.
+/// 'shader_inputs' are the variables read by the shader, e.g. "basetexture"
+pub fn analyze_dataflow(material: &MaterialFile, registry: &ProxyRegistry, shader_inputs: &[&str]) -> Dataflow {
+    let mut analysis = Analysis {
+        material,
+        registry,
+        shader_inputs,
+        definitions: Vec::new(),
+        proxy_definitions: HashMap::new(),
+        reaches_end: HashSet::new(),
+        uninitialized_reads: Vec::new(),
+    };
+
+    let mut initial = State::default();
+    for name in material.variables.keys() {
+        initial.reaching.entry(name.clone()).or_default().insert(analysis.definitions.len());
+        analysis.definitions.push(Definition {
+            variable: name.clone(),
+            site: Site::DECLARATION,
+            uses: Vec::new(),
+            overwritten_by: Vec::new(),
+        });
+    }
+    for block in material.phases.values() {
+        for proxy in &block.proxies {
+            let access = access(proxy, registry);
+            for name in access.reads.into_iter().chain(access.write) {
+                if !material.variables.contains_key(name) {
+                    initial.uninitialized.insert(name.to_owned());
+                }
+            }
+        }
+    }
+
+    // The other phases start from the end of Setup or of any of them, until nothing changes
+    let after_setup = analysis.run(SETUP_PHASE, initial);
+    let phases: Vec<&String> = material.phases.keys().filter(|phase| *phase != SETUP_PHASE).collect();
+    let mut entry = after_setup.clone();
+    loop {
+        let mut next = after_setup.clone();
+        for phase in &phases {
+            let exit = analysis.run(phase, entry.clone());
+            next.merge(&exit);
+        }
+        if next == entry {
+            break
+        }
+        entry = next;
+    }
+
+    Dataflow {
+        issues: analysis.issues(),
+        definitions: analysis.definitions,
+    }
+}
@@ -0,0 +1,158 @@
+// Text listings of compiled materials
+// disassemble prints the slots, constants and proxy functions of a program, then the code of every
+// phase with the proxy each instruction comes from and where it is in the source:
+//   ; DivideF (line 24, column 3)
+//   0003  LOAD 3                    ; $health
+// trace_phase prints the variables, then runs a phase and prints every instruction with the values
+// it read and the variables it changed:
+//   Render 0004  STORE 3                   $health: 50 -> 0
+use crate::bytecode::{Instruction, MaterialProgram, PhaseIndex, ProgramState, TraceStep};
+use crate::runtime::ProxyHost;
+use crate::writer::write_value;
+use crate::{MaterialError, SourceSpan};
+
+// Mnemonic and operands, then a comment naming what the operands refer to
+fn write_instruction(program: &MaterialProgram, instruction: &Instruction) -> (String, String) {
+    let slot = |slot: u32| format!("${}", program.slots[slot as usize].name);
+    match *instruction {
+        Instruction::CONST(index) => (format!("CONST {}", index), write_value(&program.constants[index as usize])),
+        Instruction::LOAD(index) => (format!("LOAD {}", index), slot(index)),
+        Instruction::LOAD_ELEMENT(index, element) => (format!("LOAD_ELEMENT {} {}", index, element), format!("{}[{}]", slot(index), element)),
+        Instruction::ENTITY(name, _) => (format!("ENTITY {}", name), format!("entity.{}", program.strings[name as usize])),
+        Instruction::NEG(_) => (String::from("NEG"), String::new()),
+        Instruction::BINARY(operator, _) => (format!("BINARY {}", operator.symbol()), String::new()),
+        Instruction::VECTOR(count, true) => (format!("VECTOR {} list", count), String::new()),
+        Instruction::VECTOR(count, false) => (format!("VECTOR {}", count), String::new()),
+        Instruction::CALL(name, count, _) => (format!("CALL {} {}", name, count), format!("{}()", program.strings[name as usize])),
+        Instruction::COMPARE(operator, text, _) => (format!("COMPARE {}", operator.symbol()), program.strings[text as usize].clone()),
+        Instruction::NOT => (String::from("NOT"), String::new()),
+        Instruction::JUMP_IF_FALSE(target) => (format!("JUMP_IF_FALSE {:04}", target), String::new()),
+        Instruction::JUMP_IF_TRUE(target) => (format!("JUMP_IF_TRUE {:04}", target), String::new()),
+        Instruction::CALL_PROXY(index) => (format!("CALL_PROXY {}", index), program.calls[index as usize].name.to_owned()),
+        Instruction::STORE(index) => (format!("STORE {}", index), slot(index)),
+        Instruction::STORE_ELEMENT(index, element) => (format!("STORE_ELEMENT {} {}", index, element), format!("{}[{}]", slot(index), element)),
+        Instruction::POP => (String::from("POP"), String::new()),
+        Instruction::FAIL(message, _) => (format!("FAIL {}", message), format!("\"{}\"", program.strings[message as usize])),
+    }
+}
+
+fn instruction_span(instruction: &Instruction) -> Option<SourceSpan> {
+    match *instruction {
+        Instruction::ENTITY(_, span) | Instruction::NEG(span) | Instruction::BINARY(_, span) | Instruction::CALL(_, _, span) | Instruction::COMPARE(_, _, span) => Some(span),
+        Instruction::FAIL(_, span) => span,
+        _ => None
+    }
+}
+
+fn write_line(out: &mut String, pc: usize, text: &str, comment: &str, span: Option<SourceSpan>) {
+    let mut line = format!("{:04}  {:<24}", pc, text);
+    if !comment.is_empty() {
+        line.push_str(&format!("  ; {}", comment));
+    }
+    if let Some(span) = span {
+        line.push_str(&format!("  @{}:{}", span.line, span.column));
+    }
+    out.push_str(line.trim_end());
+    out.push('\n');
+}
+
+/// Lists the slot table, the constants, the proxy functions and the code of every phase
+pub fn disassemble(program: &MaterialProgram) -> String {
+    let mut out = String::from("slots\n");
+    for (index, (slot, value)) in program.slots.iter().zip(&program.initial).enumerate() {
+        match slot.kind {
+            Some(kind) => out.push_str(&format!("{:4}  ${}: {} = {}\n", index, slot.name, kind.name(), write_value(value))),
+            None => out.push_str(&format!("{:4}  ${} = {}\n", index, slot.name, write_value(value)))
+        }
+    }
+    if !program.constants.is_empty() {
+        out.push_str("constants\n");
+        for (index, value) in program.constants.iter().enumerate() {
+            out.push_str(&format!("{:4}  {}\n", index, write_value(value)));
+        }
+    }
+    if !program.calls.is_empty() {
+        out.push_str("proxies\n");
+        for (index, call) in program.calls.iter().enumerate() {
+            let inputs: Vec<String> = call.inputs.iter().map(|(name, kind)| match kind {
+                Some(kind) => format!("{}: {}", name, kind.name()),
+                None => name.to_string()
+            }).collect();
+            out.push_str(&format!("{:4}  {}({}) -> {}\n", index, call.name, inputs.join(", "), call.result.name()));
+        }
+    }
+
+    for (name, phase) in &program.phases {
+        out.push_str(&format!("\n{}Proxies\n", name));
+        let mut origin = None;
+        for (pc, instruction) in phase.code.iter().enumerate() {
+            // A header every time the instructions of the next proxy start
+            if origin != Some(phase.origins[pc]) {
+                origin = Some(phase.origins[pc]);
+                let proxy = &phase.proxies[phase.origins[pc] as usize];
+                match proxy.span {
+                    Some(span) => out.push_str(&format!("; {} (line {}, column {})\n", proxy.name, span.line, span.column)),
+                    None => out.push_str(&format!("; {}\n", proxy.name))
+                }
+            }
+            let (text, comment) = write_instruction(program, instruction);
+            write_line(&mut out, pc, &text, &comment, instruction_span(instruction));
+        }
+    }
+    out
+}
+
+// What an instruction did: the variable it read, the value it pushed, the jump it took, and the old
+// and new value of every variable it changed
+fn write_effects(program: &MaterialProgram, step: &TraceStep<'_>) -> String {
+    if let Some(e) = step.error {
+        return format!("error: {}", e.message)
+    }
+    let variable = |slot: u32| &program.slots[slot as usize].name;
+    let mut effects = match step.instruction {
+        Instruction::LOAD(slot) | Instruction::LOAD_ELEMENT(slot, _) => vec![format!("${} = {}", variable(slot), write_value(step.before.value(slot)))],
+        Instruction::COMPARE(..) | Instruction::NOT => vec![format!("flag {}", step.after.flag())],
+        Instruction::JUMP_IF_FALSE(target) if !step.after.flag() => vec![format!("jump {:04}", target)],
+        Instruction::JUMP_IF_TRUE(target) if step.after.flag() => vec![format!("jump {:04}", target)],
+        Instruction::JUMP_IF_FALSE(_) | Instruction::JUMP_IF_TRUE(_) | Instruction::POP | Instruction::FAIL(..) | Instruction::STORE(_) | Instruction::STORE_ELEMENT(..) => Vec::new(),
+        _ => match step.after.stack().last() {
+            Some(top) => vec![format!("push {}", write_value(top))],
+            None => Vec::new()
+        }
+    };
+    for (slot, (before, after)) in step.before.values.iter().zip(&step.after.values).enumerate() {
+        let stored = match step.instruction {
+            Instruction::STORE(target) | Instruction::STORE_ELEMENT(target, _) => target as usize == slot,
+            _ => false
+        };
+        // a store that writes the value the variable already had is shown too
+        if before != after || stored {
+            effects.push(format!("${}: {} -> {}", program.slots[slot].name, write_value(before), write_value(after)));
+        }
+    }
+    effects.join("  ")
+}
+
+// The value of every variable, on a single line
+fn write_state(program: &MaterialProgram, state: &ProgramState) -> String {
+    program.slots.iter().zip(&state.values).map(|(slot, value)| format!("${} = {}", slot.name, write_value(value))).collect::<Vec<_>>().join("  ")
+}
+
+/// Runs a phase like MaterialProgram::run_index and appends to 'out' the value of every variable, then a line
+/// for every instruction run with the values it read and the old and new value of every variable it changed
+pub fn trace_phase(program: &MaterialProgram, index: PhaseIndex, state: &mut ProgramState, host: &mut dyn ProxyHost, out: &mut String) -> Result<(), MaterialError> {
+    let phase = match program.phases.get_index(index) {
+        Some((name, _)) => name,
+        None => return Err(format!("Unknown phase {}, the program has {} phases", index, program.phases.len()).into())
+    };
+    let line = format!("{} state {}", phase, write_state(program, state));
+    out.push_str(line.trim_end());
+    out.push('\n');
+    program.run_traced(index, state, host, &mut |step: &TraceStep<'_>| {
+        let (text, _) = write_instruction(program, &step.instruction);
+        let effects = write_effects(program, step);
+        let line = format!("{} {:04}  {:<24}  {}", phase, step.pc, text, effects);
+        out.push_str(line.trim_end());
+        out.push('\n');
+    })
+}
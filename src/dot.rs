@@ -0,0 +1,124 @@
+// Graphviz export of the proxies of a material
+// Variables are ellipses, proxies are boxes grouped by phase, an edge goes from a variable to the
+// proxies reading it and from a proxy to the variable it writes, labeled with the parameter name:
+// "$health" -> "Render#1" [label="srcvar"]
+// #if blocks are not drawn, export the material returned by evaluate_conditions.
+use crate::runtime::ProxyRegistry;
+use crate::writer::{escape_string, write_assignment, write_value};
+use crate::{MaterialFile, MaterialProxy, MaterialVariableReference, EXPRESSION_PROXY};
+use std::collections::HashSet;
+
+fn quote(string: &str) -> String {
+    format!("\"{}\"", escape_string(string))
+}
+
+fn proxy_id(phase: &str, index: usize) -> String {
+    quote(&format!("{}#{}", phase, index))
+}
+
+fn variable_id(name: &str) -> String {
+    quote(&format!("${}", name))
+}
+
+// Name of the output parameter, None for unknown proxies, their parameters are drawn as inputs
+fn output_parameter<'a>(proxy: &MaterialProxy, registry: &'a ProxyRegistry) -> Option<&'a str> {
+    if proxy.name == EXPRESSION_PROXY {
+        return Some("resultvar")
+    }
+    match registry.get(&proxy.name) {
+        Some(spec) => spec.output().map(|p| p.name),
+        None => None
+    }
+}
+
+// Constant parameters are written in the box, variables become edges
+fn proxy_label(proxy: &MaterialProxy, index: usize) -> String {
+    let mut lines = vec![match write_assignment(proxy) {
+        Some(assignment) => format!("{}: {}", index, assignment),
+        None => format!("{}: {}", index, proxy.name)
+    }];
+    if proxy.name != EXPRESSION_PROXY {
+        if let Some(guard) = &proxy.guard {
+            lines.push(format!("if {}", guard));
+        }
+        for (parameter, reference) in proxy.parameters.iter() {
+            match reference {
+                MaterialVariableReference::TYPE(value) => lines.push(format!("{} = {}", parameter, write_value(value))),
+                MaterialVariableReference::EXPRESSION(expression) => lines.push(format!("{} = {}", parameter, expression)),
+                _ => {}
+            }
+        }
+    }
+    lines.join("\n")
+}
+
+// Edges of a proxy as (from, to, label)
+fn proxy_edges(proxy: &MaterialProxy, id: &str, registry: &ProxyRegistry, edges: &mut Vec<(String, String, String)>) {
+    if let Some(guard) = &proxy.guard {
+        for name in guard.variables() {
+            edges.push((variable_id(name), id.to_owned(), String::from("if")));
+        }
+    }
+    let output = output_parameter(proxy, registry);
+    for (parameter, reference) in proxy.parameters.iter() {
+        let written = output == Some(parameter.as_str());
+        match reference {
+            MaterialVariableReference::VARIABLE(name) if written => edges.push((id.to_owned(), variable_id(name), parameter.clone())),
+            MaterialVariableReference::VARIABLE(name) => edges.push((variable_id(name), id.to_owned(), parameter.clone())),
+            MaterialVariableReference::ARRAYREF(name, index) if written => edges.push((id.to_owned(), variable_id(name), format!("{}[{}]", parameter, index))),
+            MaterialVariableReference::ARRAYREF(name, index) => edges.push((variable_id(name), id.to_owned(), format!("{}[{}]", parameter, index))),
+            MaterialVariableReference::EXPRESSION(expression) => {
+                for name in expression.variables() {
+                    edges.push((variable_id(name), id.to_owned(), parameter.clone()));
+                }
+            },
+            MaterialVariableReference::TYPE(_) => {}
+        }
+    }
+}
+
+/// Writes the proxies and variables of a material as a Graphviz digraph, every phase is a cluster.
+/// Variables referenced by proxies without being declared are drawn dashed
+pub fn write_dot(material: &MaterialFile, registry: &ProxyRegistry) -> String {
+    let mut out = String::from("digraph material {\n\trankdir=LR;\n");
+    out.push_str(&format!("\tlabel={};\n", quote(&material.shader)));
+
+    let mut edges = Vec::new();
+    for (cluster, (phase, block)) in material.phases.iter().enumerate() {
+        if block.proxies.is_empty() {
+            continue
+        }
+        out.push_str(&format!("\n\tsubgraph cluster_{} {{\n", cluster));
+        out.push_str(&format!("\t\tlabel={};\n\t\tnode [shape=box];\n", quote(&format!("{}Proxies", phase))));
+        for (index, proxy) in block.proxies.iter().enumerate() {
+            let id = proxy_id(phase, index);
+            out.push_str(&format!("\t\t{} [label={}];\n", id, quote(&proxy_label(proxy, index))));
+            proxy_edges(proxy, &id, registry, &mut edges);
+        }
+        out.push_str("\t}\n");
+    }
+
+    out.push_str("\n\tnode [shape=ellipse];\n");
+    let mut drawn = HashSet::new();
+    for (name, value) in &material.variables {
+        let id = variable_id(name);
+        out.push_str(&format!("\t{} [label={}];\n", id, quote(&format!("${}\n{}", name, write_value(value)))));
+        drawn.insert(id);
+    }
+    for (from, to, _) in &edges {
+        for id in [from, to] {
+            if id.starts_with("\"$") && drawn.insert(id.clone()) {
+                out.push_str(&format!("\t{} [style=dashed];\n", id));
+            }
+        }
+    }
+
+    if !edges.is_empty() {
+        out.push('\n');
+    }
+    for (from, to, label) in &edges {
+        out.push_str(&format!("\t{} -> {} [label={}];\n", from, to, quote(label)));
+    }
+    out.push_str("}\n");
+    out
+}
@@ -0,0 +1,1023 @@
+// Constant expressions in variable declarations:
+// $scale 1.0 / 64.0
+// $tint [0.5 * 2, 1, 1]
+// $health_warn clamp($health_max / 4, 10, 50)
+// They are folded by the parser, a variable can reference the variables declared before it, in its
+// block and in the blocks around it.
+// Integers stay integers: 1 / 64 is an integer division and folds to 0, 1.0 / 64 is 0.015625
+// Inside proxy blocks they are evaluated by the runtime: $health = entity.health / 100,
+// and guards decide whether a proxy runs: DivideF if $health > 0 { ... }
+use crate::conditions::{compare_values, CompareOperator};
+use crate::ordered_map::OrderedMap;
+use crate::writer::write_value;
+use crate::{pest_error, treat_value, vector_from, MaterialError, MaterialFile, MaterialVariableKind, MaterialVariableType, Rule, SMFParser, SourceSpan};
+use pest::Parser;
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BinaryOperator {
+    ADD,
+    SUB,
+    MUL,
+    DIV,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
+    VALUE (MaterialVariableType),
+    VARIABLE (String),
+    ENTITY (String), // entity.health, only available to proxies
+    VECTOR (Vec<Expression>, bool), // true if there is a trailing comma, which makes it a list
+    NEG (Box<Expression>),
+    BINARY (BinaryOperator, Box<Expression>, Box<Expression>),
+    CALL (String, Vec<Expression>), // min, max, clamp or lerp
+    PARAMETER (String), // bare name, replaced by its argument when a template is instantiated
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: SourceSpan,
+}
+
+/// Provides the values of the variables and entity fields referenced by an expression,
+/// the span is the one of the reference
+pub trait ExpressionContext {
+    fn variable(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError>;
+
+    fn entity_field(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        Err(MaterialError::new(format!("'entity.{}' can only be used in proxies", name), span))
+    }
+}
+
+/// A closure resolving variables, entity fields are not available
+impl<F: FnMut(&str, SourceSpan) -> Result<MaterialVariableType, MaterialError>> ExpressionContext for F {
+    fn variable(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        self(name, span)
+    }
+}
+
+/// Returns what a variable is replaced by, None to keep it
+pub type VariableReplacer<'a> = dyn FnMut(&str, SourceSpan) -> Result<Option<ExpressionKind>, MaterialError> + 'a;
+
+/// Same as ExpressionContext for type checking, provides kinds instead of values
+pub trait KindContext {
+    fn variable_kind(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableKind, MaterialError>;
+    fn entity_field_kind(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableKind, MaterialError>;
+}
+
+impl BinaryOperator {
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            BinaryOperator::ADD => "+",
+            BinaryOperator::SUB => "-",
+            BinaryOperator::MUL => "*",
+            BinaryOperator::DIV => "/",
+        }
+    }
+}
+
+// 0 for integers, 1 for floats, 2 for doubles, an operation on two numbers has the highest rank of both
+fn rank(value: &MaterialVariableType) -> Option<u8> {
+    match value {
+        MaterialVariableType::INTEGER(_) => Some(0),
+        MaterialVariableType::FLOAT(_) => Some(1),
+        MaterialVariableType::DOUBLE(_) => Some(2),
+        _ => None
+    }
+}
+
+fn scalar_kind(rank: u8) -> MaterialVariableKind {
+    match rank {
+        0 => MaterialVariableKind::INTEGER,
+        1 => MaterialVariableKind::FLOAT,
+        _ => MaterialVariableKind::DOUBLE,
+    }
+}
+
+fn vector_kind(len: usize, rank: u8) -> Option<MaterialVariableKind> {
+    match (len, rank) {
+        (2, 0) => Some(MaterialVariableKind::ARRAY2),
+        (3, 0) => Some(MaterialVariableKind::ARRAY3),
+        (4, 0) => Some(MaterialVariableKind::ARRAY4),
+        (2, 1) => Some(MaterialVariableKind::ARRAY2F),
+        (3, 1) => Some(MaterialVariableKind::ARRAY3F),
+        (4, 1) => Some(MaterialVariableKind::ARRAY4F),
+        (2, _) => Some(MaterialVariableKind::ARRAY2D),
+        (3, _) => Some(MaterialVariableKind::ARRAY3D),
+        (4, _) => Some(MaterialVariableKind::ARRAY4D),
+        _ => None
+    }
+}
+
+// Builds a vector from 2 to 4 numbers, integers are widened if the numbers are not all of the same type
+pub(crate) fn make_vector(values: &[MaterialVariableType]) -> Option<MaterialVariableType> {
+    let mut max = 0;
+    for value in values {
+        match rank(value) {
+            Some(r) => max = max.max(r),
+            None => return None
+        }
+    }
+    match vector_kind(values.len(), max) {
+        Some(kind) => vector_from(kind, values),
+        None => None
+    }
+}
+
+// Converts a number to the type of the given rank
+fn widen(value: &MaterialVariableType, rank: u8) -> MaterialVariableType {
+    match value.coerce(scalar_kind(rank)) {
+        Some(data) => data,
+        None => value.clone()
+    }
+}
+
+fn scalar_binary(operator: BinaryOperator, a: &MaterialVariableType, b: &MaterialVariableType, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+    use MaterialVariableType::*;
+    let rank = match (rank(a), rank(b)) {
+        (Some(x), Some(y)) => x.max(y),
+        _ => return Err(MaterialError::new(format!("Cannot apply '{}' to '{}' and '{}'", operator.symbol(), a.kind().name(), b.kind().name()), span))
+    };
+    if operator == BinaryOperator::DIV && b.as_f64() == Some(0.0) {
+        return Err(MaterialError::new("Division by zero".to_owned(), span))
+    }
+    match (widen(a, rank), widen(b, rank)) {
+        (INTEGER(x), INTEGER(y)) => {
+            let result = match operator {
+                BinaryOperator::ADD => x.checked_add(y),
+                BinaryOperator::SUB => x.checked_sub(y),
+                BinaryOperator::MUL => x.checked_mul(y),
+                BinaryOperator::DIV => x.checked_div(y),
+            };
+            match result {
+                Some(n) => Ok(INTEGER(n)),
+                None => Err(MaterialError::new(format!("Integer overflow in '{} {} {}'", x, operator.symbol(), y), span))
+            }
+        },
+        (FLOAT(x), FLOAT(y)) => {
+            Ok(FLOAT(match operator {
+                BinaryOperator::ADD => x + y,
+                BinaryOperator::SUB => x - y,
+                BinaryOperator::MUL => x * y,
+                BinaryOperator::DIV => x / y,
+            }))
+        },
+        (DOUBLE(x), DOUBLE(y)) => {
+            Ok(DOUBLE(match operator {
+                BinaryOperator::ADD => x + y,
+                BinaryOperator::SUB => x - y,
+                BinaryOperator::MUL => x * y,
+                BinaryOperator::DIV => x / y,
+            }))
+        },
+        _ => Err(MaterialError::new(format!("Cannot apply '{}' to '{}' and '{}'", operator.symbol(), a.kind().name(), b.kind().name()), span))
+    }
+}
+
+// Applies a scalar operation to numbers, component-wise to vectors of the same size,
+// and to each component of a vector with a number
+fn combine(name: &str, a: &MaterialVariableType, b: &MaterialVariableType, span: SourceSpan, scalar: &dyn Fn(&MaterialVariableType, &MaterialVariableType) -> Result<MaterialVariableType, MaterialError>) -> Result<MaterialVariableType, MaterialError> {
+    // The components are kept in fixed arrays, compiled programs run this on every frame
+    let mismatch = || MaterialError::new(format!("Cannot apply '{}' to '{}' and '{}'", name, a.kind().name(), b.kind().name()), span);
+    let mut values = [MaterialVariableType::NONE, MaterialVariableType::NONE, MaterialVariableType::NONE, MaterialVariableType::NONE];
+    let len = match (a.vector_parts(), b.vector_parts()) {
+        (None, None) if rank(a).is_some() && rank(b).is_some() => return scalar(a, b),
+        (Some((x, len_x)), Some((y, len_y))) => {
+            if len_x != len_y {
+                return Err(MaterialError::new(format!("Cannot apply '{}' to a '{}' and a '{}': their sizes differ", name, a.kind().name(), b.kind().name()), span))
+            }
+            for i in 0..len_x {
+                values[i] = scalar(&x[i], &y[i])?;
+            }
+            len_x
+        },
+        (Some((x, len)), None) if rank(b).is_some() => {
+            for i in 0..len {
+                values[i] = scalar(&x[i], b)?;
+            }
+            len
+        },
+        (None, Some((y, len))) if rank(a).is_some() => {
+            for i in 0..len {
+                values[i] = scalar(a, &y[i])?;
+            }
+            len
+        },
+        _ => return Err(mismatch())
+    };
+    match make_vector(&values[..len]) {
+        Some(vector) => Ok(vector),
+        None => Err(mismatch())
+    }
+}
+
+/// Applies an arithmetic operator to numbers or vectors
+pub fn apply_binary(operator: BinaryOperator, a: &MaterialVariableType, b: &MaterialVariableType, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+    combine(operator.symbol(), a, b, span, &|x, y| scalar_binary(operator, x, y, span))
+}
+
+/// Negates a number or every component of a vector
+pub fn apply_neg(value: &MaterialVariableType, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+    use MaterialVariableType::*;
+    match value {
+        INTEGER(n) => match n.checked_neg() {
+            Some(n) => Ok(INTEGER(n)),
+            None => Err(MaterialError::new(format!("Integer overflow in '-{}'", n), span))
+        },
+        FLOAT(n) => Ok(FLOAT(-n)),
+        DOUBLE(n) => Ok(DOUBLE(-n)),
+        _ => apply_binary(BinaryOperator::MUL, value, &INTEGER(-1), span).map_err(|_| MaterialError::new(format!("Cannot negate a '{}'", value.kind().name()), span))
+    }
+}
+
+fn select(name: &str, a: &MaterialVariableType, b: &MaterialVariableType, span: SourceSpan, keep_first: fn(f64, f64) -> bool) -> Result<MaterialVariableType, MaterialError> {
+    combine(name, a, b, span, &|x, y| {
+        let rank = match (rank(x), rank(y)) {
+            (Some(r1), Some(r2)) => r1.max(r2),
+            _ => return Err(MaterialError::new(format!("Cannot apply '{}' to '{}' and '{}'", name, x.kind().name(), y.kind().name()), span))
+        };
+        match (x.as_f64(), y.as_f64()) {
+            (Some(n1), Some(n2)) if keep_first(n1, n2) => Ok(widen(x, rank)),
+            _ => Ok(widen(y, rank))
+        }
+    })
+}
+
+/// Calls one of the functions available in expressions: min(a, b), max(a, b), clamp(x, lo, hi), lerp(a, b, t)
+pub fn call_function(name: &str, args: &[MaterialVariableType], span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+    let expected = match name {
+        "min" | "max" => 2,
+        "clamp" | "lerp" => 3,
+        _ => return Err(MaterialError::new(format!("Unknown function '{}'", name), span))
+    };
+    if args.len() != expected {
+        return Err(MaterialError::new(format!("'{}' expects {} arguments, got {}", name, expected, args.len()), span))
+    }
+    match name {
+        "min" => select(name, &args[0], &args[1], span, |a, b| a <= b),
+        "max" => select(name, &args[0], &args[1], span, |a, b| a >= b),
+        "clamp" => {
+            match select(name, &args[0], &args[1], span, |a, b| a >= b) {
+                Ok(low) => select(name, &low, &args[2], span, |a, b| a <= b),
+                Err(e) => Err(e)
+            }
+        },
+        _ => {
+            // a + (b - a) * t
+            let delta = apply_binary(BinaryOperator::SUB, &args[1], &args[0], span)?;
+            let scaled = apply_binary(BinaryOperator::MUL, &delta, &args[2], span)?;
+            apply_binary(BinaryOperator::ADD, &args[0], &scaled, span)
+        }
+    }
+}
+
+// Size and rank of a number or vector kind, the size is None for numbers
+fn shape(kind: MaterialVariableKind) -> Option<(Option<usize>, u8)> {
+    use MaterialVariableKind::*;
+    match kind {
+        INTEGER => Some((None, 0)),
+        FLOAT => Some((None, 1)),
+        DOUBLE => Some((None, 2)),
+        ARRAY2 => Some((Some(2), 0)),
+        ARRAY3 => Some((Some(3), 0)),
+        ARRAY4 => Some((Some(4), 0)),
+        ARRAY2F => Some((Some(2), 1)),
+        ARRAY3F => Some((Some(3), 1)),
+        ARRAY4F => Some((Some(4), 1)),
+        ARRAY2D => Some((Some(2), 2)),
+        ARRAY3D => Some((Some(3), 2)),
+        ARRAY4D => Some((Some(4), 2)),
+        _ => None
+    }
+}
+
+// Kind of the result of 'combine'
+fn combine_kinds(name: &str, a: MaterialVariableKind, b: MaterialVariableKind, span: SourceSpan) -> Result<MaterialVariableKind, MaterialError> {
+    let ((len_a, rank_a), (len_b, rank_b)) = match (shape(a), shape(b)) {
+        (Some(x), Some(y)) => (x, y),
+        _ => return Err(MaterialError::new(format!("Cannot apply '{}' to '{}' and '{}'", name, a.name(), b.name()), span))
+    };
+    let rank = rank_a.max(rank_b);
+    let len = match (len_a, len_b) {
+        (Some(x), Some(y)) if x != y => return Err(MaterialError::new(format!("Cannot apply '{}' to a '{}' and a '{}': their sizes differ", name, a.name(), b.name()), span)),
+        (Some(x), _) | (_, Some(x)) => x,
+        _ => return Ok(scalar_kind(rank))
+    };
+    match vector_kind(len, rank) {
+        Some(kind) => Ok(kind),
+        None => Err(MaterialError::new(format!("Cannot apply '{}' to '{}' and '{}'", name, a.name(), b.name()), span))
+    }
+}
+
+/// Kind of the result of a function call, see call_function
+pub fn function_kind(name: &str, args: &[MaterialVariableKind], span: SourceSpan) -> Result<MaterialVariableKind, MaterialError> {
+    let expected = match name {
+        "min" | "max" => 2,
+        "clamp" | "lerp" => 3,
+        _ => return Err(MaterialError::new(format!("Unknown function '{}'", name), span))
+    };
+    if args.len() != expected {
+        return Err(MaterialError::new(format!("'{}' expects {} arguments, got {}", name, expected, args.len()), span))
+    }
+    let mut kind = args[0];
+    for arg in &args[1..] {
+        kind = combine_kinds(name, kind, *arg, span)?;
+    }
+    Ok(kind)
+}
+
+pub(crate) fn unknown_parameter(name: &str, span: SourceSpan) -> MaterialError {
+    MaterialError::new(format!("Unknown name '{}', parameters can only be used in templates", name), span)
+}
+
+impl Expression {
+    /// True if the expression does not reference any variable
+    pub fn is_constant(&self) -> bool {
+        match &self.kind {
+            ExpressionKind::VALUE(_) => true,
+            ExpressionKind::VARIABLE(_) | ExpressionKind::ENTITY(_) | ExpressionKind::PARAMETER(_) => false,
+            ExpressionKind::NEG(operand) => operand.is_constant(),
+            ExpressionKind::BINARY(_, left, right) => left.is_constant() && right.is_constant(),
+            ExpressionKind::VECTOR(elements, _) | ExpressionKind::CALL(_, elements) => elements.iter().all(|e| e.is_constant()),
+        }
+    }
+
+    /// Names of the referenced variables, in source order
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names = Vec::new();
+        self.collect_variables(&mut names);
+        names
+    }
+
+    fn collect_variables<'a>(&'a self, names: &mut Vec<&'a str>) {
+        match &self.kind {
+            ExpressionKind::VALUE(_) | ExpressionKind::ENTITY(_) | ExpressionKind::PARAMETER(_) => {},
+            ExpressionKind::VARIABLE(name) => names.push(name),
+            ExpressionKind::NEG(operand) => operand.collect_variables(names),
+            ExpressionKind::BINARY(_, left, right) => {
+                left.collect_variables(names);
+                right.collect_variables(names);
+            },
+            ExpressionKind::VECTOR(elements, _) | ExpressionKind::CALL(_, elements) => {
+                for element in elements {
+                    element.collect_variables(names);
+                }
+            },
+        }
+    }
+
+    // Calls 'visit' on every node of the expression, the operands before the node holding them
+    fn visit_mut(&mut self, visit: &mut dyn FnMut(&mut Expression) -> Result<(), MaterialError>) -> Result<(), MaterialError> {
+        let result = match &mut self.kind {
+            ExpressionKind::VALUE(_) | ExpressionKind::VARIABLE(_) | ExpressionKind::ENTITY(_) | ExpressionKind::PARAMETER(_) => Ok(()),
+            ExpressionKind::NEG(operand) => operand.visit_mut(visit),
+            ExpressionKind::BINARY(_, left, right) => {
+                match left.visit_mut(visit) {
+                    Ok(_) => right.visit_mut(visit),
+                    Err(e) => Err(e)
+                }
+            },
+            ExpressionKind::VECTOR(elements, _) | ExpressionKind::CALL(_, elements) => {
+                elements.iter_mut().try_for_each(|element| element.visit_mut(visit))
+            },
+        };
+        match result {
+            Ok(_) => visit(self),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Replaces the variables for which `replace` returns an expression, used to expand defined proxies
+    pub fn replace_variables(&mut self, replace: &mut VariableReplacer<'_>) -> Result<(), MaterialError> {
+        self.visit_mut(&mut |expression: &mut Expression| {
+            let replacement = match &expression.kind {
+                ExpressionKind::VARIABLE(name) => replace(name, expression.span),
+                _ => Ok(None)
+            };
+            match replacement {
+                Ok(Some(kind)) => {
+                    expression.kind = kind;
+                    Ok(())
+                },
+                Ok(None) => Ok(()),
+                Err(e) => Err(e)
+            }
+        })
+    }
+
+    /// Replaces the parameters of a template by the values of its arguments
+    pub fn replace_parameters(&mut self, arguments: &OrderedMap<MaterialVariableType>) -> Result<(), MaterialError> {
+        self.visit_mut(&mut |expression: &mut Expression| {
+            let value = match &expression.kind {
+                ExpressionKind::PARAMETER(name) => {
+                    match arguments.get(name) {
+                        Some(value) => value.clone(),
+                        None => return Err(unknown_parameter(name, expression.span))
+                    }
+                },
+                _ => return Ok(())
+            };
+            expression.kind = ExpressionKind::VALUE(value);
+            Ok(())
+        })
+    }
+
+    pub fn evaluate(&self, context: &mut dyn ExpressionContext) -> Result<MaterialVariableType, MaterialError> {
+        match &self.kind {
+            ExpressionKind::VALUE(value) => Ok(value.clone()),
+            ExpressionKind::VARIABLE(name) => context.variable(name, self.span),
+            ExpressionKind::ENTITY(name) => context.entity_field(name, self.span),
+            ExpressionKind::PARAMETER(name) => Err(unknown_parameter(name, self.span)),
+            ExpressionKind::NEG(operand) => {
+                match operand.evaluate(context) {
+                    Ok(value) => apply_neg(&value, self.span),
+                    Err(e) => Err(e)
+                }
+            },
+            ExpressionKind::BINARY(operator, left, right) => {
+                let left = left.evaluate(context)?;
+                let right = right.evaluate(context)?;
+                apply_binary(*operator, &left, &right, self.span)
+            },
+            ExpressionKind::VECTOR(elements, trailing_comma) => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements {
+                    match element.evaluate(context) {
+                        Ok(value) => values.push(value),
+                        Err(e) => return Err(e)
+                    }
+                }
+                if !*trailing_comma {
+                    if let Some(vector) = make_vector(&values) {
+                        return Ok(vector)
+                    }
+                }
+                Ok(MaterialVariableType::LIST(values))
+            },
+            ExpressionKind::CALL(name, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg.evaluate(context) {
+                        Ok(value) => values.push(value),
+                        Err(e) => return Err(e)
+                    }
+                }
+                call_function(name, &values, self.span)
+            },
+        }
+    }
+
+    /// Kind of the value of the expression, without evaluating it
+    pub fn infer_kind(&self, context: &mut dyn KindContext) -> Result<MaterialVariableKind, MaterialError> {
+        match &self.kind {
+            ExpressionKind::VALUE(value) => Ok(value.kind()),
+            ExpressionKind::VARIABLE(name) => context.variable_kind(name, self.span),
+            ExpressionKind::ENTITY(name) => context.entity_field_kind(name, self.span),
+            ExpressionKind::PARAMETER(name) => Err(unknown_parameter(name, self.span)),
+            ExpressionKind::NEG(operand) => {
+                match operand.infer_kind(context) {
+                    Ok(kind) if shape(kind).is_some() => Ok(kind),
+                    Ok(kind) => Err(MaterialError::new(format!("Cannot negate a '{}'", kind.name()), self.span)),
+                    Err(e) => Err(e)
+                }
+            },
+            ExpressionKind::BINARY(operator, left, right) => {
+                let left = left.infer_kind(context)?;
+                let right = right.infer_kind(context)?;
+                combine_kinds(operator.symbol(), left, right, self.span)
+            },
+            ExpressionKind::VECTOR(elements, trailing_comma) => {
+                let mut rank = 0;
+                let mut numbers = true;
+                for element in elements {
+                    match element.infer_kind(context) {
+                        Ok(kind) => {
+                            match shape(kind) {
+                                Some((None, r)) => rank = rank.max(r),
+                                _ => numbers = false
+                            }
+                        },
+                        Err(e) => return Err(e)
+                    }
+                }
+                match vector_kind(elements.len(), rank) {
+                    Some(kind) if numbers && !*trailing_comma => Ok(kind),
+                    _ => Ok(MaterialVariableKind::LIST)
+                }
+            },
+            ExpressionKind::CALL(name, args) => {
+                let mut kinds = Vec::with_capacity(args.len());
+                for arg in args {
+                    match arg.infer_kind(context) {
+                        Ok(kind) => kinds.push(kind),
+                        Err(e) => return Err(e)
+                    }
+                }
+                function_kind(name, &kinds, self.span)
+            },
+        }
+    }
+}
+
+// Binding strength of the operators, used to write the parentheses back
+fn precedence(expression: &Expression) -> u8 {
+    match &expression.kind {
+        ExpressionKind::BINARY(BinaryOperator::ADD, ..) | ExpressionKind::BINARY(BinaryOperator::SUB, ..) => 1,
+        ExpressionKind::BINARY(..) => 2,
+        ExpressionKind::NEG(_) => 3,
+        _ => 4
+    }
+}
+
+fn write_operand(f: &mut fmt::Formatter<'_>, operand: &Expression, parenthesize: bool) -> fmt::Result {
+    if parenthesize {
+        write!(f, "({})", operand)
+    } else {
+        write!(f, "{}", operand)
+    }
+}
+
+fn write_list(f: &mut fmt::Formatter<'_>, elements: &[Expression]) -> fmt::Result {
+    for (i, element) in elements.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", element)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExpressionKind::VALUE(value) => write!(f, "{}", write_value(value)),
+            ExpressionKind::VARIABLE(name) => write!(f, "${}", name),
+            ExpressionKind::ENTITY(name) => write!(f, "entity.{}", name),
+            ExpressionKind::PARAMETER(name) => write!(f, "{}", name),
+            ExpressionKind::NEG(operand) => {
+                write!(f, "-")?;
+                write_operand(f, operand, precedence(operand) < 3)
+            },
+            ExpressionKind::BINARY(operator, left, right) => {
+                let strength = precedence(self);
+                write_operand(f, left, precedence(left) < strength)?;
+                write!(f, " {} ", operator.symbol())?;
+                // a - (b - c) and a / (b * c) keep their parentheses
+                let right_grouped = match operator {
+                    BinaryOperator::SUB | BinaryOperator::DIV => precedence(right) <= strength,
+                    _ => precedence(right) < strength
+                };
+                write_operand(f, right, right_grouped)
+            },
+            ExpressionKind::VECTOR(elements, trailing_comma) => {
+                write!(f, "[")?;
+                write_list(f, elements)?;
+                if *trailing_comma {
+                    write!(f, ",")?;
+                }
+                write!(f, "]")
+            },
+            ExpressionKind::CALL(name, args) => {
+                write!(f, "{}(", name)?;
+                write_list(f, args)?;
+                write!(f, ")")
+            },
+        }
+    }
+}
+
+/// Condition deciding whether a proxy runs, evaluated every time its block runs
+#[derive(Debug, PartialEq, Clone)]
+pub enum ProxyGuard {
+    COMPARE (Expression, CompareOperator, Expression),
+    NOT (Box<ProxyGuard>),
+    AND (Box<ProxyGuard>, Box<ProxyGuard>),
+    OR (Box<ProxyGuard>, Box<ProxyGuard>),
+}
+
+impl ProxyGuard {
+    pub fn evaluate(&self, context: &mut dyn ExpressionContext) -> Result<bool, MaterialError> {
+        match self {
+            ProxyGuard::NOT(guard) => guard.evaluate(context).map(|b| !b),
+            ProxyGuard::AND(left, right) => {
+                match left.evaluate(context) {
+                    Ok(true) => right.evaluate(context),
+                    other => other
+                }
+            },
+            ProxyGuard::OR(left, right) => {
+                match left.evaluate(context) {
+                    Ok(false) => right.evaluate(context),
+                    other => other
+                }
+            },
+            ProxyGuard::COMPARE(left, operator, right) => {
+                let l = left.evaluate(context)?;
+                let r = right.evaluate(context)?;
+                match compare_values(&l, *operator, &r) {
+                    Some(result) => Ok(result),
+                    None => Err(MaterialError::new(format!("Cannot evaluate '{}': only numbers can be ordered, got '{}' and '{}'", self, l.kind().name(), r.kind().name()), left.span))
+                }
+            },
+        }
+    }
+
+    /// Names of the variables referenced by the comparisons, in source order
+    pub fn variables(&self) -> Vec<&str> {
+        match self {
+            ProxyGuard::NOT(guard) => guard.variables(),
+            ProxyGuard::AND(left, right) | ProxyGuard::OR(left, right) => {
+                let mut names = left.variables();
+                names.extend(right.variables());
+                names
+            },
+            ProxyGuard::COMPARE(left, _, right) => {
+                let mut names = left.variables();
+                names.extend(right.variables());
+                names
+            },
+        }
+    }
+
+    // Calls 'visit' on both sides of every comparison
+    fn visit_expressions_mut(&mut self, visit: &mut dyn FnMut(&mut Expression) -> Result<(), MaterialError>) -> Result<(), MaterialError> {
+        match self {
+            ProxyGuard::NOT(guard) => guard.visit_expressions_mut(visit),
+            ProxyGuard::AND(left, right) | ProxyGuard::OR(left, right) => {
+                match left.visit_expressions_mut(visit) {
+                    Ok(_) => right.visit_expressions_mut(visit),
+                    Err(e) => Err(e)
+                }
+            },
+            ProxyGuard::COMPARE(left, _, right) => {
+                match visit(left) {
+                    Ok(_) => visit(right),
+                    Err(e) => Err(e)
+                }
+            },
+        }
+    }
+
+    /// See Expression::replace_variables
+    pub fn replace_variables(&mut self, replace: &mut VariableReplacer<'_>) -> Result<(), MaterialError> {
+        self.visit_expressions_mut(&mut |expression: &mut Expression| expression.replace_variables(replace))
+    }
+
+    /// See Expression::replace_parameters
+    pub fn replace_parameters(&mut self, arguments: &OrderedMap<MaterialVariableType>) -> Result<(), MaterialError> {
+        self.visit_expressions_mut(&mut |expression: &mut Expression| expression.replace_parameters(arguments))
+    }
+
+    /// Type checks the guard: the operands of '<', '<=', '>' and '>=' must be numbers
+    pub fn check(&self, context: &mut dyn KindContext) -> Result<(), MaterialError> {
+        match self {
+            ProxyGuard::NOT(guard) => guard.check(context),
+            ProxyGuard::AND(left, right) | ProxyGuard::OR(left, right) => {
+                match left.check(context) {
+                    Ok(_) => right.check(context),
+                    Err(e) => Err(e)
+                }
+            },
+            ProxyGuard::COMPARE(left, operator, right) => {
+                let l = left.infer_kind(context)?;
+                let r = right.infer_kind(context)?;
+                let ordered = !matches!(operator, CompareOperator::EQ | CompareOperator::NE);
+                let numbers = matches!((shape(l), shape(r)), (Some((None, _)), Some((None, _))));
+                if ordered && !numbers {
+                    return Err(MaterialError::new(format!("Cannot evaluate '{}': only numbers can be ordered, got '{}' and '{}'", self, l.name(), r.name()), left.span))
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl fmt::Display for ProxyGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyGuard::COMPARE(left, operator, right) => write!(f, "{} {} {}", left, operator.symbol(), right),
+            ProxyGuard::NOT(guard) => {
+                match **guard {
+                    ProxyGuard::NOT(_) => write!(f, "!{}", guard),
+                    _ => write!(f, "!({})", guard),
+                }
+            },
+            ProxyGuard::AND(left, right) => {
+                for (i, operand) in [left, right].iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " && ")?;
+                    }
+                    match ***operand {
+                        ProxyGuard::OR(..) => write!(f, "({})", operand)?,
+                        _ => write!(f, "{}", operand)?,
+                    }
+                }
+                Ok(())
+            },
+            ProxyGuard::OR(left, right) => write!(f, "{} || {}", left, right),
+        }
+    }
+}
+
+fn expression_error(message: &str, pair: &pest::iterators::Pair<'_, Rule>) -> MaterialError {
+    MaterialError::new(message.to_owned(), SourceSpan::from_pest(&pair.as_span()))
+}
+
+fn treat_operand(pair: pest::iterators::Pair<'_, Rule>) -> Result<Expression, MaterialError> {
+    let span = SourceSpan::from_pest(&pair.as_span());
+    match pair.as_rule() {
+        Rule::expression => treat_expression(pair),
+        Rule::value => {
+            let value = match pair.into_inner().next() {
+                Some(data) => treat_value(data, true),
+                None => return Err("Invalid value in expression".into())
+            };
+            match value {
+                Ok(value) => Ok(Expression { kind: ExpressionKind::VALUE(value), span }),
+                Err(e) => Err(e)
+            }
+        },
+        Rule::parameter => Ok(Expression { kind: ExpressionKind::PARAMETER(pair.as_str().to_owned()), span }),
+        Rule::variable | Rule::entityfield => {
+            let rule = pair.as_rule();
+            let name = match pair.into_inner().next() {
+                Some(ident) => ident.as_str().to_owned(),
+                None => return Err("Invalid identifier in expression".into())
+            };
+            let kind = match rule {
+                Rule::variable => ExpressionKind::VARIABLE(name),
+                _ => ExpressionKind::ENTITY(name)
+            };
+            Ok(Expression { kind, span })
+        },
+        Rule::vecexpr | Rule::call => {
+            let rule = pair.as_rule();
+            let mut name = String::new();
+            let mut elements = Vec::new();
+            let mut trailing_comma = false;
+            for element in pair.into_inner() {
+                match element.as_rule() {
+                    Rule::ident => name = element.as_str().to_owned(),
+                    Rule::trailingcomma => trailing_comma = true,
+                    _ => {
+                        match treat_expression(element) {
+                            Ok(data) => elements.push(data),
+                            Err(e) => return Err(e)
+                        }
+                    }
+                }
+            }
+            let kind = match rule {
+                Rule::call => ExpressionKind::CALL(name, elements),
+                _ => ExpressionKind::VECTOR(elements, trailing_comma)
+            };
+            Ok(Expression { kind, span })
+        },
+        _ => Err(expression_error("Invalid operand in expression", &pair))
+    }
+}
+
+fn treat_unary(pair: pest::iterators::Pair<'_, Rule>) -> Result<Expression, MaterialError> {
+    let span = SourceSpan::from_pest(&pair.as_span());
+    let mut negations = 0;
+    let mut operand = None;
+    for element in pair.into_inner() {
+        match element.as_rule() {
+            Rule::neg => negations += 1,
+            _ => operand = Some(element)
+        }
+    }
+    let mut expression = match operand {
+        Some(data) => {
+            treat_operand(data)?
+        },
+        None => return Err(MaterialError::new("Expected operand after '-'".to_owned(), span))
+    };
+    for _ in 0..negations {
+        expression = Expression { kind: ExpressionKind::NEG(Box::new(expression)), span };
+    }
+    Ok(expression)
+}
+
+/// Parses an expression on its own, e.g. a value given on the command line
+pub fn parse_expression(data: &str) -> Result<Expression, MaterialError> {
+    match SMFParser::parse(Rule::standaloneexpression, data) {
+        Ok(mut pairs) => {
+            match pairs.next() {
+                Some(pair) => treat_expression(pair),
+                None => Err("Empty expression".into())
+            }
+        },
+        Err(e) => {
+            let e = pest_error(e);
+            Err(MaterialError {
+                message: String::from("Invalid expression"),
+                span: e.span,
+                related: None,
+            })
+        }
+    }
+}
+
+// expression and product are both left associative chains of operands and operators
+pub(crate) fn treat_expression(pair: pest::iterators::Pair<'_, Rule>) -> Result<Expression, MaterialError> {
+    let mut expression: Option<Expression> = None;
+    let mut operator = None;
+    for element in pair.into_inner() {
+        match element.as_rule() {
+            Rule::addop | Rule::mulop => {
+                operator = match element.as_str() {
+                    "+" => Some(BinaryOperator::ADD),
+                    "-" => Some(BinaryOperator::SUB),
+                    "*" => Some(BinaryOperator::MUL),
+                    _ => Some(BinaryOperator::DIV),
+                };
+            },
+            _ => {
+                let operand = match element.as_rule() {
+                    Rule::product => treat_expression(element),
+                    _ => treat_unary(element)
+                };
+                let operand = operand?;
+                expression = match (expression, operator) {
+                    (Some(left), Some(operator)) => {
+                        let span = SourceSpan {
+                            start: left.span.start,
+                            end: operand.span.end,
+                            line: left.span.line,
+                            column: left.span.column,
+                        };
+                        Some(Expression { kind: ExpressionKind::BINARY(operator, Box::new(left), Box::new(operand)), span })
+                    },
+                    _ => Some(operand)
+                };
+            }
+        }
+    }
+    match expression {
+        Some(data) => Ok(data),
+        None => Err("Empty expression".into())
+    }
+}
+
+pub(crate) fn treat_guard(pair: pest::iterators::Pair<'_, Rule>) -> Result<ProxyGuard, MaterialError> {
+    match pair.as_rule() {
+        Rule::guard => {
+            match pair.into_inner().next() {
+                Some(data) => treat_guard(data),
+                None => Err("Expected condition after 'if'".into())
+            }
+        },
+        Rule::guardor | Rule::guardand => {
+            let rule = pair.as_rule();
+            let mut guard = None;
+            for element in pair.into_inner() {
+                let operand = treat_guard(element)?;
+                guard = match guard {
+                    None => Some(operand),
+                    Some(left) if rule == Rule::guardor => Some(ProxyGuard::OR(Box::new(left), Box::new(operand))),
+                    Some(left) => Some(ProxyGuard::AND(Box::new(left), Box::new(operand))),
+                };
+            }
+            match guard {
+                Some(data) => Ok(data),
+                None => Err("Empty condition".into())
+            }
+        },
+        Rule::guardnot => {
+            match pair.into_inner().next() {
+                Some(data) => {
+                    match treat_guard(data) {
+                        Ok(guard) => Ok(ProxyGuard::NOT(Box::new(guard))),
+                        Err(e) => Err(e)
+                    }
+                },
+                None => Err("Empty condition after '!'".into())
+            }
+        },
+        Rule::guardcomparison => {
+            let mut inner = pair.into_inner();
+            let (left, operator, right) = match (inner.next(), inner.next(), inner.next()) {
+                (Some(left), Some(operator), Some(right)) => (left, operator, right),
+                _ => return Err("Expected 3 elements in comparison".into())
+            };
+            let operator = match operator.as_str() {
+                "==" => CompareOperator::EQ,
+                "!=" => CompareOperator::NE,
+                "<" => CompareOperator::LT,
+                "<=" => CompareOperator::LE,
+                ">" => CompareOperator::GT,
+                ">=" => CompareOperator::GE,
+                _ => return Err("Invalid comparison operator".into())
+            };
+            let left = treat_expression(left)?;
+            let right = treat_expression(right)?;
+            Ok(ProxyGuard::COMPARE(left, operator, right))
+        },
+        _ => Err(expression_error("Invalid condition", &pair))
+    }
+}
+
+/// The variables of a block and where they are declared
+#[derive(Clone, Copy)]
+pub(crate) struct VariableScope<'a> {
+    pub variables: &'a OrderedMap<MaterialVariableType>,
+    pub spans: &'a HashMap<String, SourceSpan>,
+}
+
+// A reference to a variable declared after it
+fn check_declared_before(name: &str, declaration: Option<&SourceSpan>, span: SourceSpan) -> Result<(), MaterialError> {
+    match declaration {
+        Some(declaration) if declaration.start > span.start => {
+            Err(MaterialError::with_related(format!("'${}' is used before it is declared, expressions can only reference earlier variables", name), span, *declaration))
+        },
+        _ => Ok(())
+    }
+}
+
+// Folds the expressions of a block once all its variables are known,
+// references are looked up in the block first, then in the blocks around it
+struct Folder<'a> {
+    material: &'a MaterialFile,
+    spans: &'a HashMap<String, SourceSpan>, // declarations of the block
+    outer: &'a [VariableScope<'a>],
+    pending: HashMap<String, Expression>,
+    folded: HashMap<String, MaterialVariableType>,
+    stack: Vec<String>,
+}
+
+impl<'a> Folder<'a> {
+    fn resolve(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        if self.material.variables.contains_key(name) {
+            check_declared_before(name, self.spans.get(name), span)?;
+        }
+        if let Some(value) = self.folded.get(name) {
+            return Ok(value.clone())
+        }
+        if let Some(expression) = self.pending.get(name).cloned() {
+            if self.stack.iter().any(|n| n == name) {
+                self.stack.push(name.to_owned());
+                let cycle = self.stack.iter().map(|n| format!("${}", n)).collect::<Vec<_>>().join(" -> ");
+                return Err(MaterialError::new(format!("Cycle between variables: {}", cycle), span))
+            }
+            self.stack.push(name.to_owned());
+            let value = expression.evaluate(self)?;
+            self.stack.pop();
+            let value = match self.material.declared_types.get(name) {
+                Some(kind) => match value.coerce(*kind) {
+                    Some(data) => data,
+                    None => return Err(MaterialError::new(format!("Type mismatch: '${}' is declared as '{}' but its value is a '{}'", name, kind.name(), value.kind().name()), expression.span))
+                },
+                None => value
+            };
+            self.folded.insert(name.to_owned(), value.clone());
+            return Ok(value)
+        }
+        if let Some(value) = self.material.variables.get(name) {
+            return Ok(value.clone())
+        }
+        for scope in self.outer.iter().rev() {
+            if let Some(value) = scope.variables.get(name) {
+                check_declared_before(name, scope.spans.get(name), span)?;
+                return Ok(value.clone())
+            }
+        }
+        Err(MaterialError::new(format!("Unknown variable '${}'", name), span))
+    }
+}
+
+impl<'a> ExpressionContext for Folder<'a> {
+    fn variable(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        self.resolve(name, span)
+    }
+}
+
+/// Folds expressions referencing variables, 'spans' holds where the variables of the block are declared
+/// and 'outer' the variables of the enclosing blocks, outermost first
+pub(crate) fn fold_variables(material: &mut MaterialFile, spans: &HashMap<String, SourceSpan>, pending: Vec<(String, Expression)>, outer: &[VariableScope<'_>]) -> Result<(), MaterialError> {
+    let names: Vec<String> = pending.iter().map(|(name, _)| name.clone()).collect();
+    let folded = {
+        let mut folder = Folder {
+            material,
+            spans,
+            outer,
+            pending: pending.into_iter().collect(),
+            folded: HashMap::new(),
+            stack: Vec::new(),
+        };
+        for name in &names {
+            let span = folder.pending[name].span;
+            match folder.resolve(name, span) {
+                Ok(_) => {},
+                Err(e) => return Err(e)
+            }
+        }
+        folder.folded
+    };
+    for (name, value) in folded {
+        material.variables.insert(name, value);
+    }
+    Ok(())
+}
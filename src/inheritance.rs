@@ -0,0 +1,194 @@
+// Material inheritance: UnlitGeneric : "base/unlit_red" { ... }
+// A derived material starts from the effective material of its parent, its variables override
+// the inherited ones and its proxy blocks are appended to the inherited ones unless marked 'override'
+use crate::conditions::Defines;
+use crate::{parse_material_file, MaterialError, MaterialFile, MaterialProxy, RENDER_PHASE, SETUP_PHASE};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Provides the source of a material from the name written after ':'
+pub trait MaterialLoader {
+    fn load(&mut self, name: &str) -> Result<String, MaterialError>;
+
+    /// The parsed material, loaders holding parsed materials return them without going through a source
+    fn load_parsed(&mut self, name: &str) -> Result<MaterialFile, MaterialError> {
+        let source = self.load(name)?;
+        match parse_material_file(&source) {
+            Ok(data) => Ok(data),
+            Err(e) => Err(MaterialError {
+                message: format!("In '{}': {}", name, e.message),
+                span: e.span,
+                related: e.related,
+            })
+        }
+    }
+}
+
+/// Loads `<root>/<name>.smf` from the disk
+pub struct FileSystemLoader {
+    pub root: PathBuf,
+}
+
+impl FileSystemLoader {
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileSystemLoader {
+        FileSystemLoader {
+            root: root.into(),
+        }
+    }
+}
+
+impl MaterialLoader for FileSystemLoader {
+    fn load(&mut self, name: &str) -> Result<String, MaterialError> {
+        let path = self.root.join(format!("{}.smf", name));
+        match std::fs::read_to_string(&path) {
+            Ok(data) => Ok(data),
+            Err(e) => Err(format!("Could not load material '{}' from '{}': {}", name, path.display(), e).into())
+        }
+    }
+}
+
+/// Sources kept in memory, keyed by material name
+impl MaterialLoader for HashMap<String, String> {
+    fn load(&mut self, name: &str) -> Result<String, MaterialError> {
+        match self.get(name) {
+            Some(data) => Ok(data.clone()),
+            None => Err(format!("Unknown material '{}'", name).into())
+        }
+    }
+}
+
+/// A material with its inheritance chain flattened
+#[derive(Debug, Clone)]
+pub struct ResolvedMaterial {
+    /// The effective material, it has no parent
+    pub material: MaterialFile,
+    /// Names of the materials of the chain, from the root base to the material itself
+    pub chain: Vec<String>,
+    /// Material of the chain that set the effective value of each variable
+    pub variable_origins: HashMap<String, String>,
+    /// Material of the chain that declared each proxy, keyed by phase, same order as the proxies
+    pub proxy_origins: HashMap<String, Vec<String>>,
+}
+
+impl ResolvedMaterial {
+    pub fn variable_origin(&self, name: &str) -> Option<&str> {
+        self.variable_origins.get(name).map(|origin| origin.as_str())
+    }
+
+    pub fn proxy_origin(&self, phase: &str, index: usize) -> Option<&str> {
+        match self.proxy_origins.get(phase) {
+            Some(origins) => origins.get(index).map(|origin| origin.as_str()),
+            None => None
+        }
+    }
+
+    pub fn setup_proxy_origin(&self, index: usize) -> Option<&str> {
+        self.proxy_origin(SETUP_PHASE, index)
+    }
+
+    pub fn render_proxy_origin(&self, index: usize) -> Option<&str> {
+        self.proxy_origin(RENDER_PHASE, index)
+    }
+}
+
+fn inherit_proxies(proxies: &mut Vec<MaterialProxy>, origins: &mut Vec<String>, derived: Vec<MaterialProxy>, override_parent: bool, name: &str) {
+    if override_parent {
+        proxies.clear();
+        origins.clear();
+    }
+    origins.extend(derived.iter().map(|_| name.to_owned()));
+    proxies.extend(derived);
+}
+
+fn inherit(resolved: &mut ResolvedMaterial, material: MaterialFile, name: &str) -> Result<(), MaterialError> {
+    let effective = &mut resolved.material;
+    effective.shader = material.shader;
+    for (varname, kind) in material.declared_types {
+        effective.declared_types.insert(varname, kind);
+    }
+    for (varname, value) in material.variables {
+        // a type declared by a parent also applies to the overrides
+        match effective.set_variable(&varname, value) {
+            Ok(_) => {},
+            Err(e) => return Err(format!("In '{}': {}", name, e.message).into())
+        }
+        resolved.variable_origins.insert(varname, name.to_owned());
+    }
+    for (phase, block) in material.phases {
+        let origins = resolved.proxy_origins.entry(phase.clone()).or_default();
+        inherit_proxies(effective.proxies_mut(&phase), origins, block.proxies, block.override_parent, name);
+    }
+    // without defines only the material itself may have conditionals, they are kept
+    effective.conditionals.extend(material.conditionals);
+    resolved.chain.push(name.to_owned());
+    Ok(())
+}
+
+fn resolve(material: MaterialFile, name: &str, loader: &mut dyn MaterialLoader, stack: &mut Vec<String>, defines: Option<&Defines>) -> Result<ResolvedMaterial, MaterialError> {
+    if stack.iter().any(|n| n == name) {
+        stack.push(name.to_owned());
+        return Err(format!("Inheritance cycle: {}", stack.join(" -> ")).into())
+    }
+    stack.push(name.to_owned());
+
+    let mut resolved = match &material.parent {
+        Some(parent_name) => {
+            let parent = loader.load_parsed(parent_name)?;
+            let resolved = resolve(parent, parent_name, loader, stack, defines)?;
+            // the #if blocks of the parent would be applied after the declarations of the material
+            if !resolved.material.conditionals.is_empty() {
+                return Err(format!("In '{}': '{}' has #if blocks, the material must be resolved with defines", name, parent_name).into())
+            }
+            resolved
+        },
+        None => ResolvedMaterial {
+            material: MaterialFile::new(String::new()),
+            chain: Vec::new(),
+            variable_origins: HashMap::new(),
+            proxy_origins: HashMap::new(),
+        }
+    };
+
+    // with defines each material of the chain is evaluated before being inherited from
+    let material = match defines {
+        Some(defines) => {
+            match material.evaluate_conditions(defines) {
+                Ok(data) => data,
+                Err(e) => return Err(format!("In '{}': {}", name, e.message).into())
+            }
+        },
+        None => material
+    };
+    match inherit(&mut resolved, material, name) {
+        Ok(_) => {},
+        Err(e) => return Err(e)
+    }
+    stack.pop();
+    Ok(resolved)
+}
+
+/// Flattens the inheritance chain of an already parsed material, 'name' is only used for cycle detection and origins.
+/// The conditionals of the material are kept, the other materials of the chain must not have any
+pub fn resolve_material(material: MaterialFile, name: &str, loader: &mut dyn MaterialLoader) -> Result<ResolvedMaterial, MaterialError> {
+    resolve(material, name, loader, &mut Vec::new(), None)
+}
+
+/// Same as resolve_material, the conditionals of every material of the chain are evaluated with the defines
+pub fn resolve_material_with_defines(material: MaterialFile, name: &str, loader: &mut dyn MaterialLoader, defines: &Defines) -> Result<ResolvedMaterial, MaterialError> {
+    resolve(material, name, loader, &mut Vec::new(), Some(defines))
+}
+
+fn load(name: &str, loader: &mut dyn MaterialLoader, defines: Option<&Defines>) -> Result<ResolvedMaterial, MaterialError> {
+    let material = loader.load_parsed(name)?;
+    resolve(material, name, loader, &mut Vec::new(), defines)
+}
+
+/// Loads a material through the loader and flattens its inheritance chain, see resolve_material
+pub fn load_material(name: &str, loader: &mut dyn MaterialLoader) -> Result<ResolvedMaterial, MaterialError> {
+    load(name, loader, None)
+}
+
+/// Same as load_material, the conditionals of every material of the chain are evaluated with the defines
+pub fn load_material_with_defines(name: &str, loader: &mut dyn MaterialLoader, defines: &Defines) -> Result<ResolvedMaterial, MaterialError> {
+    load(name, loader, Some(defines))
+}
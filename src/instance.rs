@@ -0,0 +1,228 @@
+// Material instances: the state of a material drawn by the engine
+// The host triggers the phases of the material on an instance: Setup once when the material is
+// created, Render every frame and any other phase (OnDamage, PerTick...) on events
+// Instances share the parsed material, which is never modified. An instance only stores the values
+// it overrides: the ones set by the engine and the ones written by its proxies
+// Variables whose value changed are flagged dirty until the engine clears the flags, after uploading them
+// The random numbers of the proxies come from the seed of the instance, see random.rs
+use crate::random::ProxyRng;
+use crate::runtime::{run_proxies, DirtyFlags, ProxyHost, ProxyRegistry, VariableStore};
+use crate::{MaterialError, MaterialFile, MaterialVariableType, RENDER_PHASE, SETUP_PHASE};
+use std::sync::Arc;
+
+pub struct MaterialInstance {
+    material: Arc<MaterialFile>,
+    overrides: Vec<(u32, MaterialVariableType)>, // index of the variable in the material, sorted by index
+    setup_done: bool,
+    seed: u64,
+    rngs: Vec<ProxyRng>, // streams of the phases, in the order of the phases of the material
+    dirty: DirtyFlags, // by index of the variable in the material
+}
+
+impl MaterialInstance {
+    /// An instance with the seed 0
+    pub fn new(material: Arc<MaterialFile>) -> MaterialInstance {
+        MaterialInstance::with_seed(material, 0)
+    }
+
+    pub fn with_seed(material: Arc<MaterialFile>, seed: u64) -> MaterialInstance {
+        MaterialInstance {
+            overrides: Vec::new(),
+            setup_done: false,
+            seed,
+            rngs: phase_streams(&material, seed),
+            dirty: DirtyFlags::all(material.variables.len()),
+            material,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restarts the random streams of every phase from a new seed
+    pub fn reseed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rngs = phase_streams(&self.material, seed);
+    }
+
+    /// The shared material, its variables have their declared values
+    pub fn material(&self) -> &Arc<MaterialFile> {
+        &self.material
+    }
+
+    /// The shared material with the values of the instance
+    pub fn to_material(&self) -> MaterialFile {
+        let mut material = (*self.material).clone();
+        for (index, value) in &self.overrides {
+            if let Some((name, _)) = self.material.variables.get_index(*index as usize) {
+                material.variables.insert(name.clone(), value.clone());
+            }
+        }
+        material
+    }
+
+    // Position of the override of a variable, or where it would be inserted
+    fn find(&self, name: &str) -> Option<Result<usize, usize>> {
+        self.material.variables.index_of(name).map(|index| self.overrides.binary_search_by_key(&(index as u32), |(i, _)| *i))
+    }
+
+    // Value of the variable at 'index' in the material
+    fn value_at(&self, index: usize) -> Option<&MaterialVariableType> {
+        match self.overrides.binary_search_by_key(&(index as u32), |(i, _)| *i) {
+            Ok(position) => Some(&self.overrides[position].1),
+            Err(_) => self.material.variables.get_index(index).map(|(_, value)| value)
+        }
+    }
+
+    pub fn variable(&self, name: &str) -> Option<&MaterialVariableType> {
+        match self.find(name) {
+            Some(Ok(position)) => Some(&self.overrides[position].1),
+            Some(Err(_)) => self.material.variables.get(name),
+            None => None
+        }
+    }
+
+    /// Overrides the value of a variable of the material, converted to its declared type. Setting the
+    /// value of the shared material drops the override. The variable becomes dirty if its value changes
+    pub fn set_variable(&mut self, name: &str, value: MaterialVariableType) -> Result<(), MaterialError> {
+        let index = match self.material.variables.index_of(name) {
+            Some(data) => data as u32,
+            None => return Err(format!("Unknown variable '${}'", name).into())
+        };
+        let value = match self.material.declared_types.get(name) {
+            Some(kind) => {
+                match value.coerce(*kind) {
+                    Some(data) => data,
+                    None => return Err(format!("Type mismatch: '${}' is declared as '{}' but its value is a '{}'", name, kind.name(), value.kind().name()).into())
+                }
+            },
+            None => value
+        };
+        if self.value_at(index as usize) != Some(&value) {
+            self.dirty.mark(index as usize);
+        }
+        let shared = self.material.variables.get_index(index as usize).map(|(_, data)| data);
+        match (self.overrides.binary_search_by_key(&index, |(i, _)| *i), shared == Some(&value)) {
+            (Ok(position), true) => {
+                self.overrides.remove(position);
+            },
+            (Ok(position), false) => self.overrides[position].1 = value,
+            (Err(_), true) => {},
+            (Err(position), false) => self.overrides.insert(position, (index, value))
+        }
+        Ok(())
+    }
+
+    pub fn is_overridden(&self, name: &str) -> bool {
+        matches!(self.find(name), Some(Ok(_)))
+    }
+
+    /// Names of the variables the instance overrides, in declaration order
+    pub fn overridden(&self) -> impl Iterator<Item = &String> {
+        let variables = &self.material.variables;
+        self.overrides.iter().filter_map(move |(index, _)| variables.get_index(*index as usize).map(|(name, _)| name))
+    }
+
+    /// Gives a variable its value in the shared material back
+    pub fn reset_variable(&mut self, name: &str) {
+        if let Some(Ok(position)) = self.find(name) {
+            let (index, value) = self.overrides.remove(position);
+            if self.material.variables.get_index(index as usize).map(|(_, shared)| shared) != Some(&value) {
+                self.dirty.mark(index as usize);
+            }
+        }
+    }
+
+    /// Drops every override and restarts the random streams, Setup runs again on the next render.
+    /// Every variable becomes dirty
+    pub fn reset(&mut self) {
+        self.overrides.clear();
+        self.setup_done = false;
+        self.rngs = phase_streams(&self.material, self.seed);
+        self.dirty = DirtyFlags::all(self.material.variables.len());
+    }
+
+    /// Variables whose value changed since the flags were cleared, with their value, in declaration
+    /// order. Every variable is dirty when the instance is created
+    pub fn dirty(&self) -> impl Iterator<Item = (&String, &MaterialVariableType)> {
+        self.dirty.iter().filter_map(move |index| {
+            match (self.material.variables.get_index(index), self.value_at(index)) {
+                (Some((name, _)), Some(value)) => Some((name, value)),
+                _ => None
+            }
+        })
+    }
+
+    pub fn is_dirty(&self, name: &str) -> bool {
+        self.material.variables.index_of(name).is_some_and(|index| self.dirty.contains(index))
+    }
+
+    /// Clears the dirty flags, once the engine uploaded the dirty variables
+    pub fn clear_dirty(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Names of the phases the material has proxies for, in declaration order
+    pub fn phases(&self) -> impl Iterator<Item = &String> {
+        self.material.phases.keys()
+    }
+
+    /// Runs the proxies of a phase, a phase the material has no block for does nothing.
+    /// The random numbers come from the stream of the phase
+    pub fn trigger(&mut self, phase: &str, registry: &ProxyRegistry, host: &mut dyn ProxyHost) -> Result<(), MaterialError> {
+        // The proxies are borrowed from the shared material while the instance is written
+        let material = Arc::clone(&self.material);
+        let index = match material.phases.index_of(phase) {
+            Some(data) => data,
+            None => return Ok(())
+        };
+        let proxies = match material.phases.get_index(index) {
+            Some((_, block)) => &block.proxies,
+            None => return Ok(())
+        };
+        // The stream is copied out while the instance is borrowed by the proxies
+        let mut rng = self.rngs[index];
+        let result = run_proxies(proxies, self, registry, host, &mut rng);
+        self.rngs[index] = rng;
+        result
+    }
+
+    /// Runs the Setup phase until it succeeds once, a failed Setup runs again on the next call
+    pub fn setup(&mut self, registry: &ProxyRegistry, host: &mut dyn ProxyHost) -> Result<(), MaterialError> {
+        if self.setup_done {
+            return Ok(())
+        }
+        match self.trigger(SETUP_PHASE, registry, host) {
+            Ok(_) => {
+                self.setup_done = true;
+                Ok(())
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Runs the Render phase, runs the Setup phase first if it has not run yet
+    pub fn render(&mut self, registry: &ProxyRegistry, host: &mut dyn ProxyHost) -> Result<(), MaterialError> {
+        match self.setup(registry, host) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+        self.trigger(RENDER_PHASE, registry, host)
+    }
+}
+
+// A stream for every phase of the material, see ProxyRng::for_phase
+fn phase_streams(material: &MaterialFile, seed: u64) -> Vec<ProxyRng> {
+    material.phases.keys().map(|phase| ProxyRng::for_phase(seed, phase)).collect()
+}
+
+impl VariableStore for MaterialInstance {
+    fn variable(&self, name: &str) -> Option<&MaterialVariableType> {
+        MaterialInstance::variable(self, name)
+    }
+
+    fn write_variable(&mut self, name: &str, value: MaterialVariableType) -> Result<(), MaterialError> {
+        self.set_variable(name, value)
+    }
+}
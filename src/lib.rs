@@ -0,0 +1,1616 @@
+extern crate pest;
+#[macro_use]
+extern crate pest_derive;
+
+use conditions::{CompareOperator, ConditionOperand, MaterialCondition, MaterialConditional};
+use expression::{fold_variables, treat_expression, treat_guard, Expression, ProxyGuard, VariableScope};
+use ordered_map::OrderedMap;
+use pest::Parser;
+use template::Declarations;
+use std::collections::HashMap;
+use std::fmt;
+
+pub mod bytecode;
+pub mod composite;
+pub mod conditions;
+pub mod dataflow;
+pub mod disassembler;
+pub mod dot;
+pub mod expression;
+pub mod inheritance;
+pub mod instance;
+pub mod library;
+pub mod optimize;
+pub mod ordered_map;
+pub mod patch;
+pub mod random;
+pub mod runtime;
+pub mod simulation;
+pub mod template;
+pub mod writer;
+
+#[derive(Parser)]
+#[grammar = "grammar.pest"]
+pub struct SMFParser;
+
+/// Location of a construct in the source of a material file
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SourceSpan {
+    pub start: usize, // byte offsets
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl SourceSpan {
+    pub fn from_pest(span: &pest::Span<'_>) -> SourceSpan {
+        let (line, column) = span.start_pos().line_col();
+        SourceSpan {
+            start: span.start(),
+            end: span.end(),
+            line,
+            column,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MaterialError {
+    pub message: String,
+    pub span: Option<SourceSpan>,
+    pub related: Option<SourceSpan>, // e.g. the first declaration of a duplicate
+}
+
+impl MaterialError {
+    pub fn new(message: String, span: SourceSpan) -> MaterialError {
+        MaterialError {
+            message,
+            span: Some(span),
+            related: None,
+        }
+    }
+
+    pub fn with_related(message: String, span: SourceSpan, related: SourceSpan) -> MaterialError {
+        MaterialError {
+            message,
+            span: Some(span),
+            related: Some(related),
+        }
+    }
+}
+
+impl From<&'static str> for MaterialError {
+    fn from(message: &'static str) -> Self {
+        MaterialError {
+            message: message.to_owned(),
+            span: None,
+            related: None,
+        }
+    }
+}
+
+impl From<String> for MaterialError {
+    fn from(message: String) -> Self {
+        MaterialError {
+            message,
+            span: None,
+            related: None,
+        }
+    }
+}
+
+impl fmt::Display for MaterialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.span, &self.related) {
+            (Some(span), Some(related)) => write!(f, "{} (line {}, column {}; see line {}, column {})", self.message, span.line, span.column, related.line, related.column),
+            (Some(span), None) => write!(f, "{} (line {}, column {})", self.message, span.line, span.column),
+            _ => write!(f, "{}", self.message)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum MaterialVariableType {
+    NONE,
+    FLOAT (f32),
+    DOUBLE (f64),
+    INTEGER (i32),
+    STRING (String),
+
+    ARRAY2 (i32, i32),
+    ARRAY3 (i32, i32, i32),
+    ARRAY4 (i32, i32, i32, i32),
+
+    ARRAY2F (f32, f32),
+    ARRAY3F (f32, f32, f32),
+    ARRAY4F (f32, f32, f32, f32),
+
+    ARRAY2D (f64, f64),
+    ARRAY3D (f64, f64, f64),
+    ARRAY4D (f64, f64, f64, f64),
+
+    LIST (Vec<MaterialVariableType>),
+    MAP (Vec<(String, MaterialVariableType)>), // in declaration order, keys are unique
+}
+
+impl MaterialVariableType {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MaterialVariableType::STRING(s) => Some(s),
+            _ => None
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[MaterialVariableType]> {
+        match self {
+            MaterialVariableType::LIST(values) => Some(values),
+            _ => None
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&[(String, MaterialVariableType)]> {
+        match self {
+            MaterialVariableType::MAP(entries) => Some(entries),
+            _ => None
+        }
+    }
+
+    /// Looks up a key of a MAP, None for any other type
+    pub fn get(&self, key: &str) -> Option<&MaterialVariableType> {
+        match self.as_map() {
+            Some(entries) => entries.iter().find(|entry| entry.0 == key).map(|entry| &entry.1),
+            None => None
+        }
+    }
+
+    /// Looks up a dot separated path through nested MAPs, e.g. "sampler.wrap"
+    pub fn get_path(&self, path: &str) -> Option<&MaterialVariableType> {
+        let mut value = self;
+        for key in path.split('.') {
+            value = value.get(key)?;
+        }
+        Some(value)
+    }
+}
+
+/// Type of a MaterialVariableType without its data, used by type annotations
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum MaterialVariableKind {
+    NONE,
+    FLOAT,
+    DOUBLE,
+    INTEGER,
+    STRING,
+
+    ARRAY2,
+    ARRAY3,
+    ARRAY4,
+
+    ARRAY2F,
+    ARRAY3F,
+    ARRAY4F,
+
+    ARRAY2D,
+    ARRAY3D,
+    ARRAY4D,
+
+    LIST,
+    MAP,
+}
+
+impl MaterialVariableKind {
+    /// Parses the name used in type annotations: `$tint: vec3f [1,1,1]`
+    pub fn from_name(name: &str) -> Option<MaterialVariableKind> {
+        match name {
+            "float" => Some(MaterialVariableKind::FLOAT),
+            "double" => Some(MaterialVariableKind::DOUBLE),
+            "int" => Some(MaterialVariableKind::INTEGER),
+            "string" => Some(MaterialVariableKind::STRING),
+            "vec2" => Some(MaterialVariableKind::ARRAY2),
+            "vec3" => Some(MaterialVariableKind::ARRAY3),
+            "vec4" => Some(MaterialVariableKind::ARRAY4),
+            "vec2f" => Some(MaterialVariableKind::ARRAY2F),
+            "vec3f" => Some(MaterialVariableKind::ARRAY3F),
+            "vec4f" => Some(MaterialVariableKind::ARRAY4F),
+            "vec2d" => Some(MaterialVariableKind::ARRAY2D),
+            "vec3d" => Some(MaterialVariableKind::ARRAY3D),
+            "vec4d" => Some(MaterialVariableKind::ARRAY4D),
+            "list" => Some(MaterialVariableKind::LIST),
+            "map" => Some(MaterialVariableKind::MAP),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            MaterialVariableKind::NONE => "none",
+            MaterialVariableKind::FLOAT => "float",
+            MaterialVariableKind::DOUBLE => "double",
+            MaterialVariableKind::INTEGER => "int",
+            MaterialVariableKind::STRING => "string",
+            MaterialVariableKind::ARRAY2 => "vec2",
+            MaterialVariableKind::ARRAY3 => "vec3",
+            MaterialVariableKind::ARRAY4 => "vec4",
+            MaterialVariableKind::ARRAY2F => "vec2f",
+            MaterialVariableKind::ARRAY3F => "vec3f",
+            MaterialVariableKind::ARRAY4F => "vec4f",
+            MaterialVariableKind::ARRAY2D => "vec2d",
+            MaterialVariableKind::ARRAY3D => "vec3d",
+            MaterialVariableKind::ARRAY4D => "vec4d",
+            MaterialVariableKind::LIST => "list",
+            MaterialVariableKind::MAP => "map",
+        }
+    }
+}
+
+impl MaterialVariableType {
+    pub fn kind(&self) -> MaterialVariableKind {
+        match self {
+            MaterialVariableType::NONE => MaterialVariableKind::NONE,
+            MaterialVariableType::FLOAT(_) => MaterialVariableKind::FLOAT,
+            MaterialVariableType::DOUBLE(_) => MaterialVariableKind::DOUBLE,
+            MaterialVariableType::INTEGER(_) => MaterialVariableKind::INTEGER,
+            MaterialVariableType::STRING(_) => MaterialVariableKind::STRING,
+            MaterialVariableType::ARRAY2(..) => MaterialVariableKind::ARRAY2,
+            MaterialVariableType::ARRAY3(..) => MaterialVariableKind::ARRAY3,
+            MaterialVariableType::ARRAY4(..) => MaterialVariableKind::ARRAY4,
+            MaterialVariableType::ARRAY2F(..) => MaterialVariableKind::ARRAY2F,
+            MaterialVariableType::ARRAY3F(..) => MaterialVariableKind::ARRAY3F,
+            MaterialVariableType::ARRAY4F(..) => MaterialVariableKind::ARRAY4F,
+            MaterialVariableType::ARRAY2D(..) => MaterialVariableKind::ARRAY2D,
+            MaterialVariableType::ARRAY3D(..) => MaterialVariableKind::ARRAY3D,
+            MaterialVariableType::ARRAY4D(..) => MaterialVariableKind::ARRAY4D,
+            MaterialVariableType::LIST(_) => MaterialVariableKind::LIST,
+            MaterialVariableType::MAP(_) => MaterialVariableKind::MAP,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            MaterialVariableType::FLOAT(n) => Some(*n as f64),
+            MaterialVariableType::DOUBLE(n) => Some(*n),
+            MaterialVariableType::INTEGER(n) => Some(*n as f64),
+            _ => None
+        }
+    }
+
+    // Scalar components of a vector, or of a list made only of numbers
+    pub(crate) fn components(&self) -> Option<Vec<MaterialVariableType>> {
+        use MaterialVariableType::*;
+        match self {
+            ARRAY2(a, b) => Some(vec![INTEGER(*a), INTEGER(*b)]),
+            ARRAY3(a, b, c) => Some(vec![INTEGER(*a), INTEGER(*b), INTEGER(*c)]),
+            ARRAY4(a, b, c, d) => Some(vec![INTEGER(*a), INTEGER(*b), INTEGER(*c), INTEGER(*d)]),
+            ARRAY2F(a, b) => Some(vec![FLOAT(*a), FLOAT(*b)]),
+            ARRAY3F(a, b, c) => Some(vec![FLOAT(*a), FLOAT(*b), FLOAT(*c)]),
+            ARRAY4F(a, b, c, d) => Some(vec![FLOAT(*a), FLOAT(*b), FLOAT(*c), FLOAT(*d)]),
+            ARRAY2D(a, b) => Some(vec![DOUBLE(*a), DOUBLE(*b)]),
+            ARRAY3D(a, b, c) => Some(vec![DOUBLE(*a), DOUBLE(*b), DOUBLE(*c)]),
+            ARRAY4D(a, b, c, d) => Some(vec![DOUBLE(*a), DOUBLE(*b), DOUBLE(*c), DOUBLE(*d)]),
+            LIST(values) => {
+                if values.iter().all(|v| v.as_f64().is_some()) {
+                    Some(values.clone())
+                } else {
+                    None
+                }
+            },
+            _ => None
+        }
+    }
+
+    /// Converts the value to the given kind. Integers convert to floating point, floats and doubles
+    /// convert to each other, vectors convert element-wise and to lists. Returns None if the conversion
+    /// is not allowed. Converting to float rounds doubles and integers above 2^24 to the nearest float,
+    /// like the literal 0.1 of `$scale: float 0.1` which is read as a double.
+    /// Floating point never converts to integers
+    pub fn coerce(&self, kind: MaterialVariableKind) -> Option<MaterialVariableType> {
+        use MaterialVariableType::*;
+        if self.kind() == kind {
+            return Some(self.clone())
+        }
+        let f = |v: &MaterialVariableType| v.as_f64().map(|n| n as f32);
+        let d = |v: &MaterialVariableType| v.as_f64();
+
+        match kind {
+            MaterialVariableKind::FLOAT => f(self).map(FLOAT),
+            MaterialVariableKind::DOUBLE => d(self).map(DOUBLE),
+            MaterialVariableKind::LIST => self.components().map(LIST),
+            MaterialVariableKind::NONE | MaterialVariableKind::INTEGER | MaterialVariableKind::STRING | MaterialVariableKind::MAP => None,
+            _ => match self {
+                LIST(values) => vector_from(kind, values),
+                _ => {
+                    let (parts, len) = self.vector_parts()?;
+                    vector_from(kind, &parts[..len])
+                }
+            }
+        }
+    }
+
+    /// The components of a vector in a fixed array with their count, lists are not vectors here.
+    /// Unlike components it does not allocate, the operations of compiled programs use it
+    pub(crate) fn vector_parts(&self) -> Option<([MaterialVariableType; 4], usize)> {
+        use MaterialVariableType::*;
+        match *self {
+            ARRAY2(a, b) => Some(([INTEGER(a), INTEGER(b), NONE, NONE], 2)),
+            ARRAY3(a, b, c) => Some(([INTEGER(a), INTEGER(b), INTEGER(c), NONE], 3)),
+            ARRAY4(a, b, c, d) => Some(([INTEGER(a), INTEGER(b), INTEGER(c), INTEGER(d)], 4)),
+            ARRAY2F(a, b) => Some(([FLOAT(a), FLOAT(b), NONE, NONE], 2)),
+            ARRAY3F(a, b, c) => Some(([FLOAT(a), FLOAT(b), FLOAT(c), NONE], 3)),
+            ARRAY4F(a, b, c, d) => Some(([FLOAT(a), FLOAT(b), FLOAT(c), FLOAT(d)], 4)),
+            ARRAY2D(a, b) => Some(([DOUBLE(a), DOUBLE(b), NONE, NONE], 2)),
+            ARRAY3D(a, b, c) => Some(([DOUBLE(a), DOUBLE(b), DOUBLE(c), NONE], 3)),
+            ARRAY4D(a, b, c, d) => Some(([DOUBLE(a), DOUBLE(b), DOUBLE(c), DOUBLE(d)], 4)),
+            _ => None
+        }
+    }
+}
+
+// Builds a vector of the given kind from its components, None if a component does not convert
+pub(crate) fn vector_from(kind: MaterialVariableKind, c: &[MaterialVariableType]) -> Option<MaterialVariableType> {
+    use MaterialVariableType::*;
+    let i = |v: &MaterialVariableType| match v {
+        INTEGER(n) => Some(*n),
+        _ => None
+    };
+    let f = |v: &MaterialVariableType| v.as_f64().map(|n| n as f32);
+    let d = |v: &MaterialVariableType| v.as_f64();
+    match (kind, c.len()) {
+        (MaterialVariableKind::ARRAY2, 2) => Some(ARRAY2(i(&c[0])?, i(&c[1])?)),
+        (MaterialVariableKind::ARRAY3, 3) => Some(ARRAY3(i(&c[0])?, i(&c[1])?, i(&c[2])?)),
+        (MaterialVariableKind::ARRAY4, 4) => Some(ARRAY4(i(&c[0])?, i(&c[1])?, i(&c[2])?, i(&c[3])?)),
+        (MaterialVariableKind::ARRAY2F, 2) => Some(ARRAY2F(f(&c[0])?, f(&c[1])?)),
+        (MaterialVariableKind::ARRAY3F, 3) => Some(ARRAY3F(f(&c[0])?, f(&c[1])?, f(&c[2])?)),
+        (MaterialVariableKind::ARRAY4F, 4) => Some(ARRAY4F(f(&c[0])?, f(&c[1])?, f(&c[2])?, f(&c[3])?)),
+        (MaterialVariableKind::ARRAY2D, 2) => Some(ARRAY2D(d(&c[0])?, d(&c[1])?)),
+        (MaterialVariableKind::ARRAY3D, 3) => Some(ARRAY3D(d(&c[0])?, d(&c[1])?, d(&c[2])?)),
+        (MaterialVariableKind::ARRAY4D, 4) => Some(ARRAY4D(d(&c[0])?, d(&c[1])?, d(&c[2])?, d(&c[3])?)),
+        _ => None
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]pub enum MaterialVariableReference { // Can be a value or reference a variable
+    TYPE (MaterialVariableType),
+    VARIABLE(String),
+    ARRAYREF (String, u32),
+    EXPRESSION (Expression), // only in 'Expression' proxies
+}
+
+/// Name of the proxy an assignment `$health = entity.health / 100` is lowered to,
+/// its parameters are 'expression' and 'resultvar'
+pub const EXPRESSION_PROXY: &str = "Expression";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MaterialProxy {
+    pub name: String,
+    pub parameters: OrderedMap<MaterialVariableReference>, // in declaration order
+    pub guard: Option<ProxyGuard>, // the proxy only runs if it holds
+    pub span: Option<SourceSpan>, // None if the proxy was not parsed
+}
+/// Phase of the proxies of a SetupProxies block, they run once when the material is applied
+pub const SETUP_PHASE: &str = "Setup";
+/// Phase of the proxies of a RenderProxies block, they run every time the object is rendered
+pub const RENDER_PHASE: &str = "Render";
+
+/// Proxies of a <phase>Proxies block, other phases than Setup and Render are triggered by the host
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProxyBlock {
+    pub proxies: Vec<MaterialProxy>,
+    // 'override' blocks replace the proxies of the parent instead of being appended to them
+    pub override_parent: bool,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MaterialFile {
+    pub shader: String,
+    pub parent: Option<String>, // UnlitGeneric : "base/unlit_red" { ... }
+    pub variables: OrderedMap<MaterialVariableType>, // in declaration order
+    pub declared_types: HashMap<String, MaterialVariableKind>, // only annotated variables
+    pub phases: OrderedMap<ProxyBlock>, // keyed by phase name, in declaration order
+    // #if blocks, applied over the rest of the material by MaterialFile::evaluate_conditions
+    pub conditionals: Vec<MaterialConditional>,
+}
+
+impl MaterialFile {
+    pub fn new(shader: String) -> MaterialFile {
+        MaterialFile {
+            shader,
+            parent: None,
+            variables: OrderedMap::new(),
+            declared_types: HashMap::new(),
+            phases: OrderedMap::new(),
+            conditionals: Vec::new(),
+        }
+    }
+
+    /// Sets a variable, converting the value to the declared type of the variable if it has one
+    pub fn set_variable(&mut self, name: &str, value: MaterialVariableType) -> Result<(), MaterialError> {
+        let value = match self.declared_types.get(name) {
+            Some(kind) => {
+                match value.coerce(*kind) {
+                    Some(data) => data,
+                    None => return Err(format!("Type mismatch: '${}' is declared as '{}' but its value is a '{}'", name, kind.name(), value.kind().name()).into())
+                }
+            },
+            None => value
+        };
+        self.variables.insert(name.to_owned(), value);
+        Ok(())
+    }
+
+    /// Proxies of a phase, empty if the material has no block for it
+    pub fn proxies(&self, phase: &str) -> &[MaterialProxy] {
+        match self.phases.get(phase) {
+            Some(block) => &block.proxies,
+            None => &[]
+        }
+    }
+
+    /// Proxies of a phase, the phase is added if the material has no block for it
+    pub fn proxies_mut(&mut self, phase: &str) -> &mut Vec<MaterialProxy> {
+        &mut self.phase_mut(phase).proxies
+    }
+
+    /// Block of a phase, the phase is added if the material has no block for it
+    pub fn phase_mut(&mut self, phase: &str) -> &mut ProxyBlock {
+        self.phases.get_or_insert_with(phase, ProxyBlock::default)
+    }
+
+    pub fn setup_proxies(&self) -> &[MaterialProxy] {
+        self.proxies(SETUP_PHASE)
+    }
+
+    pub fn render_proxies(&self) -> &[MaterialProxy] {
+        self.proxies(RENDER_PHASE)
+    }
+}
+
+pub(crate) fn var_to_string(pair: &mut pest::iterators::Pairs<'_, Rule>) -> Result<String, MaterialError> {
+    let p = pair.next();
+    match p {
+        Some(data) => {
+            match data.as_rule() {
+                Rule::ident => {
+                    Ok(data.as_str().to_owned())
+                },
+                _ => Err("Expected 'ident' in 'variable'".into())
+            }
+        },
+        None => Err("Empty variable".into())
+    }
+}
+
+fn treat_identblockstart(pair: &mut pest::iterators::Pairs<'_, Rule>, material: &mut MaterialFile) -> Result<(), MaterialError> {
+    match pair.next() {
+        Some(ident_pair) => {
+            match ident_pair.as_rule() {
+                Rule::ident => {
+                    material.shader = ident_pair.as_str().to_owned();
+                },
+                _ => return Err("Expected identifier inside of 'identblockstart'".into())
+            }
+        },
+        None => return Err("Empty identblockstart".into())
+    };
+    if let Some(parent_pair) = pair.next() {
+        match parent_pair.as_rule() {
+            Rule::parent => {
+                let string = match parent_pair.into_inner().next() {
+                    Some(data) => data,
+                    None => return Err("Empty parent".into())
+                };
+                match treat_value(string, false) {
+                    Ok(MaterialVariableType::STRING(parent)) => material.parent = Some(parent),
+                    Ok(_) => return Err("Expected string as parent material".into()),
+                    Err(e) => return Err(e)
+                }
+            },
+            _ => return Err("Expected parent in 'inheritblockstart'".into())
+        }
+    };
+    Ok(())
+}
+
+// Consumes the 'override' keyword of a proxy block if there is one
+pub(crate) fn treat_proxyoverride(pair: &mut pest::iterators::Pairs<'_, Rule>) -> bool {
+    match pair.peek() {
+        Some(data) if data.as_rule() == Rule::proxyoverride => {
+            pair.next();
+            true
+        },
+        _ => false
+    }
+}
+
+pub(crate) fn treat_arrayref(arrayref: &mut pest::iterators::Pairs<'_, Rule>) -> Result<MaterialVariableReference, MaterialError> {
+
+    let name;
+    let index;
+    match arrayref.next() {
+        Some(data) => {
+            match data.as_rule() {
+                Rule::ident => {
+                    name = data.as_str().to_owned();
+                }
+                _ => return Err("Expected 'ident' in 'arrayref'".into())
+            }
+        },
+        None => return Err("Empty arrayref".into())
+    }
+
+    match arrayref.next() {
+        Some(data) => {
+            match data.as_rule() {
+                Rule::integer => {
+                    let string = data.as_str().to_owned();
+                    index = match string.parse::<u32>() {
+                        Ok(data) => data,
+                        Err(_) => return Err("Integer parsing error".into())
+                    };
+                }
+                _ => return Err("Expected 'ident' in 'arrayref'".into())
+            }
+        },
+        None => return Err("Empty arrayref".into())
+    }
+
+    Ok(MaterialVariableReference::ARRAYREF(name, index))
+}
+
+fn treat_assignment(assignment: pest::iterators::Pair<'_, Rule>, arguments: &OrderedMap<MaterialVariableType>) -> Result<MaterialProxy, MaterialError> {
+    let span = SourceSpan::from_pest(&assignment.as_span());
+    let mut inner = assignment.into_inner();
+    let target = match inner.next() {
+        Some(data) => {
+            match data.as_rule() {
+                Rule::arrayref => treat_arrayref(&mut data.into_inner()),
+                _ => var_to_string(&mut data.into_inner()).map(MaterialVariableReference::VARIABLE)
+            }
+        },
+        None => return Err("Expected variable in assignment".into())
+    };
+    let target = target?;
+    let mut expression = match inner.next() {
+        Some(data) => {
+            treat_expression(data)?
+        },
+        None => return Err(MaterialError::new("Expected expression in assignment".to_owned(), span))
+    };
+    match expression.replace_parameters(arguments) {
+        Ok(_) => {},
+        Err(e) => return Err(e)
+    }
+    let guard = match inner.next() {
+        Some(data) => {
+            match treat_guard(data) {
+                Ok(mut guard) => {
+                    match guard.replace_parameters(arguments) {
+                        Ok(_) => Some(guard),
+                        Err(e) => return Err(e)
+                    }
+                },
+                Err(e) => return Err(e)
+            }
+        },
+        None => None
+    };
+    let mut parameters = OrderedMap::with_capacity(2);
+    parameters.insert("expression".to_owned(), MaterialVariableReference::EXPRESSION(expression));
+    parameters.insert("resultvar".to_owned(), target);
+    Ok(MaterialProxy {
+        name: EXPRESSION_PROXY.to_owned(),
+        parameters,
+        guard,
+        span: Some(span),
+    })
+}
+
+// Treats a 'proxy' or an 'assignment', 'arguments' are the values of the parameters of the template
+// the proxy is in, empty outside of templates
+pub(crate) fn treat_proxy(proxy: pest::iterators::Pair<'_, Rule>, arguments: &OrderedMap<MaterialVariableType>) -> Result<MaterialProxy, MaterialError> {
+    if proxy.as_rule() == Rule::assignment {
+        return treat_assignment(proxy, arguments)
+    }
+    let proxy_span = SourceSpan::from_pest(&proxy.as_span());
+    let mut name = String::new();
+    let mut guard = None;
+    let mut parameters = OrderedMap::new();
+    let mut spans: HashMap<String, SourceSpan> = HashMap::new();
+
+    let proxy = proxy.into_inner();
+
+    for element in proxy {
+        match element.as_rule() {
+            Rule::identblockstart => {
+                match element.into_inner().next() {
+                    Some(data) => {
+                        match data.as_rule() {
+                            Rule::ident => {
+                                name = data.as_str().to_owned();
+                            },
+                            _ => return Err("Expected 'ident' in 'identblockstart'".into())
+                        }
+                    },
+                    None => return Err("Invalid identblockstart".into())
+                }
+                
+            },
+            Rule::ident => {
+                name = element.as_str().to_owned();
+            },
+            Rule::guard => {
+                let mut data = treat_guard(element)?;
+                match data.replace_parameters(arguments) {
+                    Ok(_) => guard = Some(data),
+                    Err(e) => return Err(e)
+                }
+            },
+            Rule::proxyparam => {
+                let param_name: String;
+                //todo: remove ugly .clone()
+                match element.clone().into_inner().next() {
+                    Some(data) => {
+                        match data.as_rule() {
+                            Rule::ident => {
+                                param_name = data.as_str().to_owned();
+                            },
+                            _ => return Err("Expected ident in 'proxyparam'".into())
+                        }
+                    },
+                    None => return Err("Expected 2 elements in 'proxyparam'".into())
+                };
+                let span = SourceSpan::from_pest(&element.as_span());
+                if let Some(first) = spans.get(&param_name) {
+                    return Err(MaterialError::with_related(format!("Duplicate parameter '{}' in proxy '{}'", param_name, name), span, *first))
+                }
+                spans.insert(param_name.clone(), span);
+            
+                match element.into_inner().nth(1) {
+                    Some(data) => {
+                        match data.as_rule() {
+                            Rule::srcdest => {
+                                match data.into_inner().next() {
+                                    Some(srcdst) => {
+                                        match srcdst.as_rule() {
+                                            Rule::variable => {
+                                                let vts = var_to_string(&mut srcdst.into_inner());
+                                                match vts {
+                                                    Ok(varname) => {
+                                                        parameters.insert(param_name, MaterialVariableReference::VARIABLE(varname));
+                                                    },
+                                                    Err(e) => return Err(e)
+                                                }
+                                                
+                                            },
+                                            Rule::arrayref => {
+                                                let matvarref = treat_arrayref(&mut srcdst.into_inner())?;
+
+                                                parameters.insert(param_name, matvarref);
+                                            },
+                                            Rule::value => {
+                                                let srcdst = match srcdst.into_inner().next() {
+                                                    Some(data) => data,
+                                                    None => return Err("Empty value".into())
+                                                };
+                                                let type_ = treat_value(srcdst, false)?;
+                                                parameters.insert(param_name, MaterialVariableReference::TYPE(type_));
+                                            },
+                                            _ => return Err("Invalid srcdest".into())
+                                        }
+                                    },
+                                    None => return Err("Empty srcdest".into())
+                                }
+                            },
+                            _ => return Err("Expected srcdest in 'proxyparam'".into())
+                        }
+                    },
+                    None => return Err("Expected 2 elements in 'proxyparam'".into())
+                }
+                
+            },
+            _ => return Err("Invalid proxy".into())
+        }
+    }
+    
+    Ok(MaterialProxy {
+        name,
+        parameters,
+        guard,
+        span: Some(proxy_span),
+    })
+}
+
+// Returns the phase of a 'proxyblockstart', the names of the Setup and Render phases are not case sensitive
+pub(crate) fn treat_proxyphase(pair: &mut pest::iterators::Pairs<'_, Rule>) -> Result<String, MaterialError> {
+    let phase = match pair.next() {
+        Some(data) => {
+            match data.into_inner().next() {
+                Some(phase) => phase.as_str().to_owned(),
+                None => return Err("Invalid proxy block name".into())
+            }
+        },
+        None => return Err("Expected proxy block name".into())
+    };
+    for canonical in [SETUP_PHASE, RENDER_PHASE].iter() {
+        if phase.eq_ignore_ascii_case(canonical) {
+            return Ok((*canonical).to_owned())
+        }
+    }
+    Ok(phase)
+}
+
+fn treat_proxyblock(pair: pest::iterators::Pair<'_, Rule>, material: &mut MaterialFile, arguments: &OrderedMap<MaterialVariableType>) -> Result<(), MaterialError> {
+    let mut pair = pair.into_inner();
+    let override_parent = treat_proxyoverride(&mut pair);
+    let phase = treat_proxyphase(&mut pair)?;
+    let block = material.phase_mut(&phase);
+    if override_parent {
+        block.override_parent = true;
+    }
+    let proxy_vec = &mut block.proxies;
+    for element in pair {
+        match element.as_rule() {
+            Rule::proxy | Rule::assignment => {
+                match treat_proxy(element, arguments) {
+                    Ok(proxy) => proxy_vec.push(proxy),
+                    Err(e) => return Err(e)
+                }
+            },
+            _ => return Err("Expected proxy".into())
+        }
+    }
+    Ok(())
+}
+
+fn comp_types(type1: &MaterialVariableType, type2: &MaterialVariableType) -> bool {
+    std::mem::discriminant(type1) == std::mem::discriminant(type2)
+}
+
+//this code is a mess!
+fn treat_arraydec(array: pest::iterators::Pairs<'_, Rule>) -> Result<MaterialVariableType, MaterialError> {
+    let mut type_ = MaterialVariableType::NONE;
+    let mut vec = Vec::with_capacity(4);
+    for element in array {
+        match element.as_rule() {
+            Rule::number => {
+                match element.into_inner().next() {
+                    Some(data) => {
+                        match data.as_rule() {
+                            //todo: For now non_int = double, that is not desirable as it could also represent a float
+                            Rule::non_int | Rule::signed_non_int | Rule::nonfinite => {
+                                let string = data.as_str().to_owned();
+                                let n = match string.parse::<f64>() {
+                                    Ok(data) => data,
+                                    Err(_) => return Err("Double parsing error in vector declaration".into())
+                                };
+                                let type2 = MaterialVariableType::DOUBLE(n);
+                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
+                                    return Err("Ambiguous number type in vector declaration".into())
+                                }
+                                type_ = type2;
+                                vec.push(type_.clone());
+                            },
+                            Rule::float => {
+                                let string = data.as_str().to_owned();
+                                let n = f32::from_parsed_number_string(&string)?;
+                                let type2 = MaterialVariableType::FLOAT(n);
+                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
+                                    return Err("Ambiguous number type in vector declaration".into())
+                                }
+                                type_ = type2;
+                                vec.push(type_.clone());
+                            },
+                            Rule::double => {
+                                let string = data.as_str().to_owned();
+                                let n = f64::from_parsed_number_string(&string)?;
+                                let type2 = MaterialVariableType::DOUBLE(n);
+                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
+                                    return Err("Ambiguous number type in vector declaration".into())
+                                }
+                                type_ = type2;
+                                vec.push(type_.clone());
+                            },
+                            Rule::integer | Rule::signed_integer => {
+                                let string = data.as_str().to_owned();
+                                let n = match string.parse::<i32>() {
+                                    Ok(data) => data,
+                                    Err(_) => return Err("Integer parsing error in vector declaration".into())
+                                };
+                                let type2 = MaterialVariableType::INTEGER(n);
+                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
+                                    return Err("Ambiguous number type in vector declaration".into())
+                                }
+                                type_ = type2;
+                                vec.push(type_.clone());
+                            },
+                            _ => return Err("Invalid number".into())
+                        }
+                    },
+                    None => return Err("Empty number".into())
+                }
+            },
+            _ => return Err("Only numbers are allowed in a vector declaration".into())
+        }
+    }
+
+    match vec.len() {
+        2 => {
+            match type_ {
+                MaterialVariableType::INTEGER(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY2(n1, n2))
+                },
+                MaterialVariableType::FLOAT(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY2F(n1, n2))
+                },
+                MaterialVariableType::DOUBLE(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY2D(n1, n2))
+                }
+                _ => Err("????".into())
+            }
+        },
+        3 => {
+            match type_ {
+                MaterialVariableType::INTEGER(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n3 = match vec[2] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY3(n1, n2, n3))
+                },
+                MaterialVariableType::FLOAT(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n3 = match vec[2] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY3F(n1, n2, n3))
+                },
+                MaterialVariableType::DOUBLE(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n3 = match vec[2] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY3D(n1, n2, n3))
+                }
+                _ => Err("????".into())
+            }
+        },
+        4 => {
+            match type_ {
+                MaterialVariableType::INTEGER(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n3 = match vec[2] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n4 = match vec[3] {
+                        MaterialVariableType::INTEGER(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY4(n1, n2, n3, n4))
+                },
+                MaterialVariableType::FLOAT(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n3 = match vec[2] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n4 = match vec[3] {
+                        MaterialVariableType::FLOAT(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY4F(n1, n2, n3, n4))
+                },
+                MaterialVariableType::DOUBLE(_) => {
+                    let n1 = match vec[0] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n2 = match vec[1] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n3 = match vec[2] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    let n4 = match vec[3] {
+                        MaterialVariableType::DOUBLE(data) => data,
+                        _ => return Err("???".into())
+                    };
+                    Ok(MaterialVariableType::ARRAY4D(n1, n2, n3, n4))
+                },
+                _ => Err("????".into())
+            }
+        },
+        _ => Err("Invalid vector size".into()),
+    }
+}
+
+fn escape_error(inner_str: &pest::iterators::Pair<'_, Rule>, start: usize, end: usize, message: String) -> MaterialError {
+    let base = inner_str.as_span().start();
+    match pest::Span::new(inner_str.get_input(), base + start, base + end) {
+        Some(span) => MaterialError::new(message, SourceSpan::from_pest(&span)),
+        None => MaterialError::new(message, SourceSpan::from_pest(&inner_str.as_span()))
+    }
+}
+
+/// Decodes the escape sequences of an 'inner_str'
+/// Supported: \" \\ \' \n \r \t \0 and \u{...} (1 to 6 hex digits)
+fn decode_string(inner_str: &pest::iterators::Pair<'_, Rule>) -> Result<String, MaterialError> {
+    let mut string = String::with_capacity(inner_str.as_str().len());
+    match decode_escapes(inner_str, inner_str.as_str(), 0, &mut string) {
+        Ok(_) => Ok(string),
+        Err(e) => Err(e)
+    }
+}
+
+// 'raw' is a slice of 'inner_str' starting at byte 'offset', used to locate errors
+fn decode_escapes(inner_str: &pest::iterators::Pair<'_, Rule>, raw: &str, offset: usize, string: &mut String) -> Result<(), MaterialError> {
+    let mut chars = raw.char_indices().map(|(i, c)| (i + offset, c));
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            string.push(c);
+            continue;
+        }
+        let (index, escaped) = match chars.next() {
+            Some(data) => data,
+            None => return Err(escape_error(inner_str, start, offset + raw.len(), "Unterminated escape sequence".to_owned()))
+        };
+        match escaped {
+            '"' => string.push('"'),
+            '\\' => string.push('\\'),
+            '\'' => string.push('\''),
+            'n' => string.push('\n'),
+            'r' => string.push('\r'),
+            't' => string.push('\t'),
+            '0' => string.push('\0'),
+            'u' => {
+                match chars.next() {
+                    Some((_, '{')) => {},
+                    _ => return Err(escape_error(inner_str, start, index + 1, "Expected '{' after '\\u'".to_owned()))
+                }
+                let mut digits = String::with_capacity(6);
+                let mut end = None;
+                for (i, d) in chars.by_ref() {
+                    if d == '}' {
+                        end = Some(i + 1);
+                        break;
+                    }
+                    digits.push(d);
+                }
+                let end = match end {
+                    Some(end) => end,
+                    None => return Err(escape_error(inner_str, start, offset + raw.len(), "Unterminated unicode escape".to_owned()))
+                };
+                if digits.is_empty() || digits.len() > 6 {
+                    return Err(escape_error(inner_str, start, end, format!("Invalid unicode escape '\\u{{{}}}': expected 1 to 6 hex digits", digits)))
+                }
+                let code = match u32::from_str_radix(&digits, 16) {
+                    Ok(code) => code,
+                    Err(_) => return Err(escape_error(inner_str, start, end, format!("Invalid unicode escape '\\u{{{}}}': expected 1 to 6 hex digits", digits)))
+                };
+                match std::char::from_u32(code) {
+                    Some(c) => string.push(c),
+                    None => return Err(escape_error(inner_str, start, end, format!("Invalid unicode escape '\\u{{{}}}': not a unicode scalar value", digits)))
+                }
+            },
+            other => return Err(escape_error(inner_str, start, index + other.len_utf8(), format!("Invalid escape sequence '\\{}'", other)))
+        }
+    }
+    Ok(())
+}
+
+fn treat_listdec(list: pest::iterators::Pairs<'_, Rule>) -> Result<MaterialVariableType, MaterialError> {
+    let mut vec = Vec::new();
+    for element in list {
+        match element.as_rule() {
+            Rule::value => {
+                let val = match element.into_inner().next() {
+                    Some(data) => data,
+                    None => return Err("Empty value in list declaration".into())
+                };
+                match treat_value(val, true) {
+                    Ok(data) => vec.push(data),
+                    Err(e) => return Err(e)
+                }
+            },
+            _ => return Err("Expected value in list declaration".into())
+        }
+    }
+    Ok(MaterialVariableType::LIST(vec))
+}
+
+fn treat_blockdec(block: pest::iterators::Pairs<'_, Rule>) -> Result<MaterialVariableType, MaterialError> {
+    let mut entries: Vec<(String, MaterialVariableType)> = Vec::new();
+    for entry in block {
+        let span = SourceSpan::from_pest(&entry.as_span());
+        let mut entry = entry.into_inner();
+        let key = match entry.next() {
+            Some(data) => data.as_str().to_owned(),
+            None => return Err("Expected key in block entry".into())
+        };
+        if entries.iter().any(|e| e.0 == key) {
+            return Err(MaterialError::new(format!("Duplicate key '{}' in block", key), span))
+        }
+        let value = match entry.next() {
+            Some(data) => {
+                match data.as_rule() {
+                    Rule::ident => MaterialVariableType::STRING(data.as_str().to_owned()),
+                    Rule::value => {
+                        let val = match data.into_inner().next() {
+                            Some(data) => data,
+                            None => return Err("Empty value in block entry".into())
+                        };
+                        treat_value(val, true)?
+                    },
+                    _ => return Err("Invalid value in block entry".into())
+                }
+            },
+            None => return Err(MaterialError::new(format!("Expected value for key '{}'", key), span))
+        };
+        entries.push((key, value));
+    }
+    Ok(MaterialVariableType::MAP(entries))
+}
+
+fn leading_whitespace(line: &str) -> usize {
+    line.len() - line.trim_start_matches([' ', '\t']).len()
+}
+
+/// Decodes a 'multiline_inner'
+/// The line following the opening quotes and the line holding the closing quotes are dropped when blank,
+/// then the indentation shared by every line (closing quotes included) is stripped
+fn decode_multiline_string(inner: &pest::iterators::Pair<'_, Rule>) -> Result<String, MaterialError> {
+    let raw = inner.as_str();
+    let mut lines = Vec::new(); // (offset, line)
+    let mut offset = 0;
+    for line in raw.split('\n') {
+        lines.push((offset, line.strip_suffix('\r').unwrap_or(line)));
+        offset += line.len() + 1;
+    }
+
+    let mut indent = usize::MAX;
+    if lines.len() > 1 {
+        if lines[0].1.trim().is_empty() {
+            lines.remove(0);
+        }
+        let last = lines[lines.len() - 1].1;
+        if last.trim().is_empty() {
+            indent = leading_whitespace(last);
+            lines.pop();
+        }
+    }
+    for (_, line) in &lines {
+        if !line.trim().is_empty() {
+            indent = indent.min(leading_whitespace(line));
+        }
+    }
+
+    let mut string = String::with_capacity(raw.len());
+    for (i, (offset, line)) in lines.iter().enumerate() {
+        if i > 0 {
+            string.push('\n');
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        match decode_escapes(inner, &line[indent..], offset + indent, &mut string) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(string)
+}
+
+fn treat_value(val: pest::iterators::Pair<'_, Rule>, support_rvalues: bool) -> Result<MaterialVariableType, MaterialError> {
+    let type_: MaterialVariableType;
+    match val.as_rule() {
+        Rule::string => {
+            let string = match val.into_inner().next() {
+                Some(data) => decode_string(&data)?,
+                None => return Err("Invalid string".into())
+            };
+            type_ = MaterialVariableType::STRING(string);
+        },
+        Rule::raw_string => {
+            let string = match val.into_inner().next() {
+                Some(data) => data.as_str().to_owned(),
+                None => return Err("Invalid raw string".into())
+            };
+            type_ = MaterialVariableType::STRING(string);
+        },
+        Rule::multiline_string => {
+            let string = match val.into_inner().next() {
+                Some(data) => decode_multiline_string(&data)?,
+                None => return Err("Invalid multi-line string".into())
+            };
+            type_ = MaterialVariableType::STRING(string);
+        },
+        Rule::number => {
+            let number = match val.into_inner().next() {
+                Some(data) => data,
+                None => return Err("Invalid number".into())
+            };
+            match number.as_rule() {
+                Rule::float => {
+                    let string = number.as_str().to_owned();
+                    let number = f32::from_parsed_number_string(&string)?;
+                    type_ = MaterialVariableType::FLOAT(number);
+                },
+                Rule::double => {
+                    let string = number.as_str().to_owned();
+                    let number = f64::from_parsed_number_string(&string)?;
+                    type_ = MaterialVariableType::DOUBLE(number);
+                },
+                Rule::non_int | Rule::signed_non_int | Rule::nonfinite => {
+                    let string = number.as_str().to_owned();
+                    let number = match string.parse::<f64>() {
+                        Ok(n) => n,
+                        Err(_) => return Err("non_int considered as a double but resulted in parsing error".into())
+                    };
+                    type_ = MaterialVariableType::DOUBLE(number);
+                },
+                Rule::integer | Rule::signed_integer => {
+                    let string = number.as_str().to_owned();
+                    let number = match string.parse::<i32>() {
+                        Ok(n) => n,
+                        Err(_) => return Err("Invalid integer".into())
+                    };
+                    type_ = MaterialVariableType::INTEGER(number);
+                },
+                _ => return Err("Invalid number".into())
+            }
+        },
+        Rule::none => {
+            type_ = MaterialVariableType::NONE;
+        },
+        Rule::array => {
+            if support_rvalues {
+                type_ = treat_arraydec(val.into_inner())?
+            } else {
+                return Err("Cannot use vector declaration as proxy parameter".into())
+            }
+        },
+        Rule::list => {
+            if support_rvalues {
+                type_ = treat_listdec(val.into_inner())?
+            } else {
+                return Err("Cannot use list declaration as proxy parameter".into())
+            }
+        },
+        Rule::block => {
+            if support_rvalues {
+                type_ = treat_blockdec(val.into_inner())?
+            } else {
+                return Err("Cannot use block declaration as proxy parameter".into())
+            }
+        }
+        _ => return Err("Invalid value type in value".into())
+    }
+    Ok(type_)
+}
+
+fn coerce_declared(varname: &str, value: MaterialVariableType, declared_type: Option<MaterialVariableKind>, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+    match declared_type {
+        Some(kind) => {
+            match value.coerce(kind) {
+                Some(data) => Ok(data),
+                None => Err(MaterialError::new(format!("Type mismatch: '${}' is declared as '{}' but its value is a '{}'", varname, kind.name(), value.kind().name()), span))
+            }
+        },
+        None => Ok(value)
+    }
+}
+
+// Returns the name, the expression and the declared type of a 'vardec'
+fn treat_vardec_expression(pair: &mut pest::iterators::Pairs<'_, Rule>) -> Result<(String, Expression, Option<MaterialVariableKind>), MaterialError> {
+    let varname = match pair.next() {
+        Some(variable) => {
+            match variable.into_inner().next() {
+                Some(ident) => {
+                    ident.as_str().to_owned()
+                },
+                None => return Err("Invalid identifier in vardec".into())
+            }
+        },
+        None => return Err("Expected 2 elements in vardec".into())
+    };
+
+    let mut declared_type = None;
+    let mut next = pair.next();
+    if let Some(annotation) = &next {
+        if annotation.as_rule() == Rule::typeannotation {
+            let type_name = match annotation.clone().into_inner().next() {
+                Some(data) => data,
+                None => return Err("Empty type annotation".into())
+            };
+            declared_type = match MaterialVariableKind::from_name(type_name.as_str()) {
+                Some(kind) => Some(kind),
+                None => return Err(MaterialError::new(format!("Unknown type '{}'", type_name.as_str()), SourceSpan::from_pest(&type_name.as_span())))
+            };
+            next = pair.next();
+        }
+    }
+
+    let expression = match next {
+        Some(expression) => {
+            treat_expression(expression)?
+        },
+        None => return Err("Expected 2 elements in vardec".into())
+    };
+    Ok((varname, expression, declared_type))
+}
+
+// Returns the name, the value and the declared type of a 'vardec' that does not reference other variables
+pub(crate) fn treat_vardec_entry(pair: &mut pest::iterators::Pairs<'_, Rule>) -> Result<(String, MaterialVariableType, Option<MaterialVariableKind>), MaterialError> {
+    let (varname, expression, declared_type) = treat_vardec_expression(pair)?;
+    let value = expression.evaluate(&mut |name: &str, span: SourceSpan| Err(MaterialError::new(format!("Variables cannot be referenced here: '${}'", name), span)))?;
+    match coerce_declared(&varname, value, declared_type, expression.span) {
+        Ok(value) => Ok((varname, value, declared_type)),
+        Err(e) => Err(e)
+    }
+}
+
+// Declarations of a material or of a conditional block. Expressions referencing variables are folded,
+// and conditionals treated, once every variable of the block is known
+struct Scope<'i> {
+    arguments: &'i OrderedMap<MaterialVariableType>, // of the template being instantiated
+    material: MaterialFile,
+    spans: HashMap<String, SourceSpan>,
+    expressions: Vec<(String, Expression)>,
+    conditionals: Vec<pest::iterators::Pair<'i, Rule>>,
+}
+
+impl<'i> Scope<'i> {
+    fn new(arguments: &'i OrderedMap<MaterialVariableType>) -> Scope<'i> {
+        Scope {
+            arguments,
+            material: MaterialFile::new(String::new()),
+            spans: HashMap::new(),
+            expressions: Vec::new(),
+            conditionals: Vec::new(),
+        }
+    }
+
+    // 'outer' holds the variables of the enclosing blocks, outermost first
+    fn finish(self, outer: &[VariableScope<'_>]) -> Result<MaterialFile, MaterialError> {
+        let mut material = self.material;
+        match fold_variables(&mut material, &self.spans, self.expressions, outer) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+        let mut scopes = outer.to_vec();
+        scopes.push(VariableScope { variables: &material.variables, spans: &self.spans });
+        let mut conditionals = Vec::with_capacity(self.conditionals.len());
+        for pair in self.conditionals {
+            match treat_conditional(pair, &scopes, self.arguments) {
+                Ok(conditional) => conditionals.push(conditional),
+                Err(e) => return Err(e)
+            }
+        }
+        material.conditionals = conditionals;
+        Ok(material)
+    }
+}
+
+fn treat_vardec(pair: pest::iterators::Pair<'_, Rule>, scope: &mut Scope<'_>) -> Result<(), MaterialError> {
+    let span = SourceSpan::from_pest(&pair.as_span());
+    let (varname, mut expression, declared_type) = treat_vardec_expression(&mut pair.into_inner())?;
+    match expression.replace_parameters(scope.arguments) {
+        Ok(_) => {},
+        Err(e) => return Err(e)
+    }
+    if let Some(first) = scope.spans.get(&varname) {
+        return Err(MaterialError::with_related(format!("Duplicate variable '${}'", varname), span, *first))
+    }
+    scope.spans.insert(varname.clone(), span);
+    if let Some(kind) = declared_type {
+        scope.material.declared_types.insert(varname.clone(), kind);
+    }
+    if expression.is_constant() {
+        let value = expression.evaluate(&mut |_: &str, _: SourceSpan| Err("Unexpected variable in constant expression".into()))?;
+        let value = coerce_declared(&varname, value, declared_type, expression.span)?;
+        scope.material.variables.insert(varname, value);
+    } else {
+        // keeps the declaration order, the value is set by Scope::finish
+        scope.material.variables.insert(varname.clone(), MaterialVariableType::NONE);
+        scope.expressions.push((varname, expression));
+    }
+    Ok(())
+}
+
+pub(crate) fn pest_error(error: pest::error::Error<Rule>) -> MaterialError {
+    let (line, column) = match error.line_col {
+        pest::error::LineColLocation::Pos(pos) => pos,
+        pest::error::LineColLocation::Span(start, _) => start
+    };
+    let (start, end) = match error.location {
+        pest::error::InputLocation::Pos(pos) => (pos, pos),
+        pest::error::InputLocation::Span(span) => span
+    };
+    MaterialError::new("Invalid Material File".to_owned(), SourceSpan { start, end, line, column })
+}
+
+trait FromParsedNumberString {
+    fn from_parsed_number_string(string: &str) -> Result<Self, &'static str> where Self: Sized;
+}
+
+impl FromParsedNumberString for f32 {
+    //todo: use string.strip_suffix("f") when it will be stabilized
+    fn from_parsed_number_string(string: &str) -> Result<Self, &'static str> {
+        let size = string.len();
+        let string = string[..size - 1].to_owned();
+        match string.parse::<f32>() {
+            Ok(res) => Ok(res),
+            Err(_) => Err("Error while trying to parse a float string")
+        }
+    }
+}
+
+impl FromParsedNumberString for f64 {
+    //todo: use string.strip_suffix("d") when it will be stabilized
+    fn from_parsed_number_string(string: &str) -> Result<Self, &'static str> {
+        let size = string.len();
+        let string = string[..size - 1].to_owned();
+        match string.parse::<f64>() {
+            Ok(res) => Ok(res),
+            Err(_) => Err("Error while trying to parse a double string")
+        }
+    }
+}
+
+pub fn parse_material_file(data: &str) -> Result<MaterialFile, MaterialError> {
+    let pairs = match SMFParser::parse(Rule::materialfile, data) {
+        Ok(mut p) => {
+            match p.next() {
+                Some(item) => item,
+                None => return Err("Invalid Material File".into())
+            }
+        }
+        Err(e) => return Err(pest_error(e))
+    };
+    let mut declarations = Declarations::new();
+    for pair in pairs.into_inner() {
+        match pair.as_rule() {
+            Rule::definition | Rule::template => {
+                match declarations.treat(pair) {
+                    Ok(_) => {},
+                    Err(e) => return Err(e)
+                }
+            },
+            Rule::material | Rule::instantiation => return declarations.material(pair),
+            _ => return Err("Unsupported rule in material file".into())
+        }
+    }
+    Err("Invalid Material File".into())
+}
+
+fn treat_material_item<'i>(pair: pest::iterators::Pair<'i, Rule>, scope: &mut Scope<'i>) -> Result<(), MaterialError> {
+    let arguments = scope.arguments;
+    let material = &mut scope.material;
+    match pair.as_rule() {
+        Rule::identblockstart | Rule::inheritblockstart => {
+            treat_identblockstart(&mut pair.into_inner(), material)?
+        },
+        Rule::vardec => {
+            treat_vardec(pair, scope)?
+        },
+        Rule::proxyblock => {
+            match treat_proxyblock(pair, material, arguments) {
+                Ok(_) => {},
+                Err(e) => return Err(e)
+            }
+        },
+        Rule::conditional => scope.conditionals.push(pair),
+        rule => return Err(MaterialError::new(format!("Unsupported rule '{:?}' in material", rule), SourceSpan::from_pest(&pair.as_span()))),
+    }
+    Ok(())
+}
+
+fn treat_conditionalbody(pair: pest::iterators::Pair<'_, Rule>, outer: &[VariableScope<'_>], arguments: &OrderedMap<MaterialVariableType>) -> Result<MaterialFile, MaterialError> {
+    let span = SourceSpan::from_pest(&pair.as_span());
+    let mut scope = Scope::new(arguments);
+    for pair in pair.into_inner() {
+        match treat_material_item(pair, &mut scope) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    if scope.material.phases.values().any(|block| block.override_parent) {
+        return Err(MaterialError::new("'override' cannot be used in a conditional block".to_owned(), span))
+    }
+    scope.finish(outer)
+}
+
+fn treat_conditionoperand(pair: pest::iterators::Pair<'_, Rule>) -> Result<ConditionOperand, MaterialError> {
+    let operand = match pair.into_inner().next() {
+        Some(data) => data,
+        None => return Err("Empty operand in condition".into())
+    };
+    match operand.as_rule() {
+        Rule::ident => Ok(ConditionOperand::DEFINE(operand.as_str().to_owned())),
+        _ => {
+            match treat_value(operand, false) {
+                Ok(value) => Ok(ConditionOperand::VALUE(value)),
+                Err(e) => Err(e)
+            }
+        }
+    }
+}
+
+fn treat_condition(pair: pest::iterators::Pair<'_, Rule>) -> Result<MaterialCondition, MaterialError> {
+    match pair.as_rule() {
+        Rule::condor | Rule::condand => {
+            let rule = pair.as_rule();
+            let mut condition = None;
+            for element in pair.into_inner() {
+                let operand = treat_condition(element)?;
+                condition = match condition {
+                    None => Some(operand),
+                    Some(left) if rule == Rule::condor => Some(MaterialCondition::OR(Box::new(left), Box::new(operand))),
+                    Some(left) => Some(MaterialCondition::AND(Box::new(left), Box::new(operand))),
+                };
+            }
+            match condition {
+                Some(data) => Ok(data),
+                None => Err("Empty condition".into())
+            }
+        },
+        Rule::notcond => {
+            match pair.into_inner().next() {
+                Some(data) => {
+                    match treat_condition(data) {
+                        Ok(condition) => Ok(MaterialCondition::NOT(Box::new(condition))),
+                        Err(e) => Err(e)
+                    }
+                },
+                None => Err("Empty condition after '!'".into())
+            }
+        },
+        Rule::defined => Ok(MaterialCondition::DEFINED(pair.as_str().to_owned())),
+        Rule::comparison => {
+            let mut inner = pair.into_inner();
+            let (left, operator, right) = match (inner.next(), inner.next(), inner.next()) {
+                (Some(left), Some(operator), Some(right)) => (left, operator, right),
+                _ => return Err("Expected 3 elements in comparison".into())
+            };
+            let operator = match operator.as_str() {
+                "==" => CompareOperator::EQ,
+                "!=" => CompareOperator::NE,
+                "<" => CompareOperator::LT,
+                "<=" => CompareOperator::LE,
+                ">" => CompareOperator::GT,
+                ">=" => CompareOperator::GE,
+                _ => return Err("Invalid comparison operator".into())
+            };
+            let left = treat_conditionoperand(left)?;
+            let right = treat_conditionoperand(right)?;
+            Ok(MaterialCondition::COMPARE(left, operator, right))
+        },
+        _ => Err("Invalid condition".into())
+    }
+}
+
+fn treat_conditional(pair: pest::iterators::Pair<'_, Rule>, outer: &[VariableScope<'_>], arguments: &OrderedMap<MaterialVariableType>) -> Result<MaterialConditional, MaterialError> {
+    let mut inner = pair.into_inner();
+    let condition = match inner.next() {
+        Some(data) => {
+            treat_condition(data)?
+        },
+        None => return Err("Expected condition after '#if'".into())
+    };
+    let then = match inner.next() {
+        Some(data) => {
+            treat_conditionalbody(data, outer, arguments)?
+        },
+        None => return Err("Expected block after '#if'".into())
+    };
+    // '#else #if' is an '#else' block holding a single conditional
+    let otherwise = match inner.next() {
+        Some(data) => {
+            let result = match data.as_rule() {
+                Rule::conditional => {
+                    match treat_conditional(data, outer, arguments) {
+                        Ok(conditional) => {
+                            let mut body = MaterialFile::new(String::new());
+                            body.conditionals.push(conditional);
+                            Ok(body)
+                        },
+                        Err(e) => Err(e)
+                    }
+                },
+                _ => treat_conditionalbody(data, outer, arguments)
+            };
+            match result {
+                Ok(body) => Some(body),
+                Err(e) => return Err(e)
+            }
+        },
+        None => None
+    };
+    Ok(MaterialConditional {
+        condition,
+        then,
+        otherwise,
+    })
+}
+
+// 'arguments' are the values of the parameters of the template being instantiated, empty for other materials
+pub(crate) fn treat_material(pair: pest::iterators::Pair<'_, Rule>, arguments: &OrderedMap<MaterialVariableType>) -> Result<MaterialFile, MaterialError> {
+    let mut scope = Scope::new(arguments);
+
+    for pair in pair.into_inner() {
+        match treat_material_item(pair, &mut scope) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    let material = scope.finish(&[])?;
+    // Sanity check
+    if material.shader.is_empty() {
+        return Err("No shader specified".into())
+    }
+    Ok(material)
+}
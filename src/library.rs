@@ -0,0 +1,119 @@
+// Libraries hold many named materials in a single file:
+// material "walls/brick" UnlitGeneric { ... }
+// material "walls/brick_red" UnlitGeneric : "walls/brick" { ... }
+use crate::inheritance::MaterialLoader;
+use crate::template::Declarations;
+use crate::{pest_error, treat_value};
+use crate::ordered_map::OrderedMap;
+use crate::{MaterialError, MaterialFile, MaterialVariableType, Rule, SMFParser, SourceSpan};
+use pest::Parser;
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MaterialLibrary {
+    pub materials: OrderedMap<MaterialFile>, // in declaration order
+}
+
+impl MaterialLibrary {
+    pub fn get(&self, name: &str) -> Option<&MaterialFile> {
+        self.materials.get(name)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.materials.contains_key(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.materials.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.materials.keys()
+    }
+
+    /// Materials in declaration order
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &MaterialFile)> {
+        self.materials.iter()
+    }
+}
+
+/// Lets the materials of a library inherit from each other, parents are taken from the library as parsed
+impl MaterialLoader for MaterialLibrary {
+    fn load(&mut self, name: &str) -> Result<String, MaterialError> {
+        Err(format!("The materials of a library have no source, '{}' can only be loaded parsed", name).into())
+    }
+
+    fn load_parsed(&mut self, name: &str) -> Result<MaterialFile, MaterialError> {
+        match self.get(name) {
+            Some(material) => Ok(material.clone()),
+            None => Err(format!("Unknown material '{}' in library", name).into())
+        }
+    }
+}
+
+pub fn parse_material_library(data: &str) -> Result<MaterialLibrary, MaterialError> {
+    let pairs = match SMFParser::parse(Rule::library, data) {
+        Ok(mut p) => {
+            match p.next() {
+                Some(item) => item,
+                None => return Err("Invalid Material Library".into())
+            }
+        }
+        Err(e) => return Err(pest_error(e))
+    };
+
+    let mut library = MaterialLibrary {
+        materials: OrderedMap::new(),
+    };
+    let mut spans: HashMap<String, SourceSpan> = HashMap::new();
+    let mut declarations = Declarations::new(); // shared by all the materials of the library
+
+    for pair in pairs.into_inner() {
+        match pair.as_rule() {
+            Rule::namedmaterial => {
+                let mut inner = pair.into_inner();
+                let name_pair = match inner.next() {
+                    Some(data) => data,
+                    None => return Err("Expected name in 'namedmaterial'".into())
+                };
+                let span = SourceSpan::from_pest(&name_pair.as_span());
+                let name = match treat_value(name_pair, false) {
+                    Ok(MaterialVariableType::STRING(name)) => name,
+                    Ok(_) => return Err("Expected string as material name".into()),
+                    Err(e) => return Err(e)
+                };
+                if let Some(first) = spans.get(&name) {
+                    return Err(MaterialError::with_related(format!("Duplicate material '{}'", name), span, *first))
+                }
+                let material = match inner.next() {
+                    Some(data) => {
+                        match declarations.material(data) {
+                            Ok(material) => material,
+                            Err(e) => return Err(MaterialError {
+                                message: format!("In '{}': {}", name, e.message),
+                                span: e.span.or(Some(span)),
+                                related: e.related,
+                            })
+                        }
+                    },
+                    None => return Err("Expected material in 'namedmaterial'".into())
+                };
+                spans.insert(name.clone(), span);
+                library.materials.insert(name, material);
+            },
+            Rule::definition | Rule::template => {
+                match declarations.treat(pair) {
+                    Ok(_) => {},
+                    Err(e) => return Err(e)
+                }
+            },
+            Rule::EOI => {},
+            _ => return Err("Unsupported rule in library".into())
+        }
+    }
+    Ok(library)
+}
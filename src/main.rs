@@ -1,663 +1,238 @@
-extern crate pest;
-#[macro_use]
-extern crate pest_derive;
-
-use pest::Parser;
-use std::collections::HashMap;
 use ansi_term::Style;
-
-#[derive(Parser)]
-#[grammar = "grammar.pest"]
-pub struct SMFParser;
-
-#[derive(Debug, PartialEq, Clone)]
-pub enum MaterialVariableType {
-    NONE,
-    FLOAT (f32),
-    DOUBLE (f64),
-    INTEGER (i32),
-    STRING (String),
-
-    ARRAY2 (i32, i32),
-    ARRAY3 (i32, i32, i32),
-    ARRAY4 (i32, i32, i32, i32),
-
-    ARRAY2F (f32, f32),
-    ARRAY3F (f32, f32, f32),
-    ARRAY4F (f32, f32, f32, f32),
-
-    ARRAY2D (f64, f64),
-    ARRAY3D (f64, f64, f64),
-    ARRAY4D (f64, f64, f64, f64),
-}
-#[derive(Debug)]pub enum MaterialVariableReference { // Can be a value or reference a variable
-    TYPE (MaterialVariableType),
-    VARIABLE(String),
-    ARRAYREF (String, u32),
-}
-#[derive(Debug)]
-pub struct MaterialProxy {
-    pub name: String,
-    pub parameters: HashMap<String, MaterialVariableReference>,
-}
-#[derive(Debug)]
-pub struct MaterialFile {
-    pub shader: String,
-    pub variables: HashMap<String, MaterialVariableType>,
-    pub setup_proxies: Vec<MaterialProxy>,
-    pub render_proxies: Vec<MaterialProxy>,
+use materialparser::bytecode::compile_material;
+use materialparser::conditions::Defines;
+use materialparser::disassembler::disassemble;
+use materialparser::dot::write_dot;
+use materialparser::expression::parse_expression;
+use materialparser::inheritance::{resolve_material_with_defines, FileSystemLoader};
+use materialparser::runtime::ProxyRegistry;
+use materialparser::simulation::{simulate, SimulationOptions};
+use materialparser::*;
+use std::path::Path;
+use std::process::exit;
+
+const USAGE: &str = "usage: smf [command]
+    dot <file> [--define <name>=<value>]... [--output <path>]
+    disasm <file> [--define <name>=<value>]...
+    simulate <file> [--define <name>=<value>]... [--frames <n>] [--dt <seconds>] [--set <field>=<expression>]... [--seed <n>] [--format csv|json] [--output <path>] [--trace]
+the parents of the material are loaded from the directory of the file, #if blocks are evaluated with the defines
+without a command, prints the sample material";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    exit(2)
 }
 
-fn var_to_string(pair: &mut pest::iterators::Pairs<'_, Rule>) -> Result<String, &'static str> {
-    let p = pair.nth(0);
-    match p {
-        Some(data) => {
-            match data.as_rule() {
-                Rule::ident => {
-                    return Ok(data.as_str().to_owned())
-                },
-                _ => return Err("Expected 'ident' in 'variable'")
+fn write_output(output: Option<&String>, data: String) {
+    match output {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, data) {
+                eprintln!("ERROR: cannot write '{}': {}", path, e);
+                exit(1)
             }
         },
-        None => return Err("Empty variable")
+        None => print!("{}", data)
     }
 }
 
-fn treat_identblockstart(pair: &mut pest::iterators::Pairs<'_, Rule>, material: &mut MaterialFile) -> Result<(), &'static str> {
-    match pair.nth(0) {
-        Some(ident_pair) => {
-            match ident_pair.as_rule() {
-                Rule::ident => {
-                    material.shader = ident_pair.as_str().to_owned();
-                },
-                _ => return Err("Expected identifier inside of 'identblockstart'")
-            }
-        },
-        None => return Err("Empty identblockstart")
-    };
-    Ok(())
-}
-
-fn treat_arrayref(arrayref: &mut pest::iterators::Pairs<'_, Rule>) -> Result<MaterialVariableReference, &'static str> {
+fn print_material_information(material: &MaterialFile) {
+    println!("{}", Style::new().bold().paint("===============================\nINFORMATION ABOUT THE MATERIAL\n==============================="));
+    println!("{} {}", Style::new().bold().paint("SHADER:"), material.shader);
 
-    let name;
-    let index;
-    match arrayref.nth(0) {
-        Some(data) => {
-            match data.as_rule() {
-                Rule::ident => {
-                    name = data.as_str().to_owned();
-                }
-                _ => return Err("Expected 'ident' in 'arrayref'")
-            }
-        },
-        None => return Err("Empty arrayref")
+    println!("{}", Style::new().bold().paint("VARIABLES:"));
+    for value in &material.variables {
+        println!("\t{}: {:?}", Style::new().italic().paint(value.0), value.1);
     }
 
-    match arrayref.nth(0) {
-        Some(data) => {
-            match data.as_rule() {
-                Rule::integer => {
-                    let string = data.as_str().to_owned();
-                    index = match string.parse::<u32>() {
-                        Ok(data) => data,
-                        Err(_) => return Err("Integer parsing error")
-                    };
-                }
-                _ => return Err("Expected 'ident' in 'arrayref'")
+    println!("{}", Style::new().bold().paint("PROXIES:"));
+    for (phase, block) in &material.phases {
+        println!("{}", Style::new().bold().paint(format!("\t{}", phase.to_uppercase())));
+        for value in &block.proxies {
+            println!("\t  {}:", Style::new().italic().paint(&value.name));
+            for param in &value.parameters {
+                println!("\t    {}: {:?}", Style::new().italic().paint(param.0), param.1);
             }
-        },
-        None => return Err("Empty arrayref")
-    }
-
-    return Ok(MaterialVariableReference::ARRAYREF(name, index))
-}
-
-fn treat_proxy(proxy: pest::iterators::Pair<'_, Rule>) -> Result<MaterialProxy, &'static str> {
-    let mut name = String::new();
-    let mut parameters = HashMap::new();
-
-    let proxy = proxy.into_inner();
-
-    for element in proxy {
-        match element.as_rule() {
-            Rule::identblockstart => {
-                match element.into_inner().nth(0) {
-                    Some(data) => {
-                        match data.as_rule() {
-                            Rule::ident => {
-                                name = data.as_str().to_owned();
-                            },
-                            _ => return Err("Expected 'ident' in 'identblockstart'")
-                        }
-                    },
-                    None => return Err("Invalid identblockstart")
-                }
-                
-            },
-            Rule::proxyparam => {
-                let param_name: String;
-                //todo: remove ugly .clone()
-                match element.clone().into_inner().nth(0) {
-                    Some(data) => {
-                        match data.as_rule() {
-                            Rule::ident => {
-                                param_name = data.as_str().to_owned();
-                            },
-                            _ => return Err("Expected ident in 'proxyparam'")
-                        }
-                    },
-                    None => return Err("Expected 2 elements in 'proxyparam'")
-                };
-            
-                match element.into_inner().nth(1) {
-                    Some(data) => {
-                        match data.as_rule() {
-                            Rule::srcdest => {
-                                match data.into_inner().nth(0) {
-                                    Some(srcdst) => {
-                                        match srcdst.as_rule() {
-                                            Rule::variable => {
-                                                let vts = var_to_string(&mut srcdst.into_inner());
-                                                match vts {
-                                                    Ok(varname) => {
-                                                        parameters.insert(param_name, MaterialVariableReference::VARIABLE(varname));
-                                                    },
-                                                    Err(e) => return Err(e)
-                                                }
-                                                
-                                            },
-                                            Rule::arrayref => {
-                                                let matvarref = match treat_arrayref(&mut srcdst.into_inner()) {
-                                                    Ok(data) => data,
-                                                    Err(e) => return Err(e)
-                                                };
-
-                                                parameters.insert(param_name, matvarref);
-                                            },
-                                            Rule::value => {
-                                                let srcdst = match srcdst.into_inner().nth(0) {
-                                                    Some(data) => data,
-                                                    None => return Err("Empty value")
-                                                };
-                                                let type_ = match treat_value(srcdst, false) {
-                                                    Ok(data) => data,
-                                                    Err(e) => return Err(e)
-                                                };
-                                                parameters.insert(param_name, MaterialVariableReference::TYPE(type_));
-                                            },
-                                            _ => return Err("Invalid srcdest")
-                                        }
-                                    },
-                                    None => return Err("Empty srcdest")
-                                }
-                            },
-                            _ => return Err("Expected srcdest in 'proxyparam'")
-                        }
-                    },
-                    None => return Err("Expected 2 elements in 'proxyparam'")
-                }
-                
-            },
-            _ => return Err("Invalid proxy")
-        }
-    }
-    
-    Ok(MaterialProxy {
-        name,
-        parameters
-    })
-}
-
-fn treat_proxyblock(pair: &mut pest::iterators::Pairs<'_, Rule>, proxy_vec: &mut Vec<MaterialProxy>) -> Result<(), &'static str> {
-    for element in pair {
-        match element.as_rule() {
-            Rule::proxy => {
-                match treat_proxy(element) {
-                    Ok(proxy) => proxy_vec.push(proxy),
-                    Err(e) => return Err(e)
-                }
-            },
-            _ => return Err("Expected proxy")
         }
     }
-    Ok(())
 }
 
-fn comp_types(type1: &MaterialVariableType, type2: &MaterialVariableType) -> bool {
-    std::mem::discriminant(type1) == std::mem::discriminant(type2)
+// --define quality=high, numbers are numbers and any other value a string
+fn add_define(arg: Option<&String>, defines: &mut Defines) {
+    let (name, value) = match arg.and_then(|a| a.split_once('=')) {
+        Some(data) => data,
+        None => usage()
+    };
+    let value = match (value.parse::<i32>(), value.parse::<f64>()) {
+        (Ok(n), _) => MaterialVariableType::INTEGER(n),
+        (_, Ok(n)) => MaterialVariableType::DOUBLE(n),
+        _ => MaterialVariableType::STRING(value.to_owned())
+    };
+    defines.insert(name.trim().to_owned(), value);
 }
 
-//this code is a mess!
-fn treat_arraydec(array: pest::iterators::Pairs<'_, Rule>) -> Result<MaterialVariableType, &'static str> {
-    let mut type_ = MaterialVariableType::NONE;
-    let mut vec = Vec::with_capacity(4);
-    for element in array {
-        match element.as_rule() {
-            Rule::number => {
-                match element.into_inner().nth(0) {
-                    Some(data) => {
-                        match data.as_rule() {
-                            //todo: For now non_int = double, that is not desirable as it could also represent a float
-                            Rule::non_int | Rule::signed_non_int => {
-                                let string = data.as_str().to_owned();
-                                let n = match string.parse::<f64>() {
-                                    Ok(data) => data,
-                                    Err(_) => return Err("Double parsing error in vector declaration")
-                                };
-                                let type2 = MaterialVariableType::DOUBLE(n);
-                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
-                                    return Err("Ambiguous number type in vector declaration")
-                                }
-                                type_ = type2;
-                                vec.push(type_.clone());
-                            },
-                            Rule::float => {
-                                let string = data.as_str().to_owned();
-                                let n = match f32::from_parsed_number_string(&string) {
-                                    Ok(data) => data,
-                                    Err(e) => return Err(e)
-                                };
-                                let type2 = MaterialVariableType::FLOAT(n);
-                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
-                                    return Err("Ambiguous number type in vector declaration")
-                                }
-                                type_ = type2;
-                                vec.push(type_.clone());
-                            },
-                            Rule::double => {
-                                let string = data.as_str().to_owned();
-                                let n = match f64::from_parsed_number_string(&string) {
-                                    Ok(data) => data,
-                                    Err(e) => return Err(e)
-                                };
-                                let type2 = MaterialVariableType::DOUBLE(n);
-                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
-                                    return Err("Ambiguous number type in vector declaration")
-                                }
-                                type_ = type2;
-                                vec.push(type_.clone());
-                            },
-                            Rule::integer | Rule::signed_integer => {
-                                let string = data.as_str().to_owned();
-                                let n = match string.parse::<i32>() {
-                                    Ok(data) => data,
-                                    Err(_) => return Err("Integer parsing error in vector declaration")
-                                };
-                                let type2 = MaterialVariableType::INTEGER(n);
-                                if type_ != MaterialVariableType::NONE && !comp_types(&type_, &type2) {
-                                    return Err("Ambiguous number type in vector declaration")
-                                }
-                                type_ = type2;
-                                vec.push(type_.clone());
-                            },
-                            _ => return Err("Invalid number")
-                        }
-                    },
-                    None => return Err("Empty number")
-                }
-            },
-            _ => return Err("Only numbers are allowed in a vector declaration")
+// The effective material: its parents are loaded from the directory of the file and the #if blocks of
+// the chain are evaluated with the defines
+fn read_material(path: &str, defines: &Defines) -> MaterialFile {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("ERROR: cannot read '{}': {}", path, e);
+            exit(1)
+        }
+    };
+    let material = match parse_material_file(&data) {
+        Ok(material) => material,
+        Err(e) => {
+            eprintln!("ERROR: {}: {}", path, e);
+            exit(1)
+        }
+    };
+    let file = Path::new(path);
+    let name = file.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut loader = FileSystemLoader::new(file.parent().unwrap_or_else(|| Path::new("")));
+    match resolve_material_with_defines(material, &name, &mut loader, defines) {
+        Ok(resolved) => resolved.material,
+        Err(e) => {
+            eprintln!("ERROR: {}: {}", path, e);
+            exit(1)
         }
-    }
-
-    match vec.len() {
-        2 => {
-            match type_ {
-                MaterialVariableType::INTEGER(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY2(n1, n2))
-                },
-                MaterialVariableType::FLOAT(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY2F(n1, n2))
-                },
-                MaterialVariableType::DOUBLE(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY2D(n1, n2))
-                }
-                _ => return Err("????")
-            }
-        },
-        3 => {
-            match type_ {
-                MaterialVariableType::INTEGER(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n3 = match vec[2] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY3(n1, n2, n3))
-                },
-                MaterialVariableType::FLOAT(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n3 = match vec[2] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY3F(n1, n2, n3))
-                },
-                MaterialVariableType::DOUBLE(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n3 = match vec[2] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY3D(n1, n2, n3))
-                }
-                _ => return Err("????")
-            }
-        },
-        4 => {
-            match type_ {
-                MaterialVariableType::INTEGER(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n3 = match vec[2] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n4 = match vec[3] {
-                        MaterialVariableType::INTEGER(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY4(n1, n2, n3, n4))
-                },
-                MaterialVariableType::FLOAT(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n3 = match vec[2] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n4 = match vec[3] {
-                        MaterialVariableType::FLOAT(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY4F(n1, n2, n3, n4))
-                },
-                MaterialVariableType::DOUBLE(_) => {
-                    let n1 = match vec[0] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n2 = match vec[1] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n3 = match vec[2] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    let n4 = match vec[3] {
-                        MaterialVariableType::DOUBLE(data) => data,
-                        _ => return Err("???")
-                    };
-                    return Ok(MaterialVariableType::ARRAY4D(n1, n2, n3, n4))
-                },
-                _ => return Err("????")
-            }
-        },
-        _ => return Err("Invalid vector size"),
     }
 }
 
-fn treat_value(val: pest::iterators::Pair<'_, Rule>, support_rvalues: bool) -> Result<MaterialVariableType, &'static str> {
-    let type_: MaterialVariableType;
-    match val.as_rule() {
-        Rule::string => {
-            let string = match val.into_inner().nth(0) {
-                Some(data) => data.as_str().to_owned(),
-                None => return Err("Invalid string")
-            };
-            type_ = MaterialVariableType::STRING(string);
-        },
-        Rule::number => {
-            let number = match val.into_inner().nth(0) {
-                Some(data) => data,
-                None => return Err("Invalid number")
-            };
-            match number.as_rule() {
-                Rule::float => {
-                    let string = number.as_str().to_owned();
-                    let number = match f32::from_parsed_number_string(&string) {
-                        Ok(n) => n,
-                        Err(e) => return Err(e)
-                    };
-                    type_ = MaterialVariableType::FLOAT(number);
-                },
-                Rule::double => {
-                    let string = number.as_str().to_owned();
-                    let number = match f64::from_parsed_number_string(&string) {
-                        Ok(n) => n,
-                        Err(e) => return Err(e)
-                    };
-                    type_ = MaterialVariableType::DOUBLE(number);
-                },
-                Rule::non_int | Rule::signed_non_int => {
-                    let string = number.as_str().to_owned();
-                    let number = match string.parse::<f64>() {
-                        Ok(n) => n,
-                        Err(_) => return Err("non_int considered as a double but resulted in parsing error")
-                    };
-                    type_ = MaterialVariableType::DOUBLE(number);
-                },
-                Rule::integer | Rule::signed_integer => {
-                    let string = number.as_str().to_owned();
-                    let number = match string.parse::<i32>() {
-                        Ok(n) => n,
-                        Err(_) => return Err("Invalid integer")
-                    };
-                    type_ = MaterialVariableType::INTEGER(number);
-                },
-                _ => return Err("Invalid number")
-            }
-        },
-        Rule::array => {
-            if support_rvalues {
-                type_ = match treat_arraydec(val.into_inner()) {
-                    Ok(data) => data,
-                    Err(e) => return Err(e)
+// dot <file> [--define <name>=<value>]... [--output <path>]
+fn export_dot(args: &[String]) {
+    let mut file = None;
+    let mut output = None;
+    let mut defines = Defines::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--define" | "-D" => add_define(args.next(), &mut defines),
+            "--output" | "-o" => {
+                match args.next() {
+                    Some(path) => output = Some(path),
+                    None => usage()
                 }
-            } else {
-                return Err("Cannot use vector declaration as proxy parameter")
-            }
+            },
+            _ if file.is_none() => file = Some(arg),
+            _ => usage()
         }
-        _ => return Err("Invalid value type in value")
     }
-    return Ok(type_)
-}
-
-fn treat_vardec(pair: &mut pest::iterators::Pairs<'_, Rule>, material: &mut MaterialFile) -> Result<(), &'static str> {
-    let varname = match pair.nth(0) {
-        Some(variable) => {
-            match variable.into_inner().nth(0) {
-                Some(ident) => {
-                    ident.as_str().to_owned()
-                },
-                None => return Err("Invalid identifier in vardec")
-            }
-        },
-        None => return Err("Expected 2 elements in vardec")
+    let file = match file {
+        Some(data) => data,
+        None => usage()
     };
 
-    let val = match pair.nth(0) {
-        Some(value) => {
-            match value.into_inner().nth(0) {
-                Some(data) => data,
-                None => return Err("Invalid value in vardec")
-            }
-        },
-        None => return Err("Expected 2 elements in vardec")
-    };
-
-    let type_ = match treat_value(val, true) {
-        Ok(data) => data,
-        Err(e) => return Err(e)
-    };
-    material.variables.insert(varname,   type_ );
-    Ok(())
-}
-
-trait FromParsedNumberString {
-    fn from_parsed_number_string(string: &String) -> Result<Self, &'static str> where Self: Sized;
+    let graph = write_dot(&read_material(file, &defines), &ProxyRegistry::with_builtins());
+    write_output(output, graph);
 }
 
-impl FromParsedNumberString for f32 {
-    //todo: use string.strip_suffix("f") when it will be stabilized
-    fn from_parsed_number_string(string: &String) -> Result<Self, &'static str> {
-        let size = string.len();
-        let string = string[..size - 1].to_owned();
-        match string.parse::<f32>() {
-            Ok(res) => Ok(res),
-            Err(_) => Err("Error while trying to parse a float string")
+// disasm <file> [--define <name>=<value>]...
+fn disassemble_file(args: &[String]) {
+    let mut file = None;
+    let mut defines = Defines::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--define" | "-D" => add_define(args.next(), &mut defines),
+            _ if file.is_none() => file = Some(arg),
+            _ => usage()
         }
     }
-}
-
-impl FromParsedNumberString for f64 {
-    //todo: use string.strip_suffix("d") when it will be stabilized
-    fn from_parsed_number_string(string: &String) -> Result<Self, &'static str> {
-        let size = string.len();
-        let string = string[..size - 1].to_owned();
-        match string.parse::<f64>() {
-            Ok(res) => Ok(res),
-            Err(_) => Err("Error while trying to parse a double string")
-        }
-    }
-}
-
-fn parse_material_file(data: &String) -> Result<MaterialFile, &'static str> {
-    let pairs = match SMFParser::parse(Rule::material, data) {
-        Ok(mut p) => {
-            match p.next() {
-                Some(item) => item,
-                None => return Err("Invalid Material File")
-            }
-        }
-        Err(_) => return Err("Invalid Material File")
+    let file = match file {
+        Some(data) => data,
+        None => usage()
     };
+    let program = compile_material(&read_material(file, &defines), &ProxyRegistry::with_builtins());
+    print!("{}", disassemble(&program));
+}
 
-    let mut material = MaterialFile {
-        shader: String::new(),
-        variables: HashMap::new(),
-        setup_proxies: Vec::with_capacity(2),
-        render_proxies: Vec::with_capacity(5),
+// simulate <file> [--define <name>=<value>]... [--frames <n>] [--dt <seconds>] [--set <field>=<expression>]... [--seed <n>] [--format csv|json] [--output <path>] [--trace]
+fn simulate_file(args: &[String]) {
+    let mut file = None;
+    let mut output = None;
+    let mut json = false;
+    let mut defines = Defines::new();
+    let mut options = SimulationOptions {
+        frames: 1,
+        dt: 0.016,
+        fields: Vec::new(),
+        trace: false,
+        seed: 0,
     };
-
-    for pair in pairs.into_inner() {
-        match pair.as_rule() {
-            Rule::identblockstart => {
-                match treat_identblockstart(&mut pair.into_inner(), &mut material) {
-                    Err(e) => return Err(e),
-                    _ => {}
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--define" | "-D" => add_define(args.next(), &mut defines),
+            "--frames" => {
+                options.frames = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage()
+                }
+            },
+            "--dt" => {
+                options.dt = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage()
+                }
+            },
+            "--set" => {
+                let (field, script) = match args.next().and_then(|a| a.split_once('=')) {
+                    Some(data) => data,
+                    None => usage()
+                };
+                let field = field.trim();
+                let field = field.strip_prefix("entity.").unwrap_or(field);
+                match parse_expression(script) {
+                    Ok(expression) => options.fields.push((field.to_owned(), expression)),
+                    Err(e) => {
+                        eprintln!("ERROR: --set {}: {}", field, e);
+                        exit(2)
+                    }
                 }
             },
-            Rule::vardec => {
-                match treat_vardec(&mut pair.into_inner(), &mut material) {
-                    Err(e) => return Err(e),
-                    _ => {}
+            "--seed" => {
+                options.seed = match args.next().and_then(|n| n.parse().ok()) {
+                    Some(n) => n,
+                    None => usage()
                 }
             },
-            Rule::setupproxyblock => {
-                match treat_proxyblock(&mut pair.into_inner(), &mut material.setup_proxies) {
-                    Ok(_) => {},
-                    Err(e) => return Err(e)
+            "--format" => {
+                json = match args.next().map(|f| f.as_str()) {
+                    Some("csv") => false,
+                    Some("json") => true,
+                    _ => usage()
                 }
             },
-            Rule::renderproxyblock => {
-                match treat_proxyblock(&mut pair.into_inner(), &mut material.render_proxies) {
-                    Ok(_) => {},
-                    Err(e) => return Err(e)
+            "--output" | "-o" => {
+                match args.next() {
+                    Some(path) => output = Some(path),
+                    None => usage()
                 }
             },
-            _ => println!("Unsupported rule: {:?}", pair.as_rule()),
+            "--trace" => options.trace = true,
+            _ if file.is_none() => file = Some(arg),
+            _ => usage()
         }
     }
-    // Sanity check
-    if material.shader.is_empty() {
-        return Err("No shader specified")
-    }
-    Ok(material)
-}
-
-fn print_material_information(material: &MaterialFile) {
-    println!("{}", Style::new().bold().paint("===============================\nINFORMATION ABOUT THE MATERIAL\n==============================="));
-    println!("{} {}", Style::new().bold().paint("SHADER:"), material.shader);
-
-    println!("{}", Style::new().bold().paint("VARIABLES:"));
-    for value in &material.variables {
-        println!("\t{}: {:?}", Style::new().italic().paint(value.0), value.1);
-    }
+    let file = match file {
+        Some(data) => data,
+        None => usage()
+    };
 
-    println!("{}", Style::new().bold().paint("PROXIES:"));
-    println!("{}", Style::new().bold().paint("\tSETUP"));
-    for value in &material.setup_proxies {
-        println!("\t  {}:", Style::new().italic().paint(&value.name));
-        for param in &value.parameters {
-            println!("\t    {}: {:?}", Style::new().italic().paint(param.0), param.1);
-        }
-    }
-    println!("{}", Style::new().bold().paint("\tRENDER"));
-    for value in &material.render_proxies {
-        println!("\t  {}:", Style::new().italic().paint(&value.name));
-        for param in &value.parameters {
-            println!("\t    {}: {:?}", Style::new().italic().paint(param.0), param.1);
+    let simulation = match simulate(&read_material(file, &defines), &ProxyRegistry::with_builtins(), &options) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("ERROR: {}: {}", file, e);
+            exit(1)
         }
+    };
+    // The trace goes to stderr so that the output stays a valid CSV or JSON document
+    eprint!("{}", simulation.trace);
+    write_output(output, if json { simulation.to_json() } else { simulation.to_csv() });
+    if let Some(e) = simulation.error {
+        eprintln!("ERROR: {}: {}", file, e);
+        exit(1)
     }
 }
 
@@ -665,10 +240,19 @@ fn main() {
     #[cfg(target_os = "windows")] //stupid windows stuff
     ansi_term::enable_ansi_support();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(|arg| arg.as_str()) {
+        Some("dot") => return export_dot(&args[1..]),
+        Some("disasm") => return disassemble_file(&args[1..]),
+        Some("simulate") => return simulate_file(&args[1..]),
+        Some(_) => usage(),
+        None => {}
+    }
+
     let buf = include_str!("UnlitGeneric.smf");
 
-    match parse_material_file(&buf.to_owned()) {
+    match parse_material_file(buf) {
         Ok(material) => print_material_information(&material),
         Err(e) => eprintln!("ERROR: {}", e)
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,133 @@
+// Build time optimizations of a material
+// fold_setup_proxies runs the setup proxies whose result is already known when the material is built:
+// $scale 64
+// SetupProxies { DivideF { srcvar 1 divisor $scale resultvar $inverse } }
+// becomes '$inverse 0.015625' without the proxy
+use crate::conditions::MaterialConditional;
+use crate::random::ProxyRng;
+use crate::runtime::{run_proxy, ProxyHost, ProxyRegistry, VariableStore};
+use crate::{MaterialError, MaterialFile, MaterialProxy, MaterialVariableReference, MaterialVariableType, EXPRESSION_PROXY, SETUP_PHASE};
+use std::collections::HashSet;
+
+// Hides the variables whose value is only known at apply time, proxies reading or writing them fail.
+// Proxies writing a variable read by a kept proxy fail too, the kept proxy must see the old value
+struct FoldStore<'a> {
+    material: &'a mut MaterialFile,
+    dynamic: &'a HashSet<String>,
+    read: &'a HashSet<String>,
+}
+
+impl<'a> VariableStore for FoldStore<'a> {
+    fn variable(&self, name: &str) -> Option<&MaterialVariableType> {
+        if self.dynamic.contains(name) {
+            return None
+        }
+        self.material.variables.get(name)
+    }
+
+    fn write_variable(&mut self, name: &str, value: MaterialVariableType) -> Result<(), MaterialError> {
+        if self.dynamic.contains(name) || self.read.contains(name) {
+            return Err(MaterialError::from(format!("'${}' is not a constant", name)))
+        }
+        self.material.set_variable(name, value)
+    }
+}
+
+// There is no entity at build time, proxies reading its fields fail. Only pure proxies are folded,
+// they do not draw random numbers
+struct FoldHost;
+
+impl ProxyHost for FoldHost {
+    fn entity_field(&mut self, _: &str) -> Option<MaterialVariableType> {
+        None
+    }
+}
+
+// Variables the proxy may write, every variable parameter for proxies the registry does not know
+fn written_variables<'a>(proxy: &'a MaterialProxy, registry: &ProxyRegistry) -> Vec<&'a str> {
+    // Name of the output parameter, "" if the proxy has none, None if the proxy is unknown
+    let output = if proxy.name == EXPRESSION_PROXY {
+        Some("resultvar")
+    } else {
+        registry.get(&proxy.name).map(|spec| spec.output().map_or("", |p| p.name))
+    };
+    let mut names = Vec::new();
+    for (parameter, reference) in proxy.parameters.iter() {
+        if output.is_some_and(|name| name != parameter) {
+            continue
+        }
+        match reference {
+            MaterialVariableReference::VARIABLE(name) | MaterialVariableReference::ARRAYREF(name, _) => names.push(name.as_str()),
+            _ => {}
+        }
+    }
+    names
+}
+
+// Variables the proxy may read, its guard and every parameter
+fn read_variables(proxy: &MaterialProxy) -> Vec<&str> {
+    let mut names = match &proxy.guard {
+        Some(guard) => guard.variables(),
+        None => Vec::new()
+    };
+    for (_, reference) in proxy.parameters.iter() {
+        match reference {
+            MaterialVariableReference::VARIABLE(name) | MaterialVariableReference::ARRAYREF(name, _) => names.push(name),
+            MaterialVariableReference::EXPRESSION(expression) => names.extend(expression.variables()),
+            MaterialVariableReference::TYPE(_) => {}
+        }
+    }
+    names
+}
+
+// Variables declared by #if blocks, their value depends on the defines of the caller
+fn conditional_variables(conditionals: &[MaterialConditional], names: &mut HashSet<String>) {
+    for conditional in conditionals {
+        for body in Some(&conditional.then).into_iter().chain(conditional.otherwise.as_ref()) {
+            names.extend(body.variables.keys().cloned());
+            conditional_variables(&body.conditionals, names);
+        }
+    }
+}
+
+/// Folds the setup proxies that are pure and whose inputs are constants into the variables of the
+/// material, and returns how many were folded. A proxy whose guard is constant and does not hold is
+/// removed. Proxies reading entity fields, impure proxies, proxies reading a variable written by a
+/// proxy that was not folded, proxies writing a variable read by a proxy that was not folded and
+/// proxies that fail are kept, the variables they write are no longer constants for the proxies after
+/// them. Setup is expected to run before any other phase.
+pub fn fold_setup_proxies(material: &mut MaterialFile, registry: &ProxyRegistry) -> usize {
+    let proxies = match material.phases.get_mut(SETUP_PHASE) {
+        Some(block) => std::mem::take(&mut block.proxies),
+        None => return 0
+    };
+    let mut dynamic = HashSet::new();
+    let mut read = HashSet::new();
+    conditional_variables(&material.conditionals, &mut dynamic);
+
+    let mut kept = Vec::with_capacity(proxies.len());
+    let mut folded = 0;
+    for proxy in proxies {
+        let pure = proxy.name == EXPRESSION_PROXY || registry.get(&proxy.name).is_some_and(|spec| spec.pure);
+        if pure {
+            let mut store = FoldStore {
+                material,
+                dynamic: &dynamic,
+                read: &read,
+            };
+            if run_proxy(&proxy, &mut store, registry, &mut FoldHost, &mut ProxyRng::new(0)).is_ok() {
+                folded += 1;
+                continue
+            }
+        }
+        for name in written_variables(&proxy, registry) {
+            dynamic.insert(name.to_owned());
+        }
+        for name in read_variables(&proxy) {
+            read.insert(name.to_owned());
+        }
+        kept.push(proxy);
+    }
+    material.proxies_mut(SETUP_PHASE).extend(kept);
+    folded
+}
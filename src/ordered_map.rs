@@ -0,0 +1,180 @@
+// String keyed map that remembers insertion order, used wherever source order matters
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Index;
+
+#[derive(Clone)]
+pub struct OrderedMap<V> {
+    entries: Vec<(String, V)>,
+    index: HashMap<String, usize>,
+}
+
+impl<V> OrderedMap<V> {
+    pub fn new() -> OrderedMap<V> {
+        OrderedMap {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> OrderedMap<V> {
+        OrderedMap {
+            entries: Vec::with_capacity(capacity),
+            index: HashMap::with_capacity(capacity),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        match self.index.get(key) {
+            Some(i) => Some(&mut self.entries[*i].1),
+            None => None
+        }
+    }
+
+    /// Position of the key in insertion order
+    pub fn index_of(&self, key: &str) -> Option<usize> {
+        self.index.get(key).cloned()
+    }
+
+    pub fn get_index(&self, index: usize) -> Option<(&String, &V)> {
+        self.entries.get(index).map(|entry| (&entry.0, &entry.1))
+    }
+
+    /// Inserts at the end, or replaces the value in place if the key already exists
+    pub fn insert(&mut self, key: String, value: V) -> Option<V> {
+        match self.index.get(&key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[*i].1, value)),
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Value of the key, inserted at the end if the key does not exist yet
+    pub fn get_or_insert_with<F: FnOnce() -> V>(&mut self, key: &str, default: F) -> &mut V {
+        let i = match self.index.get(key) {
+            Some(i) => *i,
+            None => {
+                self.index.insert(key.to_owned(), self.entries.len());
+                self.entries.push((key.to_owned(), default()));
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[i].1
+    }
+
+    /// Removes the key and keeps the order of the remaining entries
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let i = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for entry in &self.entries[i..] {
+            if let Some(index) = self.index.get_mut(&entry.0) {
+                *index -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.index.clear();
+    }
+
+    pub fn iter(&self) -> Iter<'_, V> {
+        self.entries.iter().map(entry_ref::<V> as fn(&(String, V)) -> (&String, &V))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.entries.iter().map(|entry| &entry.0)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.iter().map(|entry| &entry.1)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut V> {
+        self.entries.iter_mut().map(|entry| &mut entry.1)
+    }
+}
+
+fn entry_ref<V>(entry: &(String, V)) -> (&String, &V) {
+    (&entry.0, &entry.1)
+}
+
+impl<V> Default for OrderedMap<V> {
+    fn default() -> Self {
+        OrderedMap::new()
+    }
+}
+
+impl<V: fmt::Debug> fmt::Debug for OrderedMap<V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+// The order is part of the value, maps with the same entries in another order differ
+impl<V: PartialEq> PartialEq for OrderedMap<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.iter().eq(other.iter())
+    }
+}
+
+impl<V> Index<&str> for OrderedMap<V> {
+    type Output = V;
+
+    fn index(&self, key: &str) -> &V {
+        match self.get(key) {
+            Some(value) => value,
+            None => panic!("key '{}' not found in OrderedMap", key)
+        }
+    }
+}
+
+pub type Iter<'a, V> = std::iter::Map<std::slice::Iter<'a, (String, V)>, fn(&'a (String, V)) -> (&'a String, &'a V)>;
+
+impl<'a, V> IntoIterator for &'a OrderedMap<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<V> IntoIterator for OrderedMap<V> {
+    type Item = (String, V);
+    type IntoIter = std::vec::IntoIter<(String, V)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
+
+impl<V> std::iter::FromIterator<(String, V)> for OrderedMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let mut map = OrderedMap::new();
+        for (key, value) in iter {
+            map.insert(key, value);
+        }
+        map
+    }
+}
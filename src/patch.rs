@@ -0,0 +1,290 @@
+// Patch files modify an existing material in place:
+// patch "base/unlit_red"
+// {
+//     insert { $detailtexture "dev/noise" }
+//     replace { $color [0,1,0] RenderProxies { DivideF { srcvar $health divisor 50 resultvar $health } } }
+//     remove { $randomnumber SetupProxies { RandomDouble } RenderProxies { $color } }
+// }
+// Replaced and removed proxies are selected by their name and the variable their 'resultvar' writes,
+// a selector matching several proxies is an error. Assignments are selected by the variable they assign
+use crate::ordered_map::OrderedMap;
+use crate::{pest_error, treat_arrayref, treat_proxy, treat_proxyoverride, treat_proxyphase, treat_value, treat_vardec_entry, var_to_string};
+use crate::writer::write_reference;
+use crate::{MaterialError, MaterialFile, MaterialProxy, MaterialVariableKind, MaterialVariableReference, MaterialVariableType, Rule, SMFParser, SourceSpan};
+use pest::Parser;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum PatchAction {
+    INSERT,  // adds the target, or overwrites it if it exists
+    REPLACE, // overwrites the target, which must exist
+    REMOVE,  // removes the target, which must exist
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum PatchTarget {
+    VARIABLE (String, Option<MaterialVariableType>, Option<MaterialVariableKind>), // no value when removing
+    PROXY (String, ProxySelector, Option<Box<MaterialProxy>>), // phase, no proxy when removing
+}
+
+/// Selects the proxies of a phase a patch replaces or removes
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProxySelector {
+    pub name: Option<String>, // any name if None
+    pub target: Option<MaterialVariableReference>, // the 'resultvar' of the proxy, any if None
+}
+
+impl ProxySelector {
+    /// Selects the proxies with the name of 'proxy' writing the same variable
+    pub fn of(proxy: &MaterialProxy) -> ProxySelector {
+        ProxySelector {
+            name: Some(proxy.name.clone()),
+            target: proxy.parameters.get("resultvar").cloned(),
+        }
+    }
+
+    pub fn matches(&self, proxy: &MaterialProxy) -> bool {
+        let name = match &self.name {
+            Some(name) => *name == proxy.name,
+            None => true
+        };
+        let target = match &self.target {
+            Some(target) => proxy.parameters.get("resultvar") == Some(target),
+            None => true
+        };
+        name && target
+    }
+
+    fn describe(&self) -> String {
+        match (&self.name, &self.target) {
+            (Some(name), Some(target)) => format!("proxy '{}' writing '{}'", name, write_reference(target)),
+            (Some(name), None) => format!("proxy '{}'", name),
+            (None, Some(target)) => format!("proxy writing '{}'", write_reference(target)),
+            (None, None) => String::from("any proxy")
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct PatchOperation {
+    pub action: PatchAction,
+    pub target: PatchTarget,
+    pub span: SourceSpan,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct MaterialPatch {
+    pub target: String, // name of the patched material
+    pub operations: Vec<PatchOperation>,
+}
+
+fn treat_patchproxies(pair: pest::iterators::Pair<'_, Rule>, action: PatchAction, operations: &mut Vec<PatchOperation>) -> Result<(), MaterialError> {
+    let span = SourceSpan::from_pest(&pair.as_span());
+    let mut pair = pair.into_inner();
+    if treat_proxyoverride(&mut pair) {
+        return Err(MaterialError::new("'override' cannot be used in a patch".to_owned(), span))
+    }
+    let phase = treat_proxyphase(&mut pair)?;
+    for proxy in pair {
+        let span = SourceSpan::from_pest(&proxy.as_span());
+        let proxy = treat_proxy(proxy, &OrderedMap::new())?;
+        operations.push(PatchOperation {
+            action,
+            target: PatchTarget::PROXY(phase.clone(), ProxySelector::of(&proxy), Some(Box::new(proxy))),
+            span,
+        });
+    }
+    Ok(())
+}
+
+fn treat_proxyselector(pair: pest::iterators::Pair<'_, Rule>) -> Result<ProxySelector, MaterialError> {
+    let mut selector = ProxySelector {
+        name: None,
+        target: None,
+    };
+    for element in pair.into_inner() {
+        match element.as_rule() {
+            Rule::ident => selector.name = Some(element.as_str().to_owned()),
+            Rule::variable => selector.target = Some(MaterialVariableReference::VARIABLE(var_to_string(&mut element.into_inner())?)),
+            Rule::arrayref => selector.target = Some(treat_arrayref(&mut element.into_inner())?),
+            _ => return Err("Invalid proxy selector".into())
+        }
+    }
+    Ok(selector)
+}
+
+fn treat_patchop(pair: pest::iterators::Pair<'_, Rule>, action: PatchAction, operations: &mut Vec<PatchOperation>) -> Result<(), MaterialError> {
+    for element in pair.into_inner() {
+        let span = SourceSpan::from_pest(&element.as_span());
+        match element.as_rule() {
+            Rule::vardec => {
+                let (varname, value, declared_type) = treat_vardec_entry(&mut element.into_inner())?;
+                operations.push(PatchOperation {
+                    action,
+                    target: PatchTarget::VARIABLE(varname, Some(value), declared_type),
+                    span,
+                });
+            },
+            Rule::proxyblock => {
+                match treat_patchproxies(element, action, operations) {
+                    Ok(_) => {},
+                    Err(e) => return Err(e)
+                }
+            },
+            Rule::variable => {
+                let varname = var_to_string(&mut element.into_inner())?;
+                operations.push(PatchOperation {
+                    action,
+                    target: PatchTarget::VARIABLE(varname, None, None),
+                    span,
+                });
+            },
+            Rule::proxyremove => {
+                let mut element = element.into_inner();
+                let phase = treat_proxyphase(&mut element)?;
+                for selector in element {
+                    let span = SourceSpan::from_pest(&selector.as_span());
+                    let selector = treat_proxyselector(selector)?;
+                    operations.push(PatchOperation {
+                        action,
+                        target: PatchTarget::PROXY(phase.clone(), selector, None),
+                        span,
+                    });
+                }
+            },
+            _ => return Err("Invalid patch operation".into())
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_patch_file(data: &str) -> Result<MaterialPatch, MaterialError> {
+    let pairs = match SMFParser::parse(Rule::patch, data) {
+        Ok(mut p) => {
+            match p.next() {
+                Some(item) => item,
+                None => return Err("Invalid Patch File".into())
+            }
+        }
+        Err(e) => return Err(pest_error(e))
+    };
+
+    let mut patch = MaterialPatch {
+        target: String::new(),
+        operations: Vec::new(),
+    };
+
+    for pair in pairs.into_inner() {
+        let result = match pair.as_rule() {
+            Rule::string => {
+                match treat_value(pair, false) {
+                    Ok(MaterialVariableType::STRING(target)) => {
+                        patch.target = target;
+                        Ok(())
+                    },
+                    Ok(_) => Err("Expected string as patched material".into()),
+                    Err(e) => Err(e)
+                }
+            },
+            Rule::insertop => treat_patchop(pair, PatchAction::INSERT, &mut patch.operations),
+            Rule::replaceop => treat_patchop(pair, PatchAction::REPLACE, &mut patch.operations),
+            Rule::removeop => treat_patchop(pair, PatchAction::REMOVE, &mut patch.operations),
+            Rule::EOI => Ok(()),
+            _ => Err("Unsupported rule in patch".into())
+        };
+        match result {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(patch)
+}
+
+fn missing(operation: &PatchOperation, what: String) -> MaterialError {
+    let action = match operation.action {
+        PatchAction::INSERT => "insert",
+        PatchAction::REPLACE => "replace",
+        PatchAction::REMOVE => "remove",
+    };
+    MaterialError::new(format!("Cannot {} {}: it does not exist", action, what), operation.span)
+}
+
+fn apply_variable(material: &mut MaterialFile, operation: &PatchOperation, varname: &str, value: &Option<MaterialVariableType>, declared_type: &Option<MaterialVariableKind>) -> Result<(), MaterialError> {
+    let exists = material.variables.contains_key(varname);
+    let value = match (operation.action, value) {
+        (PatchAction::REMOVE, _) | (_, None) => {
+            if !exists {
+                return Err(missing(operation, format!("'${}'", varname)))
+            }
+            material.variables.remove(varname);
+            material.declared_types.remove(varname);
+            return Ok(())
+        },
+        (PatchAction::REPLACE, Some(_)) if !exists => return Err(missing(operation, format!("'${}'", varname))),
+        (_, Some(value)) => value
+    };
+
+    // the type declared in the patch wins over the one of the material
+    let declared_type = match declared_type {
+        Some(kind) => Some(*kind),
+        None => material.declared_types.get(varname).cloned()
+    };
+    let value = match declared_type {
+        Some(kind) => match value.coerce(kind) {
+            Some(data) => data,
+            None => return Err(MaterialError::new(format!("Type mismatch: '${}' is declared as '{}' but its value is a '{}'", varname, kind.name(), value.kind().name()), operation.span))
+        },
+        None => value.clone()
+    };
+    if let Some(kind) = declared_type {
+        material.declared_types.insert(varname.to_owned(), kind);
+    }
+    material.variables.insert(varname.to_owned(), value);
+    Ok(())
+}
+
+fn apply_proxy(material: &mut MaterialFile, operation: &PatchOperation, phase: &str, selector: &ProxySelector, proxy: &Option<Box<MaterialProxy>>) -> Result<(), MaterialError> {
+    let block = format!("{}Proxies", phase);
+    let proxies = match (operation.action, material.phases.get_mut(phase)) {
+        (PatchAction::INSERT, _) => material.proxies_mut(phase),
+        (_, Some(data)) => &mut data.proxies,
+        (_, None) => return Err(missing(operation, format!("{} in {}", selector.describe(), block)))
+    };
+    if let (PatchAction::INSERT, Some(proxy)) = (operation.action, proxy) {
+        proxies.push((**proxy).clone());
+        return Ok(())
+    }
+
+    let mut matches = proxies.iter().enumerate().filter(|(_, p)| selector.matches(p)).map(|(i, _)| i);
+    let index = match (matches.next(), matches.count()) {
+        (Some(index), 0) => index,
+        (Some(_), others) => {
+            let message = format!("Ambiguous patch: {} proxies of {} match the {}", others + 1, block, selector.describe());
+            return Err(MaterialError::new(message, operation.span))
+        },
+        (None, _) => return Err(missing(operation, format!("{} in {}", selector.describe(), block)))
+    };
+    match proxy {
+        Some(proxy) => proxies[index] = (**proxy).clone(),
+        None => {
+            proxies.remove(index);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the operations of the patch in order
+/// Operations that cannot be applied are skipped and returned
+pub fn apply_patch(material: &mut MaterialFile, patch: &MaterialPatch) -> Vec<MaterialError> {
+    let mut errors = Vec::new();
+    for operation in &patch.operations {
+        let result = match &operation.target {
+            PatchTarget::VARIABLE(varname, value, declared_type) => apply_variable(material, operation, varname, value, declared_type),
+            PatchTarget::PROXY(phase, selector, proxy) => apply_proxy(material, operation, phase, selector, proxy),
+        };
+        match result {
+            Ok(_) => {},
+            Err(e) => errors.push(e)
+        }
+    }
+    errors
+}
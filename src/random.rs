@@ -0,0 +1,48 @@
+// Reproducible randomness for the proxies
+// Every phase of a material instance draws from its own stream, seeded from the seed of the instance
+// and the name of the phase: the numbers a phase gets do not depend on how often the other phases ran.
+// The algorithm is part of the format, the same seed gives the same numbers on every platform:
+// - the generator is SplitMix64: state += 0x9E3779B97F4A7C15, then the state is mixed with
+//   z = (z ^ (z >> 30)) * 0xBF58476D1CE4E5B9, z = (z ^ (z >> 27)) * 0x94D049BB133111EB, z ^ (z >> 31)
+// - a number in [0, 1) is the top 53 bits of the next output divided by 2^53
+// - the state of a phase is the seed of the instance xor the 64 bit FNV-1a hash of the phase name
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ProxyRng {
+    state: u64,
+}
+
+// FNV-1a, 64 bits
+fn hash_name(name: &str) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for byte in name.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
+
+impl ProxyRng {
+    pub fn new(seed: u64) -> ProxyRng {
+        ProxyRng {
+            state: seed,
+        }
+    }
+
+    /// The stream of a phase of an instance seeded with 'seed'
+    pub fn for_phase(seed: u64, phase: &str) -> ProxyRng {
+        ProxyRng::new(seed ^ hash_name(phase))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed number in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
@@ -0,0 +1,646 @@
+// Proxy runtime: runs the proxies of a material over its variables
+// Each proxy is described by a ProxySpec: the role of its parameters, the kinds its inputs are
+// converted to and the function computing its output. Assignments lowered to 'Expression'
+// proxies run and validate in the same chain as the named proxies
+use crate::expression::{ExpressionContext, KindContext};
+use crate::random::ProxyRng;
+use crate::{vector_from, MaterialError, MaterialFile, MaterialProxy, MaterialVariableKind, MaterialVariableReference, MaterialVariableType, SourceSpan, EXPRESSION_PROXY, SETUP_PHASE};
+use std::collections::HashMap;
+
+/// What the engine provides to the proxies of a material
+pub trait ProxyHost {
+    /// Field of the entity the material is drawn on, e.g. "health" for `entity.health` and EntityGetHealth
+    fn entity_field(&mut self, name: &str) -> Option<MaterialVariableType>;
+}
+
+/// Variables read and written by the proxies
+pub trait VariableStore {
+    fn variable(&self, name: &str) -> Option<&MaterialVariableType>;
+    /// Writes a variable that exists, converting the value to its declared type
+    fn write_variable(&mut self, name: &str, value: MaterialVariableType) -> Result<(), MaterialError>;
+}
+
+impl VariableStore for MaterialFile {
+    fn variable(&self, name: &str) -> Option<&MaterialVariableType> {
+        self.variables.get(name)
+    }
+
+    fn write_variable(&mut self, name: &str, value: MaterialVariableType) -> Result<(), MaterialError> {
+        self.set_variable(name, value)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParameterRole {
+    INPUT,
+    OUTPUT, // must reference a variable
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParameterSpec {
+    pub name: &'static str,
+    pub role: ParameterRole,
+    pub kind: Option<MaterialVariableKind>, // inputs are converted to it, None accepts anything
+    pub default: Option<MaterialVariableType>, // inputs without a default are required
+}
+
+/// Computes the output of a proxy from its inputs, given in the order of ProxySpec::parameters.
+/// Random numbers come from the stream of the phase, see random.rs
+pub type ProxyFunction = fn(&[MaterialVariableType], &mut dyn ProxyHost, &mut ProxyRng) -> Result<MaterialVariableType, MaterialError>;
+
+#[derive(Clone)]
+pub struct ProxySpec {
+    pub name: &'static str,
+    pub parameters: Vec<ParameterSpec>, // a single OUTPUT
+    pub result: MaterialVariableKind, // kind of the value written to the output
+    pub pure: bool, // deterministic and independent of the host
+    pub function: ProxyFunction,
+}
+
+/// Proxies and entity fields known to the runtime
+#[derive(Clone)]
+pub struct ProxyRegistry {
+    proxies: HashMap<String, ProxySpec>,
+    entity_fields: HashMap<String, MaterialVariableKind>,
+}
+
+impl ProxySpec {
+    pub fn parameter(&self, name: &str) -> Option<&ParameterSpec> {
+        self.parameters.iter().find(|p| p.name == name)
+    }
+
+    pub fn output(&self) -> Option<&ParameterSpec> {
+        self.parameters.iter().find(|p| p.role == ParameterRole::OUTPUT)
+    }
+}
+
+fn input(name: &'static str, kind: MaterialVariableKind, default: Option<MaterialVariableType>) -> ParameterSpec {
+    ParameterSpec {
+        name,
+        role: ParameterRole::INPUT,
+        kind: Some(kind),
+        default,
+    }
+}
+
+fn output(name: &'static str) -> ParameterSpec {
+    ParameterSpec {
+        name,
+        role: ParameterRole::OUTPUT,
+        kind: None,
+        default: None,
+    }
+}
+
+fn entity_get_health(_: &[MaterialVariableType], host: &mut dyn ProxyHost, _: &mut ProxyRng) -> Result<MaterialVariableType, MaterialError> {
+    match host.entity_field("health") {
+        Some(value) => Ok(value),
+        None => Err("The host does not provide 'entity.health'".into())
+    }
+}
+
+fn divide_f(args: &[MaterialVariableType], _: &mut dyn ProxyHost, _: &mut ProxyRng) -> Result<MaterialVariableType, MaterialError> {
+    match (&args[0], &args[1]) {
+        (_, MaterialVariableType::FLOAT(d)) if *d == 0.0 => Err("Division by zero".into()),
+        (MaterialVariableType::FLOAT(n), MaterialVariableType::FLOAT(d)) => Ok(MaterialVariableType::FLOAT(n / d)),
+        _ => Err("DivideF expects floats".into())
+    }
+}
+
+fn random_double(args: &[MaterialVariableType], _: &mut dyn ProxyHost, rng: &mut ProxyRng) -> Result<MaterialVariableType, MaterialError> {
+    match (&args[0], &args[1]) {
+        (MaterialVariableType::DOUBLE(min), MaterialVariableType::DOUBLE(max)) => Ok(MaterialVariableType::DOUBLE(min + (max - min) * rng.next_f64())),
+        _ => Err("RandomDouble expects doubles".into())
+    }
+}
+
+impl ProxyRegistry {
+    /// A registry without any proxy or entity field
+    pub fn new() -> ProxyRegistry {
+        ProxyRegistry {
+            proxies: HashMap::new(),
+            entity_fields: HashMap::new(),
+        }
+    }
+
+    /// The built-in proxies: EntityGetHealth, DivideF and RandomDouble, and the 'health' entity field
+    pub fn with_builtins() -> ProxyRegistry {
+        use MaterialVariableKind::*;
+        let mut registry = ProxyRegistry::new();
+        registry.register(ProxySpec {
+            name: "EntityGetHealth",
+            parameters: vec![output("resultvar")],
+            result: INTEGER,
+            pure: false,
+            function: entity_get_health,
+        });
+        registry.register(ProxySpec {
+            name: "DivideF",
+            parameters: vec![input("srcvar", FLOAT, None), input("divisor", FLOAT, None), output("resultvar")],
+            result: FLOAT,
+            pure: true,
+            function: divide_f,
+        });
+        registry.register(ProxySpec {
+            name: "RandomDouble",
+            parameters: vec![
+                input("min", DOUBLE, Some(MaterialVariableType::DOUBLE(0.0))),
+                input("max", DOUBLE, Some(MaterialVariableType::DOUBLE(1.0))),
+                output("resultvar"),
+            ],
+            result: DOUBLE,
+            pure: false,
+            function: random_double,
+        });
+        registry.register_entity_field("health", INTEGER);
+        registry
+    }
+
+    /// Adds a proxy, or replaces the one with the same name
+    pub fn register(&mut self, spec: ProxySpec) {
+        self.proxies.insert(spec.name.to_owned(), spec);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ProxySpec> {
+        self.proxies.get(name)
+    }
+
+    /// Declares a field the host provides through ProxyHost::entity_field
+    pub fn register_entity_field(&mut self, name: &str, kind: MaterialVariableKind) {
+        self.entity_fields.insert(name.to_owned(), kind);
+    }
+
+    pub fn entity_field_kind(&self, name: &str) -> Option<MaterialVariableKind> {
+        self.entity_fields.get(name).cloned()
+    }
+}
+
+impl Default for ProxyRegistry {
+    fn default() -> Self {
+        ProxyRegistry::with_builtins()
+    }
+}
+
+/// One bit per variable, set when its value changes, so that the engine only uploads those
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DirtyFlags {
+    bits: Vec<u64>,
+}
+
+impl DirtyFlags {
+    /// Flags for 'len' variables, all of them set: nothing has been uploaded yet
+    pub fn all(len: usize) -> DirtyFlags {
+        let mut bits = vec![u64::MAX; len.div_ceil(64)];
+        let rest = len % 64;
+        if rest > 0 {
+            if let Some(last) = bits.last_mut() {
+                *last = (1u64 << rest) - 1;
+            }
+        }
+        DirtyFlags { bits }
+    }
+
+    pub fn mark(&mut self, index: usize) {
+        if let Some(word) = self.bits.get_mut(index / 64) {
+            *word |= 1u64 << (index % 64);
+        }
+    }
+
+    pub fn contains(&self, index: usize) -> bool {
+        self.bits.get(index / 64).is_some_and(|word| word & (1u64 << (index % 64)) != 0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bits.iter().all(|word| *word == 0)
+    }
+
+    pub fn clear(&mut self) {
+        for word in &mut self.bits {
+            *word = 0;
+        }
+    }
+
+    /// Indices of the set flags, in increasing order
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.bits.iter().enumerate().flat_map(|(i, word)| {
+            let word = *word;
+            (0..64).filter(move |bit| word & (1u64 << bit) != 0).map(move |bit| i * 64 + bit)
+        })
+    }
+}
+
+// Element 'index' of a vector or of a list
+pub(crate) fn element(value: &MaterialVariableType, name: &str, index: u32) -> Result<MaterialVariableType, MaterialError> {
+    let i = index as usize;
+    let element = match value {
+        MaterialVariableType::LIST(values) => values.get(i).cloned(),
+        _ => match value.vector_parts() {
+            Some((parts, len)) if i < len => Some(parts[i].clone()),
+            Some(_) => None,
+            None => return Err(format!("'${}' is a '{}', it cannot be indexed", name, value.kind().name()).into())
+        }
+    };
+    match element {
+        Some(data) => Ok(data),
+        None => Err(format!("Index {} is out of range for '${}'", index, name).into())
+    }
+}
+
+// Replaces element 'index' of a vector or of a list, vectors keep their type
+pub(crate) fn with_element(value: &MaterialVariableType, name: &str, index: u32, element: MaterialVariableType) -> Result<MaterialVariableType, MaterialError> {
+    let i = index as usize;
+    let out_of_range = || format!("Index {} is out of range for '${}'", index, name).into();
+    if let MaterialVariableType::LIST(values) = value {
+        if i >= values.len() {
+            return Err(out_of_range())
+        }
+        let mut elements = values.clone();
+        elements[i] = element;
+        return Ok(MaterialVariableType::LIST(elements))
+    }
+    let (mut parts, len) = match value.vector_parts() {
+        Some(data) => data,
+        None => return Err(format!("'${}' is a '{}', it cannot be indexed", name, value.kind().name()).into())
+    };
+    if i >= len {
+        return Err(out_of_range())
+    }
+    let element_kind = element.kind();
+    parts[i] = element;
+    let kind = value.kind();
+    match vector_from(kind, &parts[..len]) {
+        Some(data) => Ok(data),
+        None => Err(format!("Cannot write a '{}' into an element of '${}', a '{}'", element_kind.name(), name, kind.name()).into())
+    }
+}
+
+struct RuntimeContext<'a> {
+    store: &'a dyn VariableStore,
+    host: &'a mut dyn ProxyHost,
+}
+
+impl<'a> ExpressionContext for RuntimeContext<'a> {
+    fn variable(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        match self.store.variable(name) {
+            Some(value) => Ok(value.clone()),
+            None => Err(MaterialError::new(format!("Unknown variable '${}'", name), span))
+        }
+    }
+
+    fn entity_field(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        match self.host.entity_field(name) {
+            Some(value) => Ok(value),
+            None => Err(MaterialError::new(format!("The host does not provide 'entity.{}'", name), span))
+        }
+    }
+}
+
+fn read_reference(reference: &MaterialVariableReference, store: &dyn VariableStore, host: &mut dyn ProxyHost) -> Result<MaterialVariableType, MaterialError> {
+    match reference {
+        MaterialVariableReference::TYPE(value) => Ok(value.clone()),
+        MaterialVariableReference::VARIABLE(name) => {
+            match store.variable(name) {
+                Some(value) => Ok(value.clone()),
+                None => Err(format!("Unknown variable '${}'", name).into())
+            }
+        },
+        MaterialVariableReference::ARRAYREF(name, index) => {
+            match store.variable(name) {
+                Some(value) => element(value, name, *index),
+                None => Err(format!("Unknown variable '${}'", name).into())
+            }
+        },
+        MaterialVariableReference::EXPRESSION(expression) => expression.evaluate(&mut RuntimeContext { store, host }),
+    }
+}
+
+fn write_reference(reference: &MaterialVariableReference, parameter: &str, value: MaterialVariableType, store: &mut dyn VariableStore) -> Result<(), MaterialError> {
+    match reference {
+        MaterialVariableReference::VARIABLE(name) => {
+            if store.variable(name).is_none() {
+                return Err(format!("Unknown variable '${}'", name).into())
+            }
+            store.write_variable(name, value)
+        },
+        MaterialVariableReference::ARRAYREF(name, index) => {
+            let current = match store.variable(name) {
+                Some(data) => data,
+                None => return Err(format!("Unknown variable '${}'", name).into())
+            };
+            match with_element(current, name, *index, value) {
+                Ok(data) => store.write_variable(name, data),
+                Err(e) => Err(e)
+            }
+        },
+        _ => Err(format!("'{}' must be a variable", parameter).into())
+    }
+}
+
+fn missing_parameter(parameter: &str) -> MaterialError {
+    format!("Missing parameter '{}'", parameter).into()
+}
+
+fn run_expression(proxy: &MaterialProxy, store: &mut dyn VariableStore, host: &mut dyn ProxyHost) -> Result<(), MaterialError> {
+    let value = match proxy.parameters.get("expression") {
+        Some(reference) => read_reference(reference, store, host),
+        None => return Err(missing_parameter("expression"))
+    };
+    let value = value?;
+    match proxy.parameters.get("resultvar") {
+        Some(target) => write_reference(target, "resultvar", value, store),
+        None => Err(missing_parameter("resultvar"))
+    }
+}
+
+fn run_named(proxy: &MaterialProxy, spec: &ProxySpec, store: &mut dyn VariableStore, host: &mut dyn ProxyHost, rng: &mut ProxyRng) -> Result<(), MaterialError> {
+    let mut args = Vec::with_capacity(spec.parameters.len());
+    for parameter in spec.parameters.iter().filter(|p| p.role == ParameterRole::INPUT) {
+        let value = match (proxy.parameters.get(parameter.name), &parameter.default) {
+            (Some(reference), _) => read_reference(reference, store, host),
+            (None, Some(default)) => Ok(default.clone()),
+            (None, None) => Err(missing_parameter(parameter.name))
+        };
+        let value = value?;
+        let value = match parameter.kind {
+            Some(kind) => match value.coerce(kind) {
+                Some(data) => data,
+                None => return Err(format!("Parameter '{}' expects a '{}', got a '{}'", parameter.name, kind.name(), value.kind().name()).into())
+            },
+            None => value
+        };
+        args.push(value);
+    }
+    let result = (spec.function)(&args, host, rng)?;
+    let result = match result.coerce(spec.result) {
+        Some(data) => data,
+        None => return Err(format!("Returned a '{}' instead of a '{}'", result.kind().name(), spec.result.name()).into())
+    };
+    match spec.output() {
+        Some(parameter) => {
+            match proxy.parameters.get(parameter.name) {
+                Some(target) => write_reference(target, parameter.name, result, store),
+                None => Err(missing_parameter(parameter.name))
+            }
+        },
+        None => Ok(())
+    }
+}
+
+/// Runs a single proxy, nothing happens if its guard does not hold.
+/// Random numbers are drawn from 'rng', the stream of the phase the proxy belongs to
+pub fn run_proxy(proxy: &MaterialProxy, store: &mut dyn VariableStore, registry: &ProxyRegistry, host: &mut dyn ProxyHost, rng: &mut ProxyRng) -> Result<(), MaterialError> {
+    let holds = match &proxy.guard {
+        Some(guard) => guard.evaluate(&mut RuntimeContext { store, host }),
+        None => Ok(true)
+    };
+    let result = match holds {
+        Ok(false) => Ok(()),
+        Err(e) => Err(e),
+        Ok(true) if proxy.name == EXPRESSION_PROXY => {
+            run_expression(proxy, store, host)
+        },
+        Ok(true) => {
+            match registry.get(&proxy.name) {
+                Some(spec) => run_named(proxy, spec, store, host, rng),
+                None => Err(format!("Unknown proxy '{}'", proxy.name).into())
+            }
+        }
+    };
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => Err(MaterialError {
+            message: format!("In '{}': {}", proxy.name, e.message),
+            span: e.span.or(proxy.span),
+            related: e.related,
+        })
+    }
+}
+
+/// Runs the proxies in order, stops at the first error
+pub fn run_proxies(proxies: &[MaterialProxy], store: &mut dyn VariableStore, registry: &ProxyRegistry, host: &mut dyn ProxyHost, rng: &mut ProxyRng) -> Result<(), MaterialError> {
+    for proxy in proxies {
+        match run_proxy(proxy, store, registry, host, rng) {
+            Ok(_) => {},
+            Err(e) => return Err(e)
+        }
+    }
+    Ok(())
+}
+
+// A value of the given kind, only used to check conversions between kinds
+fn sample(kind: MaterialVariableKind) -> MaterialVariableType {
+    use MaterialVariableType::*;
+    match kind {
+        MaterialVariableKind::NONE => NONE,
+        MaterialVariableKind::FLOAT => FLOAT(0.0),
+        MaterialVariableKind::DOUBLE => DOUBLE(0.0),
+        MaterialVariableKind::INTEGER => INTEGER(0),
+        MaterialVariableKind::STRING => STRING(String::new()),
+        MaterialVariableKind::ARRAY2 => ARRAY2(0, 0),
+        MaterialVariableKind::ARRAY3 => ARRAY3(0, 0, 0),
+        MaterialVariableKind::ARRAY4 => ARRAY4(0, 0, 0, 0),
+        MaterialVariableKind::ARRAY2F => ARRAY2F(0.0, 0.0),
+        MaterialVariableKind::ARRAY3F => ARRAY3F(0.0, 0.0, 0.0),
+        MaterialVariableKind::ARRAY4F => ARRAY4F(0.0, 0.0, 0.0, 0.0),
+        MaterialVariableKind::ARRAY2D => ARRAY2D(0.0, 0.0),
+        MaterialVariableKind::ARRAY3D => ARRAY3D(0.0, 0.0, 0.0),
+        MaterialVariableKind::ARRAY4D => ARRAY4D(0.0, 0.0, 0.0, 0.0),
+        MaterialVariableKind::LIST => LIST(Vec::new()),
+        MaterialVariableKind::MAP => MAP(Vec::new()),
+    }
+}
+
+fn can_coerce(from: MaterialVariableKind, to: MaterialVariableKind) -> bool {
+    sample(from).coerce(to).is_some()
+}
+
+// Kind of element 'index' of a value of the given kind, None for lists
+fn element_kind(kind: MaterialVariableKind, name: &str) -> Result<Option<MaterialVariableKind>, MaterialError> {
+    match kind {
+        MaterialVariableKind::LIST => Ok(None),
+        _ => match sample(kind).components() {
+            Some(components) => Ok(Some(components[0].kind())),
+            None => Err(format!("'${}' is a '{}', it cannot be indexed", name, kind.name()).into())
+        }
+    }
+}
+
+// Kinds of the variables as the proxies run, a proxy writing a variable without declared type changes its kind
+struct Validator<'a> {
+    material: &'a MaterialFile,
+    registry: &'a ProxyRegistry,
+    kinds: HashMap<String, MaterialVariableKind>,
+    errors: Vec<MaterialError>,
+}
+
+impl<'a> KindContext for Validator<'a> {
+    fn variable_kind(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableKind, MaterialError> {
+        match self.kinds.get(name) {
+            Some(kind) => Ok(*kind),
+            None => Err(MaterialError::new(format!("Unknown variable '${}'", name), span))
+        }
+    }
+
+    fn entity_field_kind(&mut self, name: &str, span: SourceSpan) -> Result<MaterialVariableKind, MaterialError> {
+        match self.registry.entity_field_kind(name) {
+            Some(kind) => Ok(kind),
+            None => Err(MaterialError::new(format!("Unknown entity field 'entity.{}'", name), span))
+        }
+    }
+}
+
+impl<'a> Validator<'a> {
+    fn error(&mut self, proxy: &MaterialProxy, e: MaterialError) {
+        self.errors.push(MaterialError {
+            message: format!("In '{}': {}", proxy.name, e.message),
+            span: e.span.or(proxy.span),
+            related: e.related,
+        });
+    }
+
+    // Kind of an input, None if it cannot be known before running
+    fn input_kind(&mut self, reference: &MaterialVariableReference) -> Result<Option<MaterialVariableKind>, MaterialError> {
+        match reference {
+            MaterialVariableReference::TYPE(value) => Ok(Some(value.kind())),
+            MaterialVariableReference::VARIABLE(name) => {
+                match self.kinds.get(name) {
+                    Some(kind) => Ok(Some(*kind)),
+                    None => Err(format!("Unknown variable '${}'", name).into())
+                }
+            },
+            MaterialVariableReference::ARRAYREF(name, _) => {
+                match self.kinds.get(name) {
+                    Some(kind) => element_kind(*kind, name),
+                    None => Err(format!("Unknown variable '${}'", name).into())
+                }
+            },
+            MaterialVariableReference::EXPRESSION(expression) => expression.infer_kind(self).map(Some),
+        }
+    }
+
+    fn check_output(&mut self, reference: &MaterialVariableReference, parameter: &str, result: Option<MaterialVariableKind>) -> Result<(), MaterialError> {
+        let (name, index) = match reference {
+            MaterialVariableReference::VARIABLE(name) => (name, None),
+            MaterialVariableReference::ARRAYREF(name, index) => (name, Some(*index)),
+            _ => return Err(format!("'{}' must be a variable", parameter).into())
+        };
+        let current = match self.kinds.get(name) {
+            Some(kind) => *kind,
+            None => return Err(format!("Unknown variable '${}'", name).into())
+        };
+        let result = match result {
+            Some(kind) => kind,
+            None => return Ok(())
+        };
+        let target = match index {
+            Some(_) => match element_kind(current, name) {
+                Ok(Some(kind)) => kind,
+                Ok(None) => return Ok(()),
+                Err(e) => return Err(e)
+            },
+            None => match self.material.declared_types.get(name) {
+                Some(kind) => *kind,
+                None => {
+                    self.kinds.insert(name.clone(), result);
+                    return Ok(())
+                }
+            }
+        };
+        if !can_coerce(result, target) {
+            let into = match index {
+                Some(_) => format!("an element of '${}'", name),
+                None => format!("'${}'", name)
+            };
+            return Err(format!("Cannot write a '{}' into {}, a '{}'", result.name(), into, target.name()).into())
+        }
+        Ok(())
+    }
+
+    fn check_expression(&mut self, proxy: &MaterialProxy) -> Result<(), MaterialError> {
+        let result = match proxy.parameters.get("expression") {
+            Some(reference) => self.input_kind(reference),
+            None => return Err(missing_parameter("expression"))
+        };
+        let result = result?;
+        match proxy.parameters.get("resultvar") {
+            Some(target) => self.check_output(target, "resultvar", result),
+            None => Err(missing_parameter("resultvar"))
+        }
+    }
+
+    fn check_named(&mut self, proxy: &MaterialProxy, spec: &ProxySpec) {
+        for (name, _) in &proxy.parameters {
+            if spec.parameter(name).is_none() {
+                self.error(proxy, format!("Unknown parameter '{}'", name).into());
+            }
+        }
+        for parameter in spec.parameters.iter().filter(|p| p.role == ParameterRole::INPUT) {
+            let reference = match (proxy.parameters.get(parameter.name), &parameter.default) {
+                (Some(reference), _) => reference,
+                (None, Some(_)) => continue,
+                (None, None) => {
+                    self.error(proxy, missing_parameter(parameter.name));
+                    continue;
+                }
+            };
+            match (self.input_kind(reference), parameter.kind) {
+                (Ok(Some(kind)), Some(expected)) if !can_coerce(kind, expected) => {
+                    self.error(proxy, format!("Parameter '{}' expects a '{}', got a '{}'", parameter.name, expected.name(), kind.name()).into());
+                },
+                (Err(e), _) => self.error(proxy, e),
+                _ => {}
+            }
+        }
+        if let Some(parameter) = spec.output() {
+            let result = match proxy.parameters.get(parameter.name) {
+                Some(target) => self.check_output(target, parameter.name, Some(spec.result)),
+                None => Err(missing_parameter(parameter.name))
+            };
+            if let Err(e) = result {
+                self.error(proxy, e);
+            }
+        }
+    }
+
+    fn check(&mut self, proxies: &[MaterialProxy]) {
+        for proxy in proxies {
+            if let Some(guard) = &proxy.guard {
+                if let Err(e) = guard.check(self) {
+                    self.error(proxy, e);
+                }
+            }
+            if proxy.name == EXPRESSION_PROXY {
+                if let Err(e) = self.check_expression(proxy) {
+                    self.error(proxy, e);
+                }
+                continue;
+            }
+            match self.registry.get(&proxy.name) {
+                Some(spec) => self.check_named(proxy, spec),
+                None => self.error(proxy, format!("Unknown proxy '{}'", proxy.name).into())
+            }
+        }
+    }
+}
+
+/// Checks the proxies of every phase of a material before running them: proxy and parameter names, references to
+/// variables and the kinds of the values. Conditionals are not checked, validate the material
+/// returned by MaterialFile::evaluate_conditions
+pub fn validate_proxies(material: &MaterialFile, registry: &ProxyRegistry) -> Vec<MaterialError> {
+    let mut validator = Validator {
+        material,
+        registry,
+        kinds: material.variables.iter().map(|(name, value)| (name.clone(), value.kind())).collect(),
+        errors: Vec::new(),
+    };
+    // Setup runs first, the other phases start from the kinds it leaves
+    validator.check(material.setup_proxies());
+    let after_setup = validator.kinds.clone();
+    for (phase, block) in &material.phases {
+        if phase != SETUP_PHASE {
+            validator.kinds = after_setup.clone();
+            validator.check(&block.proxies);
+        }
+    }
+    validator.errors
+}
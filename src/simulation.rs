@@ -0,0 +1,245 @@
+// Frame by frame simulation of a material without the engine
+// Setup runs once, then Render runs every frame on a mock host. The entity fields of the host are
+// scripted with expressions of the frame number and of the time since the first frame:
+// health = 100 - $frame
+// pulse = $time * 2
+// The values of every variable after every frame are recorded and written as CSV or JSON
+use crate::bytecode::{compile_material, MaterialProgram, PhaseIndex, ProgramState};
+use crate::disassembler::trace_phase;
+use crate::expression::Expression;
+use crate::runtime::{ProxyHost, ProxyRegistry};
+use crate::writer::write_value;
+use crate::{MaterialError, MaterialFile, MaterialVariableKind, MaterialVariableType, SourceSpan, RENDER_PHASE, SETUP_PHASE};
+
+pub struct SimulationOptions {
+    pub frames: u32,
+    pub dt: f64, // seconds between two frames
+    pub fields: Vec<(String, Expression)>, // entity fields and their script
+    pub trace: bool, // record the instructions run by every phase
+    pub seed: u64, // seed of the random numbers, see ProxyRng
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct SimulationFrame {
+    pub frame: u32,
+    pub time: f64,
+    pub values: Vec<MaterialVariableType>, // in the order of Simulation::variables
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Simulation {
+    pub variables: Vec<String>,
+    pub frames: Vec<SimulationFrame>,
+    pub trace: String, // empty unless SimulationOptions::trace is set
+    pub error: Option<MaterialError>, // the simulation stops at the first error, the frames before it are kept
+}
+
+// Host of the simulation, the scripts are evaluated every time a proxy reads a field
+struct ScriptedHost<'a> {
+    fields: &'a [(String, Expression)],
+    registry: &'a ProxyRegistry,
+    frame: u32,
+    time: f64,
+    error: Option<MaterialError>, // of the last script that failed, the field it computes is missing
+}
+
+impl<'a> ScriptedHost<'a> {
+    fn clock(&self, name: &str, span: SourceSpan) -> Result<MaterialVariableType, MaterialError> {
+        match name {
+            "frame" => Ok(MaterialVariableType::INTEGER(self.frame as i32)),
+            "time" => Ok(MaterialVariableType::DOUBLE(self.time)),
+            _ => Err(MaterialError::new(format!("Unknown variable '${}', scripts can use '$frame' and '$time'", name), span))
+        }
+    }
+}
+
+impl<'a> ProxyHost for ScriptedHost<'a> {
+    fn entity_field(&mut self, name: &str) -> Option<MaterialVariableType> {
+        let fields = self.fields;
+        let script = match fields.iter().find(|(field, _)| field == name) {
+            Some((_, data)) => data,
+            None => return None
+        };
+        let value = match script.evaluate(&mut |variable: &str, span: SourceSpan| self.clock(variable, span)) {
+            Ok(data) => data,
+            Err(e) => {
+                self.error = Some(frame_error(&format!("In the script of '{}'", name), e));
+                return None
+            }
+        };
+        // Scripts using '$time' compute doubles, they are truncated for integer fields
+        match (self.registry.entity_field_kind(name), value.as_f64()) {
+            (Some(MaterialVariableKind::INTEGER), Some(n)) => Some(MaterialVariableType::INTEGER(n as i32)),
+            (Some(kind), _) => value.coerce(kind).or(Some(value)),
+            (None, _) => Some(value)
+        }
+    }
+}
+
+fn frame_error(frame: &str, e: MaterialError) -> MaterialError {
+    MaterialError {
+        message: format!("{}: {}", frame, e.message),
+        span: e.span,
+        related: e.related,
+    }
+}
+
+// A phase the material has no block for does nothing. A script that fails is reported instead of the
+// missing field
+fn run(program: &MaterialProgram, phase: Option<PhaseIndex>, state: &mut ProgramState, host: &mut ScriptedHost<'_>, trace: Option<&mut String>) -> Result<(), MaterialError> {
+    let result = match (phase, trace) {
+        (Some(index), Some(out)) => trace_phase(program, index, state, host, out),
+        (Some(index), None) => program.run_index(index, state, host),
+        (None, _) => Ok(())
+    };
+    match (result, host.error.take()) {
+        (Err(_), Some(e)) => Err(e),
+        (result, _) => result
+    }
+}
+
+/// Runs Setup, then Render for every frame, and records the variables after every frame.
+/// Fails if a script references something else than '$frame' and '$time'. A proxy or script error stops
+/// the simulation, it is returned with the frames before it and its message says which frame failed
+pub fn simulate(material: &MaterialFile, registry: &ProxyRegistry, options: &SimulationOptions) -> Result<Simulation, MaterialError> {
+    for (name, script) in &options.fields {
+        let host = ScriptedHost { fields: &[], registry, frame: 0, time: 0.0, error: None };
+        if let Err(e) = script.evaluate(&mut |variable: &str, span: SourceSpan| host.clock(variable, span)) {
+            return Err(frame_error(&format!("In the script of '{}'", name), e))
+        }
+    }
+    let program = compile_material(material, registry);
+    let mut state = program.seeded_state(options.seed);
+    let setup = program.phase_index(SETUP_PHASE);
+    let render = program.phase_index(RENDER_PHASE);
+    let mut host = ScriptedHost {
+        fields: &options.fields,
+        registry,
+        frame: 0,
+        time: 0.0,
+        error: None,
+    };
+    let mut simulation = Simulation {
+        variables: program.slots.iter().map(|slot| slot.name.clone()).collect(),
+        frames: Vec::with_capacity(options.frames as usize),
+        trace: String::new(),
+        error: None,
+    };
+    let mut trace = if options.trace { Some(String::new()) } else { None };
+
+    if let Err(e) = run(&program, setup, &mut state, &mut host, trace.as_mut()) {
+        simulation.error = Some(frame_error("Setup", e));
+    }
+    for frame in 0..options.frames {
+        if simulation.error.is_some() {
+            break
+        }
+        host.frame = frame;
+        host.time = f64::from(frame) * options.dt;
+        if let Some(out) = trace.as_mut() {
+            out.push_str(&format!("; frame {}\n", frame));
+        }
+        if let Err(e) = run(&program, render, &mut state, &mut host, trace.as_mut()) {
+            simulation.error = Some(frame_error(&format!("Frame {}", frame), e));
+            break
+        }
+        simulation.frames.push(SimulationFrame {
+            frame,
+            time: host.time,
+            values: state.values.clone(),
+        });
+    }
+    if let Some(out) = trace {
+        simulation.trace = out;
+    }
+    Ok(simulation)
+}
+
+// Cells holding a separator or a quote are quoted, quotes are doubled
+fn csv_cell(cell: String) -> String {
+    if cell.contains(',') || cell.contains('"') || cell.contains('\n') {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell
+    }
+}
+
+fn json_string(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len() + 2);
+    escaped.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_number(n: f64) -> String {
+    if n.is_finite() {
+        n.to_string()
+    } else {
+        String::from("null")
+    }
+}
+
+fn json_value(value: &MaterialVariableType) -> String {
+    use MaterialVariableType::*;
+    match value {
+        NONE => String::from("null"),
+        FLOAT(n) if n.is_finite() => n.to_string(),
+        FLOAT(_) => String::from("null"),
+        DOUBLE(n) => json_number(*n),
+        INTEGER(n) => n.to_string(),
+        STRING(s) => json_string(s),
+        LIST(values) => {
+            let elements: Vec<String> = values.iter().map(json_value).collect();
+            format!("[{}]", elements.join(", "))
+        },
+        MAP(entries) => {
+            let elements: Vec<String> = entries.iter().map(|(key, value)| format!("{}: {}", json_string(key), json_value(value))).collect();
+            format!("{{{}}}", elements.join(", "))
+        },
+        _ => match value.components() {
+            Some(components) => json_value(&LIST(components)),
+            None => String::from("null")
+        }
+    }
+}
+
+impl Simulation {
+    /// One line per frame: the frame, the time, then a column per variable with its value as written in a material file
+    pub fn to_csv(&self) -> String {
+        let mut header = vec![String::from("frame"), String::from("time")];
+        header.extend(self.variables.iter().map(|name| csv_cell(format!("${}", name))));
+        let mut out = header.join(",") + "\n";
+        for frame in &self.frames {
+            let mut row = vec![frame.frame.to_string(), frame.time.to_string()];
+            row.extend(frame.values.iter().map(|value| csv_cell(write_value(value))));
+            out.push_str(&row.join(","));
+            out.push('\n');
+        }
+        out
+    }
+
+    /// An array of frames, each with its variables: vectors and lists are arrays, maps are objects
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("[\n");
+        for (i, frame) in self.frames.iter().enumerate() {
+            let values: Vec<String> = self.variables.iter().zip(&frame.values)
+                .map(|(name, value)| format!("{}: {}", json_string(name), json_value(value)))
+                .collect();
+            out.push_str(&format!("  {{\"frame\": {}, \"time\": {}, \"variables\": {{{}}}}}", frame.frame, json_number(frame.time), values.join(", ")));
+            out.push_str(if i + 1 < self.frames.len() { ",\n" } else { "\n" });
+        }
+        out.push_str("]\n");
+        out
+    }
+}
@@ -0,0 +1,138 @@
+// Material templates: materials with named parameters, instantiated with constant arguments
+// template Tinted(tex, col) UnlitGeneric { $basetexture tex $color col }
+// Tinted("dev/a", [1, 0, 0])
+// An instance is expanded into an ordinary material. Parameters are bare names, they can be used in
+// any expression of the template: declarations, assignments and guards
+use crate::composite::{expand_material, treat_definition, ProxyDefinition};
+use crate::expression::treat_expression;
+use crate::ordered_map::OrderedMap;
+use crate::{treat_material, MaterialError, MaterialFile, MaterialVariableType, Rule, SourceSpan};
+
+struct MaterialTemplate<'i> {
+    parameters: Vec<String>,
+    body: pest::iterators::Pair<'i, Rule>, // treated again for every instance
+    span: SourceSpan,
+}
+
+/// Proxy definitions and templates of a file, usable by the materials below them
+pub(crate) struct Declarations<'i> {
+    definitions: OrderedMap<ProxyDefinition>,
+    templates: OrderedMap<MaterialTemplate<'i>>,
+}
+
+impl<'i> Declarations<'i> {
+    pub(crate) fn new() -> Declarations<'i> {
+        Declarations {
+            definitions: OrderedMap::new(),
+            templates: OrderedMap::new(),
+        }
+    }
+
+    // Treats a 'definition' or a 'template'
+    pub(crate) fn treat(&mut self, pair: pest::iterators::Pair<'i, Rule>) -> Result<(), MaterialError> {
+        match pair.as_rule() {
+            Rule::definition => treat_definition(pair, &mut self.definitions),
+            Rule::template => self.treat_template(pair),
+            _ => Err("Expected definition or template".into())
+        }
+    }
+
+    fn treat_template(&mut self, pair: pest::iterators::Pair<'i, Rule>) -> Result<(), MaterialError> {
+        let span = SourceSpan::from_pest(&pair.as_span());
+        let mut inner = pair.into_inner();
+        let name = match inner.next() {
+            Some(data) => data.as_str().to_owned(),
+            None => return Err("Expected name in 'template'".into())
+        };
+        if let Some(first) = self.templates.get(&name) {
+            return Err(MaterialError::with_related(format!("Duplicate template '{}'", name), span, first.span))
+        }
+        let mut parameters: Vec<String> = Vec::new();
+        match inner.next() {
+            Some(data) => {
+                for parameter in data.into_inner() {
+                    let parameter_name = parameter.as_str().to_owned();
+                    if parameters.contains(&parameter_name) {
+                        return Err(MaterialError::new(format!("Duplicate parameter '{}' in template '{}'", parameter_name, name), SourceSpan::from_pest(&parameter.as_span())))
+                    }
+                    parameters.push(parameter_name);
+                }
+            },
+            None => return Err("Expected parameters in 'template'".into())
+        }
+        let body = match inner.next() {
+            Some(data) => data,
+            None => return Err("Expected material in 'template'".into())
+        };
+        self.templates.insert(name, MaterialTemplate {
+            parameters,
+            body,
+            span,
+        });
+        Ok(())
+    }
+
+    // Treats a 'material' or an 'instantiation', then expands the defined proxies it invokes
+    pub(crate) fn material(&self, pair: pest::iterators::Pair<'i, Rule>) -> Result<MaterialFile, MaterialError> {
+        let material = match pair.as_rule() {
+            Rule::instantiation => self.instantiate(pair),
+            _ => treat_material(pair, &OrderedMap::new())
+        };
+        match material {
+            Ok(mut material) => {
+                match expand_material(&mut material, &self.definitions) {
+                    Ok(_) => Ok(material),
+                    Err(e) => Err(e)
+                }
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    fn instantiate(&self, pair: pest::iterators::Pair<'i, Rule>) -> Result<MaterialFile, MaterialError> {
+        let span = SourceSpan::from_pest(&pair.as_span());
+        let mut inner = pair.into_inner();
+        let name = match inner.next() {
+            Some(data) => data.as_str().to_owned(),
+            None => return Err("Expected template name in instantiation".into())
+        };
+        let template = match self.templates.get(&name) {
+            Some(data) => data,
+            None => return Err(MaterialError::new(format!("Unknown template '{}'", name), span))
+        };
+
+        let mut values = Vec::new();
+        for argument in inner {
+            let expression = treat_expression(argument)?;
+            match expression.evaluate(&mut |variable: &str, span: SourceSpan| Err(MaterialError::new(format!("Variables cannot be referenced in template arguments: '${}'", variable), span))) {
+                Ok(value) => values.push(value),
+                Err(e) => return Err(e)
+            }
+        }
+        if values.len() != template.parameters.len() {
+            return Err(MaterialError::with_related(format!("'{}' expects {} arguments, got {}", name, template.parameters.len(), values.len()), span, template.span))
+        }
+        let mut arguments: OrderedMap<MaterialVariableType> = OrderedMap::with_capacity(values.len());
+        for (parameter, value) in template.parameters.iter().zip(values) {
+            arguments.insert(parameter.clone(), value);
+        }
+
+        // Errors point at the template body and refer to the instantiation, unless they already refer
+        // to another location of the body: the instantiation is then given in the message
+        match treat_material(template.body.clone(), &arguments) {
+            Ok(material) => Ok(material),
+            Err(e) => Err(match e.related {
+                Some(related) => MaterialError {
+                    message: format!("In template '{}' instantiated at line {}, column {}: {}", name, span.line, span.column, e.message),
+                    span: e.span.or(Some(template.span)),
+                    related: Some(related),
+                },
+                None => MaterialError {
+                    message: format!("In template '{}': {}", name, e.message),
+                    span: e.span.or(Some(template.span)),
+                    related: Some(span),
+                }
+            })
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Serializes a MaterialFile back to SMF
+use crate::{MaterialFile, MaterialProxy, MaterialVariableReference, MaterialVariableType, EXPRESSION_PROXY};
+
+/// Escapes a string so that it can be written between quotes
+pub fn escape_string(string: &str) -> String {
+    let mut escaped = String::with_capacity(string.len() + 2);
+    for c in string.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            '\0' => escaped.push_str("\\0"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+    escaped
+}
+
+// The grammar needs a '.' to tell a floating point number from an integer,
+// infinities and NaN are written inf, -inf and nan
+fn write_decimal(n: f64, string: String) -> String {
+    if n.is_nan() {
+        String::from("nan")
+    } else if n.is_infinite() {
+        String::from(if n > 0.0 { "inf" } else { "-inf" })
+    } else if string.contains('.') {
+        string
+    } else {
+        string + ".0"
+    }
+}
+
+fn write_float(n: f32) -> String {
+    write_decimal(f64::from(n), n.to_string()) + "f"
+}
+
+fn write_double(n: f64) -> String {
+    write_decimal(n, n.to_string())
+}
+
+/// Writes a value the way it would appear in a material file
+pub fn write_value(value: &MaterialVariableType) -> String {
+    match value {
+        MaterialVariableType::NONE => String::from("none"),
+        MaterialVariableType::FLOAT(n) => write_float(*n),
+        MaterialVariableType::DOUBLE(n) => write_double(*n),
+        MaterialVariableType::INTEGER(n) => n.to_string(),
+        MaterialVariableType::STRING(s) => format!("\"{}\"", escape_string(s)),
+
+        MaterialVariableType::ARRAY2(a, b) => format!("[{}, {}]", a, b),
+        MaterialVariableType::ARRAY3(a, b, c) => format!("[{}, {}, {}]", a, b, c),
+        MaterialVariableType::ARRAY4(a, b, c, d) => format!("[{}, {}, {}, {}]", a, b, c, d),
+
+        MaterialVariableType::ARRAY2F(a, b) => format!("[{}, {}]", write_float(*a), write_float(*b)),
+        MaterialVariableType::ARRAY3F(a, b, c) => format!("[{}, {}, {}]", write_float(*a), write_float(*b), write_float(*c)),
+        MaterialVariableType::ARRAY4F(a, b, c, d) => format!("[{}, {}, {}, {}]", write_float(*a), write_float(*b), write_float(*c), write_float(*d)),
+
+        MaterialVariableType::ARRAY2D(a, b) => format!("[{}, {}]", write_double(*a), write_double(*b)),
+        MaterialVariableType::ARRAY3D(a, b, c) => format!("[{}, {}, {}]", write_double(*a), write_double(*b), write_double(*c)),
+        MaterialVariableType::ARRAY4D(a, b, c, d) => format!("[{}, {}, {}, {}]", write_double(*a), write_double(*b), write_double(*c), write_double(*d)),
+
+        MaterialVariableType::LIST(values) => {
+            let elements: Vec<String> = values.iter().map(write_value).collect();
+            if looks_like_vector(values) {
+                // the trailing comma keeps it from being read back as a vector
+                format!("[{},]", elements.join(", "))
+            } else {
+                format!("[{}]", elements.join(", "))
+            }
+        },
+        MaterialVariableType::MAP(entries) => {
+            if entries.is_empty() {
+                return String::from("{}");
+            }
+            let elements: Vec<String> = entries.iter().map(|(key, value)| format!("{} {}", key, write_value(value))).collect();
+            format!("{{ {} }}", elements.join(" "))
+        },
+    }
+}
+
+fn looks_like_vector(values: &[MaterialVariableType]) -> bool {
+    let is_number = |value: &MaterialVariableType| matches!(value,
+        MaterialVariableType::FLOAT(_) | MaterialVariableType::DOUBLE(_) | MaterialVariableType::INTEGER(_));
+    values.len() >= 2 && values.len() <= 4 && values.iter().all(is_number)
+}
+
+pub fn write_reference(reference: &MaterialVariableReference) -> String {
+    match reference {
+        MaterialVariableReference::TYPE(value) => write_value(value),
+        MaterialVariableReference::VARIABLE(name) => format!("${}", name),
+        MaterialVariableReference::ARRAYREF(name, index) => format!("${}[{}]", name, index),
+        MaterialVariableReference::EXPRESSION(expression) => expression.to_string(),
+    }
+}
+
+// Lowered assignments are written back as assignments
+pub(crate) fn write_assignment(proxy: &MaterialProxy) -> Option<String> {
+    if proxy.name != EXPRESSION_PROXY || proxy.parameters.len() != 2 {
+        return None;
+    }
+    match (proxy.parameters.get("expression"), proxy.parameters.get("resultvar")) {
+        (Some(MaterialVariableReference::EXPRESSION(expression)), Some(target)) => {
+            match &proxy.guard {
+                Some(guard) => Some(format!("{} = {} if {}", write_reference(target), expression, guard)),
+                None => Some(format!("{} = {}", write_reference(target), expression))
+            }
+        },
+        _ => None
+    }
+}
+
+// Blank line between blocks, but not right after an opening brace
+fn separate(out: &mut String) {
+    if !out.ends_with("{\n") {
+        out.push('\n');
+    }
+}
+
+fn write_proxyblock(out: &mut String, indent: &str, block_name: &str, proxies: &[MaterialProxy], override_parent: bool) {
+    if proxies.is_empty() && !override_parent {
+        return;
+    }
+    separate(out);
+    if override_parent {
+        out.push_str(&format!("{}override {}\n{}{{\n", indent, block_name, indent));
+    } else {
+        out.push_str(&format!("{}{}\n{}{{\n", indent, block_name, indent));
+    }
+    for proxy in proxies {
+        if let Some(assignment) = write_assignment(proxy) {
+            out.push_str(&format!("{}\t{}\n", indent, assignment));
+            continue;
+        }
+        match &proxy.guard {
+            Some(guard) => out.push_str(&format!("{}\t{} if {}\n{}\t{{\n", indent, proxy.name, guard, indent)),
+            None => out.push_str(&format!("{}\t{}\n{}\t{{\n", indent, proxy.name, indent))
+        }
+        for (name, reference) in &proxy.parameters {
+            out.push_str(&format!("{}\t\t{} {}\n", indent, name, write_reference(reference)));
+        }
+        out.push_str(&format!("{}\t}}\n", indent));
+    }
+    out.push_str(&format!("{}}}\n", indent));
+}
+
+// Everything between the braces of a material or of a conditional block
+fn write_body(out: &mut String, material: &MaterialFile, indent: &str) {
+    for (name, value) in &material.variables {
+        match material.declared_types.get(name) {
+            Some(kind) => out.push_str(&format!("{}${}: {} {}\n", indent, name, kind.name(), write_value(value))),
+            None => out.push_str(&format!("{}${} {}\n", indent, name, write_value(value)))
+        }
+    }
+    for (phase, block) in &material.phases {
+        write_proxyblock(out, indent, &format!("{}Proxies", phase), &block.proxies, block.override_parent);
+    }
+
+    let inner = format!("{}\t", indent);
+    for conditional in &material.conditionals {
+        separate(out);
+        out.push_str(&format!("{}#if {}\n{}{{\n", indent, conditional.condition, indent));
+        write_body(out, &conditional.then, &inner);
+        out.push_str(&format!("{}}}\n", indent));
+        if let Some(otherwise) = &conditional.otherwise {
+            out.push_str(&format!("{}#else\n{}{{\n", indent, indent));
+            write_body(out, otherwise, &inner);
+            out.push_str(&format!("{}}}\n", indent));
+        }
+    }
+}
+
+/// Writes a material file that parses back to the same MaterialFile
+pub fn write_material_file(material: &MaterialFile) -> String {
+    let mut out = match &material.parent {
+        Some(parent) => format!("{} : \"{}\"\n{{\n", material.shader, escape_string(parent)),
+        None => format!("{}\n{{\n", material.shader)
+    };
+    write_body(&mut out, material, "\t");
+    out.push_str("}\n");
+    out
+}
@@ -0,0 +1,93 @@
+use materialparser::dataflow::{analyze_dataflow, DataflowIssueKind};
+use materialparser::dot::write_dot;
+use materialparser::optimize::fold_setup_proxies;
+use materialparser::runtime::ProxyRegistry;
+use materialparser::{parse_material_file, MaterialFile, MaterialVariableType, SETUP_PHASE};
+
+fn parse(source: &str) -> MaterialFile {
+    match parse_material_file(source) {
+        Ok(data) => data,
+        Err(e) => panic!("{}", e)
+    }
+}
+
+#[test]
+fn expands_composite_proxies() {
+    let material = parse(r#"define Half(in, out) { DivideF { srcvar $in divisor 2 resultvar $out } }
+UnlitGeneric
+{
+	$a 4.0
+	$b 0.0
+	RenderProxies
+	{
+		Half { in $a out $b }
+	}
+}"#);
+    let proxies = material.render_proxies();
+    assert_eq!(proxies.len(), 1);
+    assert_eq!(proxies[0].name, "DivideF");
+
+    let e = parse_material_file("define Half(in, out) { DivideF { srcvar $in divisor 2 resultvar $out } }\nUnlitGeneric { $a 0 RenderProxies { Half { in $a } } }").unwrap_err();
+    assert!(e.message.contains("Half"), "{}", e.message);
+}
+
+#[test]
+fn folds_constant_setup_proxies() {
+    let mut material = parse(r#"UnlitGeneric
+{
+	$a 0.0
+	$b 0.0
+	$health 0
+	SetupProxies
+	{
+		DivideF { srcvar 3 divisor 2 resultvar $a }
+		$b = $a * 2
+		EntityGetHealth { resultvar $health }
+		RandomDouble { resultvar $b }
+	}
+}"#);
+    let folded = fold_setup_proxies(&mut material, &ProxyRegistry::with_builtins());
+    assert_eq!(folded, 2);
+    assert_eq!(material.variables.get("a"), Some(&MaterialVariableType::FLOAT(1.5)));
+    assert_eq!(material.variables.get("b"), Some(&MaterialVariableType::FLOAT(3.0)));
+    let kept: Vec<&str> = material.proxies(SETUP_PHASE).iter().map(|proxy| proxy.name.as_str()).collect();
+    assert_eq!(kept, ["EntityGetHealth", "RandomDouble"]);
+
+    // $y reads $x before it is written
+    let mut material = parse("UnlitGeneric { $x 1 $h 0 $y 0 SetupProxies { EntityGetHealth { resultvar $h } $y = $x + $h  $x = 5 } }");
+    assert_eq!(fold_setup_proxies(&mut material, &ProxyRegistry::with_builtins()), 0);
+    assert_eq!(material.variables.get("x"), Some(&MaterialVariableType::INTEGER(1)));
+    assert_eq!(material.proxies(SETUP_PHASE).len(), 3);
+}
+
+#[test]
+fn reports_dataflow_issues() {
+    let material = parse(r#"UnlitGeneric
+{
+	$basetexture "a"
+	$unused 0
+	$scale 0.0
+	$tmp 0.0
+	RenderProxies
+	{
+		$scale = $tmp * 2
+		$tmp = 1.0
+		$tmp = 2.0
+	}
+}"#);
+    let dataflow = analyze_dataflow(&material, &ProxyRegistry::with_builtins(), &["basetexture", "scale"]);
+    let has = |kind: DataflowIssueKind, variable: &str| dataflow.issues.iter().any(|issue| issue.kind == kind && issue.variable == variable);
+    assert!(has(DataflowIssueKind::UNUSED_VARIABLE, "unused"), "{:?}", dataflow.issues);
+    assert!(has(DataflowIssueKind::OVERWRITTEN_RESULT, "tmp"), "{:?}", dataflow.issues);
+    assert!(!has(DataflowIssueKind::UNUSED_VARIABLE, "basetexture"), "{:?}", dataflow.issues);
+}
+
+#[test]
+fn writes_the_dataflow_graph() {
+    let material = parse("UnlitGeneric { $health 0 $scale 0.0 RenderProxies { EntityGetHealth { resultvar $health } DivideF { srcvar $health divisor 100 resultvar $scale } } }");
+    let dot = write_dot(&material, &ProxyRegistry::with_builtins());
+    assert!(dot.starts_with("digraph"), "{}", dot);
+    assert!(dot.contains("EntityGetHealth") && dot.contains("DivideF"), "{}", dot);
+    assert!(dot.contains("$health") && dot.contains("$scale"), "{}", dot);
+    assert!(dot.trim_end().ends_with('}'), "{}", dot);
+}
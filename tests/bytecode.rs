@@ -0,0 +1,132 @@
+use materialparser::bytecode::compile_material;
+use materialparser::disassembler::trace_phase;
+use materialparser::random::ProxyRng;
+use materialparser::runtime::{run_proxies, ProxyHost, ProxyRegistry};
+use materialparser::{parse_material_file, MaterialFile, MaterialVariableType, RENDER_PHASE, SETUP_PHASE};
+
+const MATERIAL: &str = r#"UnlitGeneric
+{
+	$basetexture "dev/gradient_dif"
+	$name "a"
+	$names ["x", "y"]
+	$color [1.0, 1.0, 1.0]
+	$offset: vec2f [0, 0]
+	$health 0
+	$scale 0.0
+	$pulse 0.0
+	$alpha 1.0
+	$frame 0
+	$noise 0.0
+
+	RenderProxies
+	{
+		EntityGetHealth { resultvar $health }
+		DivideF { srcvar $health divisor 100 resultvar $scale }
+		RandomDouble { min 0 max $scale resultvar $noise }
+		$pulse = clamp($scale * 2 - 0.5, 0, 1)
+		$color = [1, $scale, $scale * $pulse]
+		DivideF { srcvar $pulse divisor 2 resultvar $color[2] }
+		$offset = -[$frame, 1] * 0.5
+		$alpha = lerp(0.25, 1, $scale) if $health > 0
+		$alpha = 0 if $health <= 0 || $frame < 0
+		$name = $basetexture if $frame > 2
+		$names = [$name, $basetexture, ]
+		$frame = $frame + 1 if $frame < 50
+		$frame = 0 if $frame >= 50
+	}
+}"#;
+
+struct Host {
+    health: i32,
+}
+
+impl ProxyHost for Host {
+    fn entity_field(&mut self, name: &str) -> Option<MaterialVariableType> {
+        match name {
+            "health" => Some(MaterialVariableType::INTEGER(self.health)),
+            _ => None
+        }
+    }
+}
+
+fn material() -> MaterialFile {
+    match parse_material_file(MATERIAL) {
+        Ok(data) => data,
+        Err(e) => panic!("{}", e)
+    }
+}
+
+#[test]
+fn compiled_program_matches_the_interpreter_on_every_frame() {
+    let material = material();
+    let registry = ProxyRegistry::with_builtins();
+    let proxies = material.proxies(RENDER_PHASE).to_vec();
+    let program = compile_material(&material, &registry);
+    let mut state = program.state();
+    let mut interpreted = material.clone();
+    let mut rng = ProxyRng::for_phase(0, RENDER_PHASE);
+
+    for frame in 0..120 {
+        let health = frame % 101;
+        run_proxies(&proxies, &mut interpreted, &registry, &mut Host { health }, &mut rng).unwrap();
+        program.run(RENDER_PHASE, &mut state, &mut Host { health }).unwrap();
+        let mut compiled = material.clone();
+        program.write_back(&state, &mut compiled);
+        assert_eq!(compiled.variables, interpreted.variables, "frame {}", frame);
+        assert!(state.stack().is_empty(), "frame {}", frame);
+    }
+    assert_eq!(interpreted.variables.get("name"), Some(&MaterialVariableType::STRING(String::from("dev/gradient_dif"))));
+}
+
+#[test]
+fn compiled_program_reports_the_errors_of_the_interpreter() {
+    let material = parse_material_file(r#"UnlitGeneric
+{
+	$a 1
+	$v [1, 2]
+	RenderProxies
+	{
+		$v = $v + [1, 2, 3]
+	}
+}"#).unwrap();
+    let registry = ProxyRegistry::with_builtins();
+    let program = compile_material(&material, &registry);
+    let mut state = program.state();
+    let compiled = program.run(RENDER_PHASE, &mut state, &mut Host { health: 0 }).unwrap_err();
+    let interpreted = run_proxies(material.proxies(RENDER_PHASE), &mut material.clone(), &registry, &mut Host { health: 0 }, &mut ProxyRng::new(0)).unwrap_err();
+    assert_eq!(compiled.message, interpreted.message);
+    assert!(compiled.message.contains("their sizes differ"), "{}", compiled.message);
+}
+
+#[test]
+fn traces_write_the_variables_and_every_change() {
+    let material = parse_material_file(r#"UnlitGeneric
+{
+	$health 50
+	$scale 0.0
+	$color [1, 1]
+	RenderProxies
+	{
+		EntityGetHealth { resultvar $health }
+		$scale = 0.0
+		$color[1] = 3
+	}
+}"#).unwrap();
+    let program = compile_material(&material, &ProxyRegistry::with_builtins());
+    let mut state = program.state();
+    let mut out = String::new();
+    let render = program.phase_index(RENDER_PHASE).unwrap();
+    trace_phase(&program, render, &mut state, &mut Host { health: 0 }, &mut out).unwrap();
+    let lines: Vec<&str> = out.lines().collect();
+    assert_eq!(lines[0], "Render state $health = 50  $scale = 0.0  $color = [1, 1]");
+    assert!(lines[2].ends_with("$health: 50 -> 0"), "{}", out);
+    // stores are shown even when the value does not change
+    assert!(lines[4].ends_with("$scale: 0.0 -> 0.0"), "{}", out);
+    assert!(lines[6].ends_with("$color: [1, 1] -> [1, 3]"), "{}", out);
+
+    // there is no Setup block, a phase that does not exist is an error
+    assert_eq!(program.phase_index(SETUP_PHASE), None);
+    let e = program.run(SETUP_PHASE, &mut state, &mut Host { health: 0 }).unwrap_err();
+    assert_eq!(e.message, "The material has no 'Setup' phase");
+    assert!(program.run_index(1, &mut state, &mut Host { health: 0 }).is_err());
+}
@@ -0,0 +1,61 @@
+use materialparser::conditions::Defines;
+use materialparser::{parse_material_file, MaterialVariableType};
+
+const MATERIAL: &str = r#"UnlitGeneric
+{
+	$detail 0
+	#if quality >= high && !mobile
+	{
+		$detail 2
+	}
+	#else
+	{
+		#if platform == "console"
+		{
+			$detail 1
+		}
+	}
+}
+"#;
+
+fn defines(entries: &[(&str, MaterialVariableType)]) -> Defines {
+    entries.iter().map(|(name, value)| (name.to_string(), value.clone())).collect()
+}
+
+fn detail(defines: &Defines) -> MaterialVariableType {
+    let material = parse_material_file(MATERIAL).unwrap().evaluate_conditions(defines).unwrap();
+    material.variables.get("detail").cloned().unwrap()
+}
+
+fn string(s: &str) -> MaterialVariableType {
+    MaterialVariableType::STRING(s.to_owned())
+}
+
+#[test]
+fn orders_quality_tiers() {
+    let platform = ("platform", string("pc"));
+    assert_eq!(detail(&defines(&[("quality", string("ultra")), platform.clone()])), MaterialVariableType::INTEGER(2));
+    assert_eq!(detail(&defines(&[("quality", string("high")), platform.clone()])), MaterialVariableType::INTEGER(2));
+    assert_eq!(detail(&defines(&[("quality", MaterialVariableType::INTEGER(2)), platform.clone()])), MaterialVariableType::INTEGER(2));
+    assert_eq!(detail(&defines(&[("quality", string("medium")), platform.clone()])), MaterialVariableType::INTEGER(0));
+    assert_eq!(detail(&defines(&[("quality", string("ultra")), ("mobile", MaterialVariableType::INTEGER(1)), platform])), MaterialVariableType::INTEGER(0));
+    assert_eq!(detail(&defines(&[("quality", string("low")), ("platform", string("console"))])), MaterialVariableType::INTEGER(1));
+}
+
+#[test]
+fn rejects_undefined_words_in_comparisons() {
+    let material = parse_material_file("UnlitGeneric { #if qualty == high { $a 1 } }").unwrap();
+    let e = material.evaluate_conditions(&defines(&[("quality", string("high"))])).unwrap_err();
+    assert_eq!(e.message, "Cannot evaluate 'qualty == high': 'qualty' is not defined, strings are written between quotes");
+
+    let material = parse_material_file("UnlitGeneric { #if platform == mobile { $a 1 } }").unwrap();
+    assert!(material.evaluate_conditions(&defines(&[("platform", string("mobile"))])).is_err());
+}
+
+#[test]
+fn lists_the_defines_conditions_depend_on() {
+    let material = parse_material_file(MATERIAL).unwrap();
+    assert_eq!(material.condition_defines(), vec!["mobile", "platform", "quality"]);
+    let conditions: Vec<String> = material.conditions().iter().map(|condition| condition.to_string()).collect();
+    assert_eq!(conditions, vec!["quality >= high && !mobile", "platform == \"console\""]);
+}
@@ -0,0 +1,58 @@
+use materialparser::bytecode::compile_material;
+use materialparser::instance::MaterialInstance;
+use materialparser::runtime::{ProxyHost, ProxyRegistry};
+use materialparser::{parse_material_file, MaterialVariableType, RENDER_PHASE};
+use std::sync::Arc;
+
+struct Host {
+    health: i32,
+}
+
+impl ProxyHost for Host {
+    fn entity_field(&mut self, name: &str) -> Option<MaterialVariableType> {
+        match name {
+            "health" => Some(MaterialVariableType::INTEGER(self.health)),
+            _ => None
+        }
+    }
+}
+
+#[test]
+fn instances_flag_the_variables_that_changed() {
+    let material = parse_material_file("UnlitGeneric { $health 0 $frames 0 SetupProxies { EntityGetHealth { resultvar $health } } RenderProxies { $frames = $frames + 1 } }").unwrap();
+    let registry = ProxyRegistry::with_builtins();
+    let mut instance = MaterialInstance::new(Arc::new(material));
+    let dirty: Vec<&String> = instance.dirty().map(|(name, _)| name).collect();
+    assert_eq!(dirty, ["health", "frames"]);
+    instance.clear_dirty();
+
+    instance.render(&registry, &mut Host { health: 0 }).unwrap();
+    // Setup wrote the value $health already had
+    assert!(!instance.is_dirty("health"));
+    let dirty: Vec<(&String, &MaterialVariableType)> = instance.dirty().collect();
+    assert_eq!(dirty, [(&String::from("frames"), &MaterialVariableType::INTEGER(1))]);
+
+    instance.clear_dirty();
+    instance.set_variable("health", MaterialVariableType::INTEGER(5)).unwrap();
+    assert!(instance.is_dirty("health"));
+    assert!(!instance.is_dirty("frames"));
+    assert!(!instance.is_dirty("unknown"));
+}
+
+#[test]
+fn programs_flag_the_slots_that_changed() {
+    let material = parse_material_file("UnlitGeneric { $a 1 $b 0 $c 2 RenderProxies { $a = 1 $b = $b + 1 } }").unwrap();
+    let program = compile_material(&material, &ProxyRegistry::with_builtins());
+    let mut state = program.state();
+    assert_eq!(state.dirty().count(), 3);
+    state.clear_dirty();
+
+    program.run(RENDER_PHASE, &mut state, &mut Host { health: 0 }).unwrap();
+    let b = program.slot("b").unwrap();
+    assert_eq!(state.dirty().collect::<Vec<_>>(), [b]);
+    assert!(!state.is_dirty(program.slot("a").unwrap()));
+
+    state.clear_dirty();
+    program.set_value(&mut state, program.slot("c").unwrap(), MaterialVariableType::INTEGER(2)).unwrap();
+    assert_eq!(state.dirty().count(), 0);
+}
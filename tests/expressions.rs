@@ -0,0 +1,48 @@
+use materialparser::{parse_material_file, MaterialVariableType};
+
+fn variable(source: &str, name: &str) -> MaterialVariableType {
+    match parse_material_file(source) {
+        Ok(material) => material.variables.get(name).cloned().unwrap(),
+        Err(e) => panic!("{}", e)
+    }
+}
+
+#[test]
+fn folds_constant_expressions() {
+    let source = "UnlitGeneric { $health_max 200 $scale 1.0 / 64 $tint [0.5 * 2, 1, 1] $warn clamp($health_max / 4, 10, 40) $mix lerp(0.0, 10.0, 0.25) }";
+    assert_eq!(variable(source, "scale"), MaterialVariableType::DOUBLE(0.015625));
+    assert_eq!(variable(source, "tint"), MaterialVariableType::ARRAY3D(1.0, 1.0, 1.0));
+    assert_eq!(variable(source, "warn"), MaterialVariableType::INTEGER(40));
+    assert_eq!(variable(source, "mix"), MaterialVariableType::DOUBLE(2.5));
+}
+
+#[test]
+fn divides_integers_as_integers() {
+    assert_eq!(variable("UnlitGeneric { $a 1 / 64 }", "a"), MaterialVariableType::INTEGER(0));
+    assert_eq!(variable("UnlitGeneric { $a 7 / 2 }", "a"), MaterialVariableType::INTEGER(3));
+    assert_eq!(variable("UnlitGeneric { $a: float 1 / 64 }", "a"), MaterialVariableType::FLOAT(0.0));
+}
+
+#[test]
+fn references_earlier_variables_only() {
+    assert_eq!(variable("UnlitGeneric { $a 2 #if x { $b $a * 2 } $c $a + 1 }", "c"), MaterialVariableType::INTEGER(3));
+
+    let e = parse_material_file("UnlitGeneric {\n\t$a $b + 1\n\t$b 2\n}").unwrap_err();
+    assert_eq!(e.message, "'$b' is used before it is declared, expressions can only reference earlier variables");
+    assert_eq!(e.span.map(|span| span.line), Some(2));
+    assert_eq!(e.related.map(|span| span.line), Some(3));
+
+    let e = parse_material_file("UnlitGeneric { #if x { $a $b } $b 2 }").unwrap_err();
+    assert!(e.message.starts_with("'$b' is used before it is declared"), "{}", e.message);
+}
+
+#[test]
+fn reports_cycles_and_type_errors() {
+    let e = parse_material_file("UnlitGeneric { $a $a + 1 }").unwrap_err();
+    assert_eq!(e.message, "Cycle between variables: $a -> $a");
+    let e = parse_material_file("UnlitGeneric { $a \"x\" * 2 }").unwrap_err();
+    assert_eq!(e.message, "Cannot apply '*' to 'string' and 'int'");
+    assert!(e.span.is_some());
+    let e = parse_material_file("UnlitGeneric { $a 1 / 0 }").unwrap_err();
+    assert_eq!(e.message, "Division by zero");
+}
@@ -0,0 +1,65 @@
+use materialparser::instance::MaterialInstance;
+use materialparser::runtime::{ProxyHost, ProxyRegistry};
+use materialparser::{parse_material_file, MaterialVariableType};
+use std::sync::Arc;
+
+const MATERIAL: &str = r#"UnlitGeneric
+{
+	$health 0
+	$frames 0
+	SetupProxies
+	{
+		EntityGetHealth { resultvar $health }
+	}
+	RenderProxies
+	{
+		$frames = $frames + 1
+	}
+}"#;
+
+struct Host {
+    health: Option<i32>,
+}
+
+impl ProxyHost for Host {
+    fn entity_field(&mut self, name: &str) -> Option<MaterialVariableType> {
+        match name {
+            "health" => self.health.map(MaterialVariableType::INTEGER),
+            _ => None
+        }
+    }
+}
+
+fn instance() -> MaterialInstance {
+    MaterialInstance::new(Arc::new(parse_material_file(MATERIAL).unwrap()))
+}
+
+#[test]
+fn failed_setup_runs_again() {
+    let registry = ProxyRegistry::with_builtins();
+    let mut instance = instance();
+    let e = instance.render(&registry, &mut Host { health: None }).unwrap_err();
+    assert!(e.message.contains("entity.health"), "{}", e.message);
+    assert_eq!(instance.variable("frames"), Some(&MaterialVariableType::INTEGER(0)));
+
+    instance.render(&registry, &mut Host { health: Some(75) }).unwrap();
+    assert_eq!(instance.variable("health"), Some(&MaterialVariableType::INTEGER(75)));
+    assert_eq!(instance.variable("frames"), Some(&MaterialVariableType::INTEGER(1)));
+
+    // Setup only runs until it succeeds
+    instance.render(&registry, &mut Host { health: Some(10) }).unwrap();
+    assert_eq!(instance.variable("health"), Some(&MaterialVariableType::INTEGER(75)));
+    assert_eq!(instance.variable("frames"), Some(&MaterialVariableType::INTEGER(2)));
+}
+
+#[test]
+fn setting_the_shared_value_drops_the_override() {
+    let mut instance = instance();
+    instance.set_variable("frames", MaterialVariableType::INTEGER(3)).unwrap();
+    assert!(instance.is_overridden("frames"));
+    instance.set_variable("frames", MaterialVariableType::INTEGER(0)).unwrap();
+    assert!(!instance.is_overridden("frames"));
+    instance.set_variable("health", MaterialVariableType::INTEGER(0)).unwrap();
+    assert_eq!(instance.overridden().count(), 0);
+    assert_eq!(instance.variable("frames"), Some(&MaterialVariableType::INTEGER(0)));
+}
@@ -0,0 +1,43 @@
+use materialparser::inheritance::load_material;
+use materialparser::library::parse_material_library;
+use materialparser::{MaterialVariableType, RENDER_PHASE};
+
+const LIBRARY: &str = r#"
+material "walls/brick" UnlitGeneric
+{
+	$basetexture "walls/brick"
+	$scale 1.0
+	RenderProxies
+	{
+		$scale = $scale * 2
+	}
+}
+
+material "walls/brick_red" UnlitGeneric : "walls/brick"
+{
+	$color [1, 0, 0]
+}
+"#;
+
+#[test]
+fn inherits_from_parsed_materials() {
+    let mut library = parse_material_library(LIBRARY).unwrap();
+    assert_eq!(library.names().collect::<Vec<_>>(), vec!["walls/brick", "walls/brick_red"]);
+    let resolved = load_material("walls/brick_red", &mut library).unwrap();
+    assert_eq!(resolved.chain, vec!["walls/brick", "walls/brick_red"]);
+    assert_eq!(resolved.material.variables.get("basetexture"), Some(&MaterialVariableType::STRING(String::from("walls/brick"))));
+    assert_eq!(resolved.variable_origin("color"), Some("walls/brick_red"));
+    assert_eq!(resolved.render_proxy_origin(0), Some("walls/brick"));
+    // The inherited proxy is the parsed one, it still points into the library
+    let proxy = &resolved.material.phases.get(RENDER_PHASE).unwrap().proxies[0];
+    assert_eq!(proxy.span.map(|span| span.line), Some(8));
+}
+
+#[test]
+fn reports_unknown_and_duplicate_materials() {
+    let mut library = parse_material_library(LIBRARY).unwrap();
+    assert_eq!(load_material("walls/wood", &mut library).unwrap_err().message, "Unknown material 'walls/wood' in library");
+    let e = parse_material_library("material \"a\" UnlitGeneric { }\nmaterial \"a\" UnlitGeneric { }").unwrap_err();
+    assert_eq!(e.message, "Duplicate material 'a'");
+    assert_eq!(e.related.map(|span| span.line), Some(1));
+}
@@ -0,0 +1,85 @@
+use materialparser::patch::{apply_patch, parse_patch_file};
+use materialparser::writer;
+use materialparser::{parse_material_file, MaterialFile, MaterialVariableReference, MaterialVariableType, RENDER_PHASE};
+
+const MATERIAL: &str = r#"UnlitGeneric
+{
+	$a 0
+	$b 0
+	$health 0
+	$color [1, 0, 0]
+
+	RenderProxies
+	{
+		$a = 1
+		$b = 1
+		DivideF { srcvar $health divisor 100 resultvar $health }
+		DivideF { srcvar $a divisor 2 resultvar $a }
+	}
+}
+"#;
+
+fn patch(source: &str) -> (MaterialFile, Vec<String>) {
+    let mut material = parse_material_file(MATERIAL).unwrap();
+    let patch = parse_patch_file(source).unwrap();
+    let errors = apply_patch(&mut material, &patch).into_iter().map(|e| e.message).collect();
+    (material, errors)
+}
+
+fn targets(material: &MaterialFile) -> Vec<String> {
+    material.phases.get(RENDER_PHASE).unwrap().proxies.iter().map(|proxy| {
+        match proxy.parameters.get("resultvar") {
+            Some(MaterialVariableReference::VARIABLE(name)) => format!("{} ${}", proxy.name, name),
+            _ => proxy.name.clone()
+        }
+    }).collect()
+}
+
+#[test]
+fn inserts_replaces_and_removes_variables() {
+    let (material, errors) = patch(r#"patch "m" { insert { $detail "dev/noise" } replace { $color [0, 1, 0] } remove { $b } }"#);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(material.variables.get("detail"), Some(&MaterialVariableType::STRING(String::from("dev/noise"))));
+    assert_eq!(material.variables.get("color"), Some(&MaterialVariableType::ARRAY3(0, 1, 0)));
+    assert!(!material.variables.contains_key("b"));
+
+    let (_, errors) = patch(r#"patch "m" { replace { $missing 1 } remove { $missing } }"#);
+    assert_eq!(errors, vec!["Cannot replace '$missing': it does not exist", "Cannot remove '$missing': it does not exist"]);
+}
+
+#[test]
+fn replaces_assignments_by_target() {
+    let (material, errors) = patch(r#"patch "m" { replace { RenderProxies { $b = 2 } } }"#);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(targets(&material), vec!["Expression $a", "Expression $b", "DivideF $health", "DivideF $a"]);
+    let expressions: Vec<String> = material.phases.get(RENDER_PHASE).unwrap().proxies[..2].iter()
+        .map(|proxy| proxy.parameters.get("expression").map(writer::write_reference).unwrap_or_default())
+        .collect();
+    assert_eq!(expressions, vec!["1", "2"]);
+}
+
+#[test]
+fn replaces_proxies_by_name_and_target() {
+    let (material, errors) = patch(r#"patch "m" { replace { RenderProxies { DivideF { srcvar $a divisor 4 resultvar $a } } } }"#);
+    assert!(errors.is_empty(), "{:?}", errors);
+    let proxies = &material.phases.get(RENDER_PHASE).unwrap().proxies;
+    assert_eq!(proxies[2].parameters.get("divisor"), Some(&MaterialVariableReference::TYPE(MaterialVariableType::INTEGER(100))));
+    assert_eq!(proxies[3].parameters.get("divisor"), Some(&MaterialVariableReference::TYPE(MaterialVariableType::INTEGER(4))));
+}
+
+#[test]
+fn removes_proxies_like_it_replaces_them() {
+    let (material, errors) = patch(r#"patch "m" { remove { RenderProxies { $b DivideF $a } } }"#);
+    assert!(errors.is_empty(), "{:?}", errors);
+    assert_eq!(targets(&material), vec!["Expression $a", "DivideF $health"]);
+
+    let (material, errors) = patch(r#"patch "m" { remove { RenderProxies { Expression DivideF } } }"#);
+    assert_eq!(errors, vec![
+        "Ambiguous patch: 2 proxies of RenderProxies match the proxy 'Expression'",
+        "Ambiguous patch: 2 proxies of RenderProxies match the proxy 'DivideF'",
+    ]);
+    assert_eq!(targets(&material).len(), 4);
+
+    let (_, errors) = patch(r#"patch "m" { remove { RenderProxies { $color } } }"#);
+    assert_eq!(errors, vec!["Cannot remove proxy writing '$color' in RenderProxies: it does not exist"]);
+}
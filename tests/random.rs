@@ -0,0 +1,71 @@
+use materialparser::bytecode::compile_material;
+use materialparser::instance::MaterialInstance;
+use materialparser::random::ProxyRng;
+use materialparser::runtime::{run_proxies, ProxyHost, ProxyRegistry};
+use materialparser::{parse_material_file, MaterialFile, MaterialVariableType, RENDER_PHASE};
+use std::sync::Arc;
+
+// The numbers are part of the format, they must not change between versions or platforms
+const FNV_RENDER: u64 = 0x7A1A_AC18_36D2_D16D;
+const RENDER_SEED_42: [f64; 3] = [0.5478094797051036, 0.5234927099397102, 0.4324539029883253];
+
+struct NoEntity;
+
+impl ProxyHost for NoEntity {
+    fn entity_field(&mut self, _: &str) -> Option<MaterialVariableType> {
+        None
+    }
+}
+
+fn material() -> MaterialFile {
+    parse_material_file(r#"UnlitGeneric
+{
+	$noise 0.0
+	RenderProxies
+	{
+		RandomDouble { resultvar $noise }
+	}
+}"#).unwrap()
+}
+
+#[test]
+fn splitmix64_outputs() {
+    let mut rng = ProxyRng::new(0);
+    assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+    assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    assert_eq!(rng.next_u64(), 0x06C4_5D18_8009_454F);
+}
+
+#[test]
+fn phase_streams_are_seeded_with_fnv1a() {
+    assert_eq!(ProxyRng::for_phase(0, ""), ProxyRng::new(0xCBF2_9CE4_8422_2325));
+    assert_eq!(ProxyRng::for_phase(0, "a"), ProxyRng::new(0xAF63_DC4C_8601_EC8C));
+    assert_eq!(ProxyRng::for_phase(42, RENDER_PHASE), ProxyRng::new(42 ^ FNV_RENDER));
+    let mut rng = ProxyRng::for_phase(42, RENDER_PHASE);
+    for expected in &RENDER_SEED_42 {
+        assert_eq!(rng.next_f64(), *expected);
+    }
+}
+
+#[test]
+fn interpreter_instances_and_programs_draw_the_same_numbers() {
+    let material = material();
+    let registry = ProxyRegistry::with_builtins();
+
+    let mut interpreted = material.clone();
+    let mut rng = ProxyRng::for_phase(42, RENDER_PHASE);
+    let mut instance = MaterialInstance::with_seed(Arc::new(material.clone()), 42);
+    let program = compile_material(&material, &registry);
+    let mut state = program.seeded_state(42);
+    let slot = program.slot("noise").unwrap();
+
+    for expected in &RENDER_SEED_42 {
+        let expected = MaterialVariableType::DOUBLE(*expected);
+        run_proxies(material.proxies(RENDER_PHASE), &mut interpreted, &registry, &mut NoEntity, &mut rng).unwrap();
+        assert_eq!(interpreted.variables.get("noise"), Some(&expected));
+        instance.render(&registry, &mut NoEntity).unwrap();
+        assert_eq!(instance.variable("noise"), Some(&expected));
+        program.run(RENDER_PHASE, &mut state, &mut NoEntity).unwrap();
+        assert_eq!(state.value(slot), &expected);
+    }
+}
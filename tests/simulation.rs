@@ -0,0 +1,46 @@
+use materialparser::expression::parse_expression;
+use materialparser::runtime::ProxyRegistry;
+use materialparser::simulation::{simulate, SimulationOptions};
+use materialparser::{parse_material_file, MaterialVariableType};
+
+fn options(frames: u32, health: &str) -> SimulationOptions {
+    SimulationOptions {
+        frames,
+        dt: 0.5,
+        fields: vec![(String::from("health"), parse_expression(health).unwrap())],
+        trace: false,
+        seed: 0,
+    }
+}
+
+#[test]
+fn records_every_frame() {
+    let material = parse_material_file("UnlitGeneric { $health 0 $scale 0.0 RenderProxies { EntityGetHealth { resultvar $health } DivideF { srcvar $health divisor 100 resultvar $scale } } }").unwrap();
+    let simulation = simulate(&material, &ProxyRegistry::with_builtins(), &options(3, "100 - $frame * 50")).unwrap();
+    assert_eq!(simulation.variables, ["health", "scale"]);
+    assert_eq!(simulation.frames.len(), 3);
+    assert_eq!(simulation.frames[1].time, 0.5);
+    assert_eq!(simulation.frames[2].values, [MaterialVariableType::INTEGER(0), MaterialVariableType::FLOAT(0.0)]);
+    assert_eq!(simulation.to_csv(), "frame,time,$health,$scale\n0,0,100,1.0f\n1,0.5,50,0.5f\n2,1,0,0.0f\n");
+    assert!(simulation.error.is_none());
+}
+
+#[test]
+fn stops_at_the_first_error() {
+    let material = parse_material_file("UnlitGeneric { $health 0 $scale 0.0 RenderProxies { EntityGetHealth { resultvar $health } $scale = 1 / $health } }").unwrap();
+    let simulation = simulate(&material, &ProxyRegistry::with_builtins(), &options(5, "2 - $frame")).unwrap();
+    assert_eq!(simulation.frames.len(), 2);
+    let e = simulation.error.unwrap();
+    assert!(e.message.starts_with("Frame 2"), "{}", e.message);
+    assert!(e.message.contains("Division by zero"), "{}", e.message);
+}
+
+#[test]
+fn reports_the_scripts_that_fail() {
+    let material = parse_material_file("UnlitGeneric { $health 0 RenderProxies { EntityGetHealth { resultvar $health } } }").unwrap();
+    let simulation = simulate(&material, &ProxyRegistry::with_builtins(), &options(5, "100 / (2 - $frame)")).unwrap();
+    assert_eq!(simulation.frames.len(), 2);
+    let e = simulation.error.unwrap();
+    assert!(e.message.starts_with("Frame 2: In the script of 'health'"), "{}", e.message);
+    assert!(e.message.contains("Division by zero"), "{}", e.message);
+}
@@ -0,0 +1,37 @@
+use materialparser::patch::parse_patch_file;
+use materialparser::{parse_material_file, MaterialVariableType};
+
+#[test]
+fn instantiates_templates() {
+    let source = "template Tinted(tex, col) UnlitGeneric { $basetexture tex $color col }\nTinted(\"dev/a\", [1, 0, 0])";
+    let material = parse_material_file(source).unwrap();
+    assert_eq!(material.shader, "UnlitGeneric");
+    assert_eq!(material.variables.get("basetexture"), Some(&MaterialVariableType::STRING(String::from("dev/a"))));
+    assert_eq!(material.variables.get("color"), Some(&MaterialVariableType::ARRAY3(1, 0, 0)));
+}
+
+#[test]
+fn points_at_the_body_and_the_instantiation() {
+    let source = "template Tinted(tex) UnlitGeneric { $basetexture tex * 2 }\nTinted(\"dev/a\")";
+    let e = parse_material_file(source).unwrap_err();
+    assert_eq!(e.message, "In template 'Tinted': Cannot apply '*' to 'string' and 'int'");
+    assert_eq!(e.span.map(|span| span.line), Some(1));
+    assert_eq!(e.related.map(|span| span.line), Some(2));
+}
+
+#[test]
+fn keeps_the_related_location_of_the_body() {
+    let source = "template Twice(tex) UnlitGeneric {\n\t$basetexture tex\n\t$basetexture tex\n}\n\nTwice(\"dev/a\")";
+    let e = parse_material_file(source).unwrap_err();
+    assert_eq!(e.message, "In template 'Twice' instantiated at line 6, column 1: Duplicate variable '$basetexture'");
+    assert_eq!(e.span.map(|span| span.line), Some(3));
+    assert_eq!(e.related.map(|span| span.line), Some(2));
+}
+
+#[test]
+fn rejects_trailing_input() {
+    assert!(parse_material_file("UnlitGeneric { $a 1 } garbage trailing !!!").is_err());
+    assert!(parse_material_file("UnlitGeneric { $a 1 }\n// comment\n").is_ok());
+    assert!(parse_patch_file("patch \"m\" { remove { $a } } garbage").is_err());
+    assert!(parse_patch_file("patch \"m\" { remove { $a } }\n").is_ok());
+}
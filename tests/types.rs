@@ -0,0 +1,26 @@
+use materialparser::{parse_material_file, MaterialVariableKind, MaterialVariableType};
+
+#[test]
+fn coerces_literals_to_declared_types() {
+    let material = parse_material_file("UnlitGeneric { $health: float 0 $scale: float 0.1 $tint: vec3f [1, 1, 0] $big: double 16777217 }").unwrap();
+    assert_eq!(material.variables.get("health"), Some(&MaterialVariableType::FLOAT(0.0)));
+    assert_eq!(material.variables.get("scale"), Some(&MaterialVariableType::FLOAT(0.1)));
+    assert_eq!(material.variables.get("tint"), Some(&MaterialVariableType::ARRAY3F(1.0, 1.0, 0.0)));
+    assert_eq!(material.variables.get("big"), Some(&MaterialVariableType::DOUBLE(16777217.0)));
+    assert_eq!(material.declared_types.get("health"), Some(&MaterialVariableKind::FLOAT));
+}
+
+#[test]
+fn rounds_when_converting_to_float() {
+    assert_eq!(MaterialVariableType::INTEGER(16777217).coerce(MaterialVariableKind::FLOAT), Some(MaterialVariableType::FLOAT(16777216.0)));
+    assert_eq!(MaterialVariableType::DOUBLE(0.1).coerce(MaterialVariableKind::FLOAT), Some(MaterialVariableType::FLOAT(0.1)));
+    assert_eq!(MaterialVariableType::DOUBLE(1.0).coerce(MaterialVariableKind::INTEGER), None);
+}
+
+#[test]
+fn rejects_mismatched_literals() {
+    let e = parse_material_file("UnlitGeneric { $health: int 0.5 }").unwrap_err();
+    assert_eq!(e.message, "Type mismatch: '$health' is declared as 'int' but its value is a 'double'");
+    let e = parse_material_file("UnlitGeneric { $tint: vec3f [1, 1] }").unwrap_err();
+    assert!(e.message.starts_with("Type mismatch"), "{}", e.message);
+}
@@ -0,0 +1,103 @@
+use materialparser::conditions::Defines;
+use materialparser::inheritance::{load_material, load_material_with_defines};
+use materialparser::{parse_material_file, MaterialFile, MaterialVariableType};
+use std::collections::HashMap;
+
+fn parse(source: &str) -> MaterialFile {
+    match parse_material_file(source) {
+        Ok(data) => data,
+        Err(e) => panic!("{}", e)
+    }
+}
+
+fn string(value: &str) -> MaterialVariableType {
+    MaterialVariableType::STRING(String::from(value))
+}
+
+#[test]
+fn parses_raw_and_multiline_strings() {
+    let material = parse("UnlitGeneric { $raw r\"C:\\dev\\n\" $lines \"\"\"\n\tfirst\n\tsecond\"\"\" }");
+    assert_eq!(material.variables.get("raw"), Some(&string("C:\\dev\\n")));
+    // the first line break and the common indentation are removed
+    assert_eq!(material.variables.get("lines"), Some(&string("first\nsecond")));
+}
+
+#[test]
+fn parses_lists_of_any_length() {
+    let material = parse(r#"UnlitGeneric { $frames ["a", "b", "c"] $weights [1, 2, 3, 4, 5] $empty [] }"#);
+    assert_eq!(material.variables.get("frames"), Some(&MaterialVariableType::LIST(vec![string("a"), string("b"), string("c")])));
+    assert_eq!(material.variables.get("empty"), Some(&MaterialVariableType::LIST(Vec::new())));
+    match material.variables.get("weights") {
+        Some(MaterialVariableType::LIST(values)) => assert_eq!(values.len(), 5),
+        other => panic!("{:?}", other)
+    }
+}
+
+#[test]
+fn parses_nested_blocks() {
+    let material = parse(r#"UnlitGeneric { $sampler { wrap clamp filter { min linear mag "nearest" } lod 2 } }"#);
+    let sampler = material.variables.get("sampler").unwrap();
+    assert_eq!(sampler.get_path("filter.mag"), Some(&string("nearest")));
+    assert_eq!(sampler.get_path("lod"), Some(&MaterialVariableType::INTEGER(2)));
+    assert_eq!(sampler.get_path("filter.max"), None);
+    let keys: Vec<&str> = sampler.as_map().unwrap().iter().map(|(key, _)| key.as_str()).collect();
+    assert_eq!(keys, ["wrap", "filter", "lod"]);
+}
+
+#[test]
+fn derived_materials_override_their_parent() {
+    let mut sources = HashMap::new();
+    sources.insert(String::from("base"), String::from("UnlitGeneric { $color [1, 0, 0] $alpha 1.0 $health 0 RenderProxies { EntityGetHealth { resultvar $health } } }"));
+    sources.insert(String::from("red"), String::from("VertexLitGeneric : \"base\" { $alpha 0.25 RenderProxies { DivideF { srcvar $health divisor 100 resultvar $alpha } } }"));
+    let resolved = load_material("red", &mut sources).unwrap();
+    assert_eq!(resolved.chain, ["base", "red"]);
+    assert_eq!(resolved.material.shader, "VertexLitGeneric");
+    assert_eq!(resolved.material.variables.get("alpha"), Some(&MaterialVariableType::DOUBLE(0.25)));
+    assert_eq!(resolved.variable_origin("color"), Some("base"));
+    assert_eq!(resolved.material.render_proxies().len(), 2);
+    assert_eq!(resolved.render_proxy_origin(1), Some("red"));
+
+    sources.insert(String::from("base"), String::from("UnlitGeneric : \"red\" { }"));
+    let e = load_material("red", &mut sources).unwrap_err();
+    assert!(e.message.contains("red -> base -> red"), "{}", e.message);
+}
+
+#[test]
+fn parent_conditionals_are_evaluated_before_the_overrides() {
+    let mut sources = HashMap::new();
+    sources.insert(String::from("base"), String::from("UnlitGeneric { $color [1, 0, 0] #if quality == \"high\" { $color [0, 0, 1] } }"));
+    sources.insert(String::from("green"), String::from("UnlitGeneric : \"base\" { $color [0, 1, 0] }"));
+    let mut defines = Defines::new();
+    defines.insert(String::from("quality"), string("high"));
+    let resolved = load_material_with_defines("green", &mut sources, &defines).unwrap();
+    let green = parse("UnlitGeneric { $color [0, 1, 0] }");
+    assert_eq!(resolved.material.variables.get("color"), green.variables.get("color"));
+
+    // the #if block of the parent would be applied after the override
+    let e = load_material("green", &mut sources).unwrap_err();
+    assert!(e.message.contains("'base' has #if blocks"), "{}", e.message);
+    assert_eq!(load_material("base", &mut sources).unwrap().material.conditionals.len(), 1);
+}
+
+#[test]
+fn keeps_declaration_order_and_rejects_duplicates() {
+    let material = parse("UnlitGeneric { $z 1 $a 2 $m 3 }");
+    let names: Vec<&String> = material.variables.keys().collect();
+    assert_eq!(names, ["z", "a", "m"]);
+    // the order is part of the value
+    assert_ne!(material.variables, parse("UnlitGeneric { $a 2 $z 1 $m 3 }").variables);
+
+    let e = parse_material_file("UnlitGeneric {\n\t$a 1\n\t$a 2\n}").unwrap_err();
+    assert_eq!(e.message, "Duplicate variable '$a'");
+    assert_eq!(e.span.map(|s| s.line), Some(3));
+    assert_eq!(e.related.map(|s| s.line), Some(2));
+}
+
+#[test]
+fn parses_proxies_of_any_phase() {
+    let material = parse("UnlitGeneric { $a 0 SetupProxies { $a = 1 } ClickProxies { $a = $a + 1 } }");
+    let phases: Vec<&String> = material.phases.keys().collect();
+    assert_eq!(phases, ["Setup", "Click"]);
+    assert_eq!(material.proxies("Click").len(), 1);
+    assert_eq!(material.proxies("Render").len(), 0);
+}
@@ -0,0 +1,110 @@
+use materialparser::writer::{write_material_file, write_value};
+use materialparser::{parse_material_file, MaterialFile, MaterialVariableType};
+
+fn variable(source: &str, name: &str) -> MaterialVariableType {
+    match parse_material_file(source) {
+        Ok(material) => material.variables.get(name).cloned().unwrap(),
+        Err(e) => panic!("{}", e)
+    }
+}
+
+fn round_trip(material: &MaterialFile) -> MaterialFile {
+    let text = write_material_file(material);
+    match parse_material_file(&text) {
+        Ok(data) => data,
+        Err(e) => panic!("{}\n{}", e, text)
+    }
+}
+
+#[test]
+fn decodes_escapes() {
+    let value = variable(r#"UnlitGeneric { $a "tab\tquote\"back\\slash\'\n\0\u{e9}\u{1F600}" }"#, "a");
+    assert_eq!(value, MaterialVariableType::STRING(String::from("tab\tquote\"back\\slash'\n\0é😀")));
+}
+
+#[test]
+fn reports_invalid_escapes_with_spans() {
+    let e = parse_material_file("UnlitGeneric {\n\t$a \"x\\q\"\n}").unwrap_err();
+    assert_eq!(e.message, "Invalid escape sequence '\\q'");
+    let span = e.span.unwrap();
+    assert_eq!((span.line, span.column), (2, 7));
+
+    let e = parse_material_file(r#"UnlitGeneric { $a "\u{110000}" }"#).unwrap_err();
+    assert!(e.message.contains("not a unicode scalar value"), "{}", e.message);
+    let e = parse_material_file(r#"UnlitGeneric { $a "\u{}" }"#).unwrap_err();
+    assert!(e.message.contains("1 to 6 hex digits"), "{}", e.message);
+}
+
+#[test]
+fn writes_values_that_parse_back() {
+    let values = vec![
+        MaterialVariableType::NONE,
+        MaterialVariableType::STRING(String::from("line\nquote\" \u{1} é")),
+        MaterialVariableType::INTEGER(-3),
+        MaterialVariableType::FLOAT(2.0),
+        MaterialVariableType::FLOAT(f32::INFINITY),
+        MaterialVariableType::DOUBLE(f64::NEG_INFINITY),
+        MaterialVariableType::DOUBLE(1e300),
+        MaterialVariableType::ARRAY3F(1.5, f32::NEG_INFINITY, 0.0),
+        MaterialVariableType::ARRAY2D(f64::INFINITY, -0.25),
+        MaterialVariableType::LIST(vec![MaterialVariableType::INTEGER(1), MaterialVariableType::INTEGER(2)]),
+        MaterialVariableType::LIST(vec![MaterialVariableType::NONE, MaterialVariableType::STRING(String::from("none"))]),
+    ];
+    let mut material = MaterialFile::new(String::from("UnlitGeneric"));
+    for (i, value) in values.iter().enumerate() {
+        material.variables.insert(format!("v{}", i), value.clone());
+    }
+    assert_eq!(round_trip(&material), material);
+}
+
+#[test]
+fn writes_nan() {
+    assert_eq!(write_value(&MaterialVariableType::DOUBLE(f64::NAN)), "nan");
+    assert_eq!(write_value(&MaterialVariableType::FLOAT(f32::NAN)), "nanf");
+    match variable("UnlitGeneric { $a nan $b -inff $c nanf }", "a") {
+        MaterialVariableType::DOUBLE(n) => assert!(n.is_nan()),
+        other => panic!("{:?}", other)
+    }
+    assert_eq!(variable("UnlitGeneric { $a nan $b -inff }", "b"), MaterialVariableType::FLOAT(f32::NEG_INFINITY));
+    // Identifiers starting like the keywords are still identifiers
+    assert_eq!(variable("UnlitGeneric { $a { filter nonlinear mode info } }", "a").get_path("mode"), Some(&MaterialVariableType::STRING(String::from("info"))));
+}
+
+#[test]
+fn writes_material_files_that_parse_back() {
+    let source = r#"UnlitGeneric
+{
+	$basetexture "dev/gradient_dif"
+	$color: vec3f [1.0, 0.5, 0.0]
+	$sampler { wrap clamp filter "trilinear" }
+	$names ["a", r"b\c", """
+		multi
+		line"""]
+	$health 0
+
+	#if quality >= 2
+	{
+		$detail 1
+	}
+
+	SetupProxies
+	{
+		EntityGetHealth
+		{
+			resultvar $health
+		}
+	}
+
+	RenderProxies
+	{
+		$health = $health / 2 if $health > 0
+	}
+}
+"#;
+    let material = parse_material_file(source).unwrap();
+    let written = write_material_file(&material);
+    let parsed = round_trip(&material);
+    assert_eq!(parsed.variables, material.variables);
+    assert_eq!(parsed.declared_types, material.declared_types);
+    assert_eq!(write_material_file(&parsed), written);
+}